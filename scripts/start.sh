@@ -29,5 +29,10 @@ else
     ./wifi-connect
 fi
 
+# Alternatively, skip the checks above and keep WiFi Connect running in the
+# background with `--daemon`. It then starts the captive portal again whenever
+# connectivity has been lost for longer than `--daemon-grace-period` seconds.
+# ./wifi-connect --daemon &
+
 # Start your application here.
 sleep infinity
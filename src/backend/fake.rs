@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use backend::{
+    AccessPoint, Connectivity, EnterpriseSettings, IpSettings, PortalSecurity, SavedNetwork,
+    Security, WifiBackend,
+};
+use errors::*;
+use ssid::SsidTemplate;
+
+/// In-memory backend for exercising the portal lifecycle without D-Bus,
+/// NetworkManager or WiFi hardware.
+///
+/// Networks are registered together with the passphrase that is accepted
+/// when connecting to them. A successful connect switches the reported
+/// connectivity to `connected_connectivity`, bringing the hotspot up to
+/// `portal_connectivity`.
+pub struct FakeBackend {
+    interface: String,
+    access_points: Vec<AccessPoint>,
+    passphrases: HashMap<String, String>,
+    portal_ssid: Option<String>,
+    portal_profiles: Vec<String>,
+    saved_connections: Vec<String>,
+    connection: Option<String>,
+    portal_scan: bool,
+    connected_connectivity: Connectivity,
+    portal_connectivity: Connectivity,
+    connectivity: Connectivity,
+    connectivity_fails: bool,
+}
+
+impl FakeBackend {
+    pub fn new(interface: &str) -> Self {
+        FakeBackend {
+            interface: interface.to_string(),
+            access_points: Vec::new(),
+            passphrases: HashMap::new(),
+            portal_ssid: None,
+            portal_profiles: Vec::new(),
+            saved_connections: Vec::new(),
+            connection: None,
+            portal_scan: false,
+            connected_connectivity: Connectivity::Full,
+            portal_connectivity: Connectivity::None,
+            connectivity: Connectivity::None,
+            connectivity_fails: false,
+        }
+    }
+
+    pub fn with_network(
+        mut self,
+        ssid: &str,
+        security: Security,
+        strength: u32,
+        passphrase: &str,
+    ) -> Self {
+        self.access_points.push(AccessPoint {
+            ssid: ssid.to_string(),
+            security,
+            strength,
+            frequency: None,
+        });
+        self.passphrases
+            .insert(ssid.to_string(), passphrase.to_string());
+        self
+    }
+
+    /// Registers a network that does not show up in scans
+    pub fn with_hidden_network(mut self, ssid: &str, passphrase: &str) -> Self {
+        self.passphrases
+            .insert(ssid.to_string(), passphrase.to_string());
+        self
+    }
+
+    /// Adds a profile saved before the portal started
+    pub fn with_saved_network(mut self, ssid: &str) -> Self {
+        self.saved_connections.push(ssid.to_string());
+        self
+    }
+
+    /// Fails to report the connectivity once connected
+    pub fn with_connectivity_failure(mut self) -> Self {
+        self.connectivity_fails = true;
+        self
+    }
+
+    /// Lets the device scan while the hotspot is up
+    pub fn with_portal_scan(mut self) -> Self {
+        self.portal_scan = true;
+        self
+    }
+
+    pub fn with_connected_connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.connected_connectivity = connectivity;
+        self
+    }
+
+    /// Connectivity of other devices, e.g. Ethernet, while the hotspot is up
+    pub fn with_portal_connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.portal_connectivity = connectivity;
+        self
+    }
+
+    pub fn set_connectivity(&mut self, connectivity: Connectivity) {
+        self.connectivity = connectivity;
+    }
+
+    pub fn portal_ssid(&self) -> Option<&str> {
+        self.portal_ssid.as_ref().map(|ssid| ssid as &str)
+    }
+
+    /// Highest autoconnect priority first
+    pub fn saved_connections(&self) -> &[String] {
+        &self.saved_connections
+    }
+}
+
+impl WifiBackend for FakeBackend {
+    fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPoint>> {
+        // Real hardware cannot scan while the hotspot is up
+        if self.portal_ssid.is_some() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self.access_points.clone())
+    }
+
+    fn scan_in_portal(&mut self) -> Result<Vec<AccessPoint>> {
+        if !self.portal_scan {
+            bail!(ErrorKind::PortalScan);
+        }
+
+        Ok(self.access_points.clone())
+    }
+
+    fn create_portal(
+        &mut self,
+        ssid: &str,
+        _security: PortalSecurity,
+        _passphrase: Option<&str>,
+        _gateway: Ipv4Addr,
+    ) -> Result<()> {
+        self.portal_ssid = Some(ssid.to_string());
+        self.portal_profiles.push(ssid.to_string());
+        self.connectivity = self.portal_connectivity;
+        Ok(())
+    }
+
+    fn stop_portal(&mut self) -> Result<()> {
+        if let Some(ssid) = self.portal_ssid.take() {
+            self.portal_profiles.retain(|profile| *profile != ssid);
+        }
+
+        Ok(())
+    }
+
+    fn delete_portal_profiles(&mut self, ssid: &SsidTemplate) -> Result<()> {
+        self.portal_profiles
+            .retain(|profile| !ssid.matches(profile));
+        Ok(())
+    }
+
+    fn delete_wifi_connections(&mut self) -> Result<()> {
+        self.saved_connections = self.connection.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        access_point: &AccessPoint,
+        _identity: &str,
+        passphrase: &str,
+        _enterprise: &EnterpriseSettings,
+        _ip: &IpSettings,
+    ) -> Result<()> {
+        let accepted = match self.passphrases.get(&access_point.ssid) {
+            Some(expected) => !access_point.security.needs_passphrase() || expected == passphrase,
+            None => bail!(ErrorKind::AccessPointNotFound(access_point.ssid.clone())),
+        };
+
+        if !accepted {
+            bail!(ErrorKind::WrongPassphrase(access_point.ssid.clone()));
+        }
+
+        self.saved_connections.push(access_point.ssid.clone());
+        self.connection = Some(access_point.ssid.clone());
+        self.connectivity = self.connected_connectivity;
+
+        Ok(())
+    }
+
+    fn connect_hidden(
+        &mut self,
+        ssid: &str,
+        security: Security,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        let access_point = AccessPoint {
+            ssid: ssid.to_string(),
+            security,
+            strength: 0,
+            frequency: None,
+        };
+
+        self.connect(&access_point, identity, passphrase, enterprise, ip)
+    }
+
+    fn forget_connection(&mut self) -> Result<()> {
+        if let Some(ssid) = self.connection.take() {
+            if let Some(index) = self
+                .saved_connections
+                .iter()
+                .rposition(|saved| *saved == ssid)
+            {
+                self.saved_connections.remove(index);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn prioritize_connection(&mut self) -> Result<()> {
+        if let Some(ref ssid) = self.connection {
+            if let Some(index) = self
+                .saved_connections
+                .iter()
+                .rposition(|saved| saved == ssid)
+            {
+                let saved = self.saved_connections.remove(index);
+                self.saved_connections.insert(0, saved);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn saved_networks(&mut self) -> Result<Vec<SavedNetwork>> {
+        let count = self.saved_connections.len() as i32;
+
+        Ok(self
+            .saved_connections
+            .iter()
+            .enumerate()
+            .map(|(index, ssid)| SavedNetwork {
+                ssid: ssid.clone(),
+                priority: Some(count - index as i32),
+            })
+            .collect())
+    }
+
+    fn delete_saved_network(&mut self, ssid: &str) -> Result<()> {
+        if !self.saved_connections.iter().any(|saved| saved == ssid) {
+            bail!(ErrorKind::SavedNetworkNotFound(ssid.into()));
+        }
+
+        self.saved_connections.retain(|saved| saved != ssid);
+
+        if self
+            .connection
+            .as_ref()
+            .map_or(false, |connection| connection == ssid)
+        {
+            self.connection = None;
+        }
+
+        Ok(())
+    }
+
+    fn set_saved_network_priority(&mut self, ssid: &str, priority: i32) -> Result<()> {
+        let index = match self
+            .saved_connections
+            .iter()
+            .position(|saved| saved == ssid)
+        {
+            Some(index) => index,
+            None => bail!(ErrorKind::SavedNetworkNotFound(ssid.into())),
+        };
+
+        // Priorities are positions counted from the end of the list
+        let saved = self.saved_connections.remove(index);
+        let count = self.saved_connections.len() as i32 + 1;
+        let index = (count - priority).max(0).min(count - 1) as usize;
+        self.saved_connections.insert(index, saved);
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        self.connectivity = Connectivity::None;
+        Ok(())
+    }
+
+    fn is_associated(&self) -> Result<bool> {
+        Ok(self.portal_ssid.is_none()
+            && self.connection.is_some()
+            && self.connectivity != Connectivity::None)
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        if self.connectivity_fails && self.connection.is_some() {
+            bail!("Connectivity unavailable");
+        }
+
+        Ok(self.connectivity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn office() -> FakeBackend {
+        FakeBackend::new("wlan0")
+            .with_network("Office", Security::Wpa, 80, "secret")
+            .with_network("Guest", Security::None, 40, "")
+    }
+
+    fn access_point(backend: &mut FakeBackend, ssid: &str) -> AccessPoint {
+        backend
+            .scan()
+            .unwrap()
+            .into_iter()
+            .find(|access_point| access_point.ssid == ssid)
+            .unwrap()
+    }
+
+    fn connect(backend: &mut FakeBackend, ssid: &str, passphrase: &str) -> Result<()> {
+        let access_point = access_point(backend, ssid);
+
+        backend.connect(
+            &access_point,
+            "",
+            passphrase,
+            &EnterpriseSettings::default(),
+            &IpSettings::default(),
+        )
+    }
+
+    #[test]
+    fn connect_checks_passphrase() {
+        let mut backend = office();
+
+        match connect(&mut backend, "Office", "wrong") {
+            Err(Error(ErrorKind::WrongPassphrase(ssid), _)) => assert_eq!(ssid, "Office"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        connect(&mut backend, "Guest", "anything").unwrap();
+
+        assert_eq!(backend.saved_connections(), ["Guest"]);
+        assert!(backend.is_associated().unwrap());
+    }
+
+    #[test]
+    fn portal_hides_networks() {
+        let mut backend = office();
+
+        backend
+            .create_portal(
+                "WiFi Connect",
+                PortalSecurity::Wpa2,
+                None,
+                Ipv4Addr::new(192, 168, 42, 1),
+            )
+            .unwrap();
+
+        assert_eq!(backend.portal_ssid(), Some("WiFi Connect"));
+        assert!(backend.scan().unwrap().is_empty());
+        assert!(backend.scan_in_portal().is_err());
+
+        backend.stop_portal().unwrap();
+
+        assert_eq!(backend.portal_ssid(), None);
+        assert_eq!(backend.scan().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn prioritize_moves_connection_first() {
+        let mut backend = office();
+
+        connect(&mut backend, "Guest", "").unwrap();
+        connect(&mut backend, "Office", "secret").unwrap();
+        backend.prioritize_connection().unwrap();
+
+        assert_eq!(backend.saved_connections(), ["Office", "Guest"]);
+
+        backend.set_saved_network_priority("Guest", 2).unwrap();
+
+        assert_eq!(backend.saved_connections(), ["Guest", "Office"]);
+    }
+
+    #[test]
+    fn lost_connectivity_is_not_associated() {
+        let mut backend = office();
+
+        connect(&mut backend, "Office", "secret").unwrap();
+        backend.set_connectivity(Connectivity::None);
+
+        assert!(!backend.is_associated().unwrap());
+        assert_eq!(backend.connectivity().unwrap(), Connectivity::None);
+    }
+}
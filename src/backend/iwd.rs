@@ -0,0 +1,1410 @@
+use std::fs;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path as FsPath, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use dbus::arg::{Array, Dict, Iter, Variant};
+use dbus::{BusType, Connection, Message, Path};
+
+use backend::{
+    dbm_to_strength, has_default_route, set_interface_address, AccessPoint, Connectivity,
+    EapMethod, EnterpriseSettings, IpSettings, Phase2Auth, PortalSecurity, SavedNetwork, Security,
+    WifiBackend, SCAN_TIMEOUT,
+};
+use errors::*;
+use ssid::SsidTemplate;
+
+const IWD_SERVICE: &str = "net.connman.iwd";
+const DEVICE_INTERFACE: &str = "net.connman.iwd.Device";
+const STATION_INTERFACE: &str = "net.connman.iwd.Station";
+const NETWORK_INTERFACE: &str = "net.connman.iwd.Network";
+const KNOWN_NETWORK_INTERFACE: &str = "net.connman.iwd.KnownNetwork";
+const ACCESS_POINT_INTERFACE: &str = "net.connman.iwd.AccessPoint";
+const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+const IWD_FAILED_ERROR: &str = "net.connman.iwd.Failed";
+const IWD_NOT_FOUND_ERROR: &str = "net.connman.iwd.NotFound";
+
+const IWD_STORAGE_DIRECTORY: &str = "/var/lib/iwd";
+const IWD_MAIN_CONFIG: &str = "/etc/iwd/main.conf";
+
+// Milliseconds
+const METHOD_TIMEOUT: i32 = 5_000;
+const CONNECT_TIMEOUT: i32 = 60_000;
+
+// Seconds
+const MODE_CHANGE_TIMEOUT: u64 = 5;
+
+/// Backend driving iwd through its `net.connman.iwd` D-Bus API.
+///
+/// iwd asks an agent for secrets, so credentials are instead provided by
+/// writing a network provisioning file to the iwd storage directory before
+/// calling `Network.Connect`.
+pub struct IwdBackend {
+    connection: Connection,
+    interface: String,
+    device_path: String,
+    /// Where iwd reads network provisioning files from
+    storage_directory: PathBuf,
+    portal_gateway: Option<Ipv4Addr>,
+    client_network: Option<ClientNetwork>,
+}
+
+/// Network connected by `connect`, with the provisioning file it replaced
+struct ClientNetwork {
+    ssid: String,
+    provisioning: Option<Provisioning>,
+}
+
+/// Provisioning file written by `connect` and the previous contents of a file
+/// of the same network
+struct Provisioning {
+    path: PathBuf,
+    previous: Option<Vec<u8>>,
+}
+
+impl Provisioning {
+    fn restore(&self) -> Result<()> {
+        match self.previous {
+            Some(ref previous) => write_private_file(&self.path, previous),
+            None if self.path.exists() => Ok(fs::remove_file(&self.path)?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl IwdBackend {
+    /// `bus` is `BusType::System` for the iwd daemon
+    pub fn new(bus: BusType, interface: &Option<String>) -> Result<Self> {
+        let connection = Connection::get_private(bus)?;
+
+        let mut backend = IwdBackend {
+            connection,
+            interface: String::new(),
+            device_path: String::new(),
+            storage_directory: PathBuf::from(IWD_STORAGE_DIRECTORY),
+            portal_gateway: None,
+            client_network: None,
+        };
+
+        let (device_path, device_interface) = backend.find_device(interface)?;
+
+        info!("WiFi device: {}", device_interface);
+
+        backend.device_path = device_path;
+        backend.interface = device_interface;
+
+        Ok(backend)
+    }
+
+    /// Provisions networks in `storage_directory` instead of the one of the
+    /// iwd daemon, for a mock service
+    #[cfg(test)]
+    fn with_storage_directory(mut self, storage_directory: PathBuf) -> Self {
+        self.storage_directory = storage_directory;
+        self
+    }
+
+    fn find_device(&self, interface: &Option<String>) -> Result<(String, String)> {
+        for path in self.objects_with_interface(DEVICE_INTERFACE)? {
+            let name: String = self.get_property(&path, DEVICE_INTERFACE, "Name")?;
+
+            match *interface {
+                Some(ref interface) if *interface != name => continue,
+                _ => return Ok((path, name)),
+            }
+        }
+
+        match *interface {
+            Some(ref interface) => bail!(ErrorKind::DeviceByInterface(interface.clone())),
+            None => bail!(ErrorKind::NoWiFiDevice),
+        }
+    }
+
+    fn method_call(&self, path: &str, interface: &str, method: &str) -> Result<Message> {
+        Ok(Message::new_method_call(
+            IWD_SERVICE,
+            path,
+            interface,
+            method,
+        )?)
+    }
+
+    fn send(&self, message: Message, timeout: i32) -> Result<Message> {
+        Ok(self
+            .connection
+            .send_with_reply_and_block(message, timeout)?)
+    }
+
+    fn call(&self, path: &str, interface: &str, method: &str) -> Result<Message> {
+        let message = self.method_call(path, interface, method)?;
+        self.send(message, METHOD_TIMEOUT)
+    }
+
+    fn get_property<T>(&self, path: &str, interface: &str, property: &str) -> Result<T>
+    where
+        T: for<'a> ::dbus::arg::Get<'a> + ::dbus::arg::Arg,
+    {
+        let message = self
+            .method_call(path, PROPERTIES_INTERFACE, "Get")?
+            .append2(interface, property);
+        let reply = self.send(message, METHOD_TIMEOUT)?;
+
+        let value: Variant<T> = reply
+            .read1()
+            .chain_err(|| ErrorKind::IwdProperty(property.into()))?;
+
+        Ok(value.0)
+    }
+
+    fn set_property(&self, path: &str, interface: &str, property: &str, value: &str) -> Result<()> {
+        let message = self
+            .method_call(path, PROPERTIES_INTERFACE, "Set")?
+            .append3(interface, property, Variant(value));
+        self.send(message, METHOD_TIMEOUT)?;
+
+        Ok(())
+    }
+
+    /// Object paths of all iwd objects implementing `interface`
+    fn objects_with_interface(&self, interface: &str) -> Result<Vec<String>> {
+        let reply = self.call("/", OBJECT_MANAGER_INTERFACE, "GetManagedObjects")?;
+
+        let objects: Dict<Path, Dict<&str, Dict<&str, Variant<Iter>, Iter>, Iter>, Iter> = reply
+            .read1()
+            .chain_err(|| ErrorKind::IwdProperty("GetManagedObjects".into()))?;
+
+        let mut paths = Vec::new();
+
+        for (path, mut interfaces) in objects {
+            if interfaces.any(|(name, _)| name == interface) {
+                paths.push(path.to_string());
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    fn device_has_interface(&self, interface: &str) -> Result<bool> {
+        Ok(self
+            .objects_with_interface(interface)?
+            .contains(&self.device_path))
+    }
+
+    /// Switches the device between `station` and `ap` mode and waits for
+    /// iwd to expose the corresponding interface
+    fn set_mode(&self, mode: &str, interface: &str) -> Result<()> {
+        if self.device_has_interface(interface)? {
+            return Ok(());
+        }
+
+        debug!("Switching {} to {} mode", self.interface, mode);
+
+        self.set_property(&self.device_path, DEVICE_INTERFACE, "Mode", mode)?;
+
+        for _ in 0..MODE_CHANGE_TIMEOUT * 10 {
+            if self.device_has_interface(interface)? {
+                return Ok(());
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        bail!(ErrorKind::IwdMode(mode.into()))
+    }
+
+    fn request_scan(&self) -> Result<()> {
+        self.set_mode("station", STATION_INTERFACE)?;
+
+        // Scan fails with `net.connman.iwd.Busy` if a scan is already ongoing,
+        // in which case waiting for it to complete is good enough
+        if let Err(e) = self.call(&self.device_path, STATION_INTERFACE, "Scan") {
+            debug!("Requesting scan failed: {}", e);
+        }
+
+        self.wait_for_scan(STATION_INTERFACE)
+    }
+
+    /// Waits until the `Scanning` property of the station or access point
+    /// `interface` is cleared
+    fn wait_for_scan(&self, interface: &str) -> Result<()> {
+        for _ in 0..SCAN_TIMEOUT * 2 {
+            let scanning: bool = self.get_property(&self.device_path, interface, "Scanning")?;
+
+            if !scanning {
+                return Ok(());
+            }
+
+            thread::sleep(Duration::from_millis(500));
+        }
+
+        warn!("Timeout reached in waiting for scan to complete");
+
+        Ok(())
+    }
+
+    /// Network object paths together with their signal strength in 100 * dBm
+    fn ordered_networks(&self) -> Result<Vec<(String, i16)>> {
+        let reply = self.call(&self.device_path, STATION_INTERFACE, "GetOrderedNetworks")?;
+
+        let networks: Array<(Path, i16), Iter> = reply
+            .read1()
+            .chain_err(|| ErrorKind::IwdProperty("GetOrderedNetworks".into()))?;
+
+        Ok(networks
+            .map(|(path, signal)| (path.to_string(), signal))
+            .collect())
+    }
+
+    /// Sends a `Connect` or `ConnectHiddenNetwork` `message` for
+    /// `access_point`, restoring the provisioning file if it fails
+    fn send_connect(
+        &mut self,
+        access_point: &AccessPoint,
+        provisioning: Option<Provisioning>,
+        message: Message,
+    ) -> Result<()> {
+        if let Err(e) = self
+            .connection
+            .send_with_reply_and_block(message, CONNECT_TIMEOUT)
+        {
+            warn!(
+                "Error connecting to access point '{}': {}",
+                access_point.ssid, e
+            );
+
+            if let Some(provisioning) = provisioning {
+                if let Err(e) = provisioning.restore() {
+                    error!("Restoring provisioning file failed: {}", e);
+                }
+            }
+
+            // Hidden networks are only found when connecting
+            if e.name() == Some(IWD_NOT_FOUND_ERROR) {
+                bail!(ErrorKind::AccessPointNotFound(access_point.ssid.clone()));
+            }
+
+            // iwd reports failed handshakes of secured networks as a plain
+            // `Failed`
+            if e.name() == Some(IWD_FAILED_ERROR) && access_point.security.needs_passphrase() {
+                bail!(ErrorKind::WrongPassphrase(access_point.ssid.clone()));
+            }
+
+            bail!(ErrorKind::WiFiConnectionFailed);
+        }
+
+        self.client_network = Some(ClientNetwork {
+            ssid: access_point.ssid.clone(),
+            provisioning,
+        });
+
+        Ok(())
+    }
+
+    fn find_network(&self, ssid: &str) -> Result<Option<String>> {
+        for (path, _) in self.ordered_networks()? {
+            let name: String = self.get_property(&path, NETWORK_INTERFACE, "Name")?;
+
+            if name == ssid {
+                return Ok(Some(path));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl WifiBackend for IwdBackend {
+    fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPoint>> {
+        self.request_scan()?;
+
+        let mut access_points = Vec::new();
+
+        for (path, signal) in self.ordered_networks()? {
+            let ssid: String = self.get_property(&path, NETWORK_INTERFACE, "Name")?;
+            let kind: String = self.get_property(&path, NETWORK_INTERFACE, "Type")?;
+
+            access_points.push(AccessPoint {
+                ssid,
+                security: get_security(&kind),
+                strength: dbm_to_strength(i32::from(signal) / 100),
+                // Networks are listed with the signal of their best access point
+                frequency: None,
+            });
+        }
+
+        Ok(access_points)
+    }
+
+    fn scan_in_portal(&mut self) -> Result<Vec<AccessPoint>> {
+        // Older iwd versions cannot scan in AP mode and do not know the method
+        self.call(&self.device_path, ACCESS_POINT_INTERFACE, "Scan")
+            .chain_err(|| ErrorKind::PortalScan)?;
+
+        self.wait_for_scan(ACCESS_POINT_INTERFACE)?;
+
+        let reply = self.call(
+            &self.device_path,
+            ACCESS_POINT_INTERFACE,
+            "GetOrderedNetworks",
+        )?;
+
+        let networks: Array<Dict<&str, Variant<Iter>, Iter>, Iter> = reply
+            .read1()
+            .chain_err(|| ErrorKind::IwdProperty("GetOrderedNetworks".into()))?;
+
+        let mut access_points = Vec::new();
+
+        for network in networks {
+            let mut ssid = None;
+            let mut signal = None;
+            let mut kind = None;
+
+            for (key, mut value) in network {
+                match key {
+                    "Name" => ssid = value.0.get::<&str>().map(|name| name.to_string()),
+                    "SignalStrength" => signal = value.0.get::<i16>(),
+                    "Type" => kind = value.0.get::<&str>().map(|kind| kind.to_string()),
+                    _ => {}
+                }
+            }
+
+            if let (Some(ssid), Some(signal), Some(kind)) = (ssid, signal, kind) {
+                access_points.push(AccessPoint {
+                    ssid,
+                    security: get_security(&kind),
+                    strength: dbm_to_strength(i32::from(signal) / 100),
+                    frequency: None,
+                });
+            }
+        }
+
+        Ok(access_points)
+    }
+
+    fn create_portal(
+        &mut self,
+        ssid: &str,
+        security: PortalSecurity,
+        passphrase: Option<&str>,
+        gateway: Ipv4Addr,
+    ) -> Result<()> {
+        // iwd only supports WPA2 protected access points
+        if security != PortalSecurity::Wpa2 {
+            bail!(ErrorKind::UnsupportedByBackend(
+                "iwd".into(),
+                "WPA3 and OWE access points".into()
+            ));
+        }
+
+        let passphrase = match passphrase {
+            Some(passphrase) => passphrase,
+            None => bail!(ErrorKind::UnsupportedByBackend(
+                "iwd".into(),
+                "open access points".into()
+            )),
+        };
+
+        self.set_mode("ap", ACCESS_POINT_INTERFACE)?;
+
+        let message = self
+            .method_call(&self.device_path, ACCESS_POINT_INTERFACE, "Start")?
+            .append2(ssid, passphrase);
+        self.send(message, METHOD_TIMEOUT)?;
+
+        set_interface_address(&self.interface, "add", gateway)?;
+        self.portal_gateway = Some(gateway);
+
+        Ok(())
+    }
+
+    fn stop_portal(&mut self) -> Result<()> {
+        if let Some(gateway) = self.portal_gateway.take() {
+            self.call(&self.device_path, ACCESS_POINT_INTERFACE, "Stop")?;
+
+            if let Err(e) = set_interface_address(&self.interface, "del", gateway) {
+                warn!("Removing access point address failed: {}", e);
+            }
+
+            self.set_mode("station", STATION_INTERFACE)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete_portal_profiles(&mut self, _ssid: &SsidTemplate) -> Result<()> {
+        // Access points are started with an explicit SSID and passphrase, so
+        // iwd keeps no profile for them
+        Ok(())
+    }
+
+    fn delete_wifi_connections(&mut self) -> Result<()> {
+        for path in self.objects_with_interface(KNOWN_NETWORK_INTERFACE)? {
+            let name: String = self.get_property(&path, KNOWN_NETWORK_INTERFACE, "Name")?;
+
+            if let Some(ref client_network) = self.client_network {
+                if client_network.ssid == name {
+                    continue;
+                }
+            }
+
+            info!("Deleting existing WiFi connection: {:?}", name);
+
+            if let Err(e) = self.call(&path, KNOWN_NETWORK_INTERFACE, "Forget") {
+                error!("Deleting existing WiFi connection failed: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        require_network_configuration(ip)?;
+
+        let network_path = match self.find_network(&access_point.ssid)? {
+            Some(network_path) => network_path,
+            None => bail!(ErrorKind::AccessPointNotFound(access_point.ssid.clone())),
+        };
+
+        let provisioning = write_provisioning_file(
+            &self.storage_directory,
+            access_point,
+            identity,
+            passphrase,
+            enterprise,
+            ip,
+            false,
+        )?;
+
+        let message = self.method_call(&network_path, NETWORK_INTERFACE, "Connect")?;
+
+        self.send_connect(access_point, provisioning, message)
+    }
+
+    fn connect_hidden(
+        &mut self,
+        ssid: &str,
+        security: Security,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        require_network_configuration(ip)?;
+
+        let access_point = AccessPoint {
+            ssid: ssid.to_string(),
+            security,
+            strength: 0,
+            frequency: None,
+        };
+
+        let provisioning = write_provisioning_file(
+            &self.storage_directory,
+            &access_point,
+            identity,
+            passphrase,
+            enterprise,
+            ip,
+            true,
+        )?;
+
+        let message = self
+            .method_call(&self.device_path, STATION_INTERFACE, "ConnectHiddenNetwork")?
+            .append1(ssid);
+
+        self.send_connect(&access_point, provisioning, message)
+    }
+
+    fn forget_connection(&mut self) -> Result<()> {
+        let client_network = match self.client_network.take() {
+            Some(client_network) => client_network,
+            None => return Ok(()),
+        };
+
+        for path in self.objects_with_interface(KNOWN_NETWORK_INTERFACE)? {
+            let name: String = self.get_property(&path, KNOWN_NETWORK_INTERFACE, "Name")?;
+
+            if name == client_network.ssid {
+                info!("Deleting WiFi connection: {:?}", name);
+                self.call(&path, KNOWN_NETWORK_INTERFACE, "Forget")?;
+            }
+        }
+
+        // iwd picks up the previous file again from its storage directory
+        if let Some(provisioning) = client_network.provisioning {
+            provisioning.restore()?;
+        }
+
+        Ok(())
+    }
+
+    fn prioritize_connection(&mut self) -> Result<()> {
+        // iwd has no autoconnect priorities and prefers the most recently
+        // connected known network, which is the new one
+        Ok(())
+    }
+
+    fn saved_networks(&mut self) -> Result<Vec<SavedNetwork>> {
+        let mut saved_networks = Vec::new();
+
+        for path in self.objects_with_interface(KNOWN_NETWORK_INTERFACE)? {
+            saved_networks.push(SavedNetwork {
+                ssid: self.get_property(&path, KNOWN_NETWORK_INTERFACE, "Name")?,
+                priority: None,
+            });
+        }
+
+        Ok(saved_networks)
+    }
+
+    fn delete_saved_network(&mut self, ssid: &str) -> Result<()> {
+        let mut found = false;
+
+        for path in self.objects_with_interface(KNOWN_NETWORK_INTERFACE)? {
+            let name: String = self.get_property(&path, KNOWN_NETWORK_INTERFACE, "Name")?;
+
+            if name == ssid {
+                info!("Deleting saved WiFi connection: {:?}", name);
+                self.call(&path, KNOWN_NETWORK_INTERFACE, "Forget")?;
+                found = true;
+            }
+        }
+
+        if !found {
+            bail!(ErrorKind::SavedNetworkNotFound(ssid.into()));
+        }
+
+        let is_client_network = self
+            .client_network
+            .as_ref()
+            .map_or(false, |client_network| client_network.ssid == ssid);
+
+        if is_client_network {
+            self.client_network = None;
+        }
+
+        Ok(())
+    }
+
+    fn set_saved_network_priority(&mut self, _ssid: &str, _priority: i32) -> Result<()> {
+        bail!(ErrorKind::UnsupportedByBackend(
+            "iwd".into(),
+            "network priorities".into()
+        ))
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        if self.device_has_interface(STATION_INTERFACE)? {
+            self.call(&self.device_path, STATION_INTERFACE, "Disconnect")?;
+        }
+
+        Ok(())
+    }
+
+    fn is_associated(&self) -> Result<bool> {
+        // There is no station interface in AP mode
+        if !self.device_has_interface(STATION_INTERFACE)? {
+            return Ok(false);
+        }
+
+        let state: String = self.get_property(&self.device_path, STATION_INTERFACE, "State")?;
+
+        Ok(state == "connected")
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        if !self.is_associated()? {
+            Ok(Connectivity::None)
+        } else if has_default_route(&self.interface)? {
+            Ok(Connectivity::Full)
+        } else {
+            Ok(Connectivity::Limited)
+        }
+    }
+}
+
+fn get_security(kind: &str) -> Security {
+    match kind {
+        "8021x" => Security::Enterprise,
+        "psk" => Security::Wpa,
+        "wep" => Security::Wep,
+        _ => Security::None,
+    }
+}
+
+/// Writes the iwd network configuration file holding the credentials for
+/// `access_point` to `directory`, keeping the contents of a file it
+/// overwrites. Open networks need no file unless they are hidden, otherwise
+/// `None` is returned.
+fn write_provisioning_file(
+    directory: &FsPath,
+    access_point: &AccessPoint,
+    identity: &str,
+    passphrase: &str,
+    enterprise: &EnterpriseSettings,
+    ip: &IpSettings,
+    hidden: bool,
+) -> Result<Option<Provisioning>> {
+    check_provisioning_values(identity, passphrase, enterprise)?;
+
+    let (extension, mut contents) = match access_point.security {
+        // iwd uses OWE on its own where the access point offers it
+        Security::None | Security::Owe if hidden || !ip.is_automatic() => ("open", String::new()),
+        Security::None | Security::Owe => return Ok(None),
+        // iwd uses SAE on its own where the access point offers it
+        Security::Wpa | Security::Wpa3 | Security::Wpa2Wpa3 => {
+            ("psk", format!("[Security]\nPassphrase={}\n", passphrase))
+        }
+        Security::Enterprise => ("8021x", eap_settings(identity, passphrase, enterprise)),
+        Security::Wep => bail!(ErrorKind::UnsupportedByBackend(
+            "iwd".into(),
+            "WEP networks".into()
+        )),
+    };
+
+    if hidden {
+        contents.push_str("[Settings]\nHidden=true\n");
+    }
+
+    contents.push_str(&ip_settings(ip));
+
+    let mut path = directory.to_path_buf();
+    path.push(format!(
+        "{}.{}",
+        encode_ssid_file_name(&access_point.ssid),
+        extension
+    ));
+
+    let previous = fs::read(&path).ok();
+
+    write_private_file(&path, contents.as_bytes())?;
+
+    Ok(Some(Provisioning { path, previous }))
+}
+
+/// Values are written unquoted into the INI file, so a line break would
+/// start a new key or section of the client's choosing
+fn check_provisioning_values(
+    identity: &str,
+    passphrase: &str,
+    enterprise: &EnterpriseSettings,
+) -> Result<()> {
+    let values = [
+        ("identity", Some(identity)),
+        ("passphrase", Some(passphrase)),
+        (
+            "anonymous identity",
+            enterprise.anonymous_identity.as_ref().map(|s| s as &str),
+        ),
+        (
+            "domain",
+            enterprise.domain_suffix_match.as_ref().map(|s| s as &str),
+        ),
+    ];
+
+    for &(name, value) in &values {
+        if let Some(value) = value {
+            if value.contains(&['\n', '\r', '\0'][..]) {
+                bail!(ErrorKind::InvalidProvisioningValue(name.into()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Static addresses and DNS servers are applied by iwd itself, which it only
+/// does with `EnableNetworkConfiguration` set in its main configuration.
+/// Otherwise the DHCP client of the system manages addresses.
+fn require_network_configuration(ip: &IpSettings) -> Result<()> {
+    if ip.is_automatic() {
+        return Ok(());
+    }
+
+    let enabled = fs::read_to_string(IWD_MAIN_CONFIG)
+        .map(|config| network_configuration_enabled(&config))
+        .unwrap_or(false);
+
+    if !enabled {
+        bail!(ErrorKind::UnsupportedByBackend(
+            "iwd".into(),
+            "static IP configuration without EnableNetworkConfiguration".into()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether `EnableNetworkConfiguration` is set in the `[General]` section of
+/// iwd's main configuration `config`
+fn network_configuration_enabled(config: &str) -> bool {
+    let mut section = "";
+    let mut enabled = false;
+
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            section = &line[1..line.len() - 1];
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            if section == "General" && key.trim() == "EnableNetworkConfiguration" {
+                let value = value.trim();
+                enabled = value == "true" || value == "1";
+            }
+        }
+    }
+
+    enabled
+}
+
+/// `[IPv4]` and `[IPv6]` sections, only used if iwd's own network
+/// configuration is enabled
+fn ip_settings(ip: &IpSettings) -> String {
+    let mut settings = String::new();
+
+    let dns_v4: Vec<String> = ip.dns_v4().iter().map(ToString::to_string).collect();
+    let dns_v6: Vec<String> = ip.dns_v6().iter().map(ToString::to_string).collect();
+
+    if ip.ipv4.is_some() || !dns_v4.is_empty() {
+        settings.push_str("[IPv4]\n");
+
+        if let Some(ref ipv4) = ip.ipv4 {
+            settings.push_str(&format!(
+                "Address={}\nNetmask={}\n",
+                ipv4.address,
+                ipv4.netmask()
+            ));
+
+            if let Some(gateway) = ipv4.gateway {
+                settings.push_str(&format!("Gateway={}\n", gateway));
+            }
+        }
+
+        if !dns_v4.is_empty() {
+            settings.push_str(&format!("DNS={}\n", dns_v4.join(" ")));
+        }
+    }
+
+    if ip.ipv6.is_some() || !dns_v6.is_empty() {
+        settings.push_str("[IPv6]\nEnabled=true\n");
+
+        if let Some(ref ipv6) = ip.ipv6 {
+            settings.push_str(&format!("Address={}/{}\n", ipv6.address, ipv6.prefix));
+
+            if let Some(gateway) = ipv6.gateway {
+                settings.push_str(&format!("Gateway={}\n", gateway));
+            }
+        }
+
+        if !dns_v6.is_empty() {
+            settings.push_str(&format!("DNS={}\n", dns_v6.join(" ")));
+        }
+    }
+
+    settings
+}
+
+/// `[Security]` section of an 802.1X network
+fn eap_settings(identity: &str, passphrase: &str, enterprise: &EnterpriseSettings) -> String {
+    let method = match enterprise.eap {
+        EapMethod::Peap => "PEAP",
+        EapMethod::Ttls => "TTLS",
+        EapMethod::Tls => "TLS",
+    };
+
+    let outer_identity = enterprise
+        .anonymous_identity
+        .as_ref()
+        .map_or(identity, |identity| identity as &str);
+
+    let mut settings = format!(
+        "[Security]\nEAP-Method={}\nEAP-Identity={}\n",
+        method, outer_identity
+    );
+
+    if let Some(ref ca_cert) = enterprise.ca_cert {
+        settings.push_str(&format!("EAP-{}-CACert={}\n", method, ca_cert.display()));
+    }
+
+    // Masks match a single label per wildcard, so subdomains are listed
+    // separately
+    if let Some(ref domain) = enterprise.domain_suffix_match {
+        settings.push_str(&format!(
+            "EAP-{0}-ServerDomainMask={1};*.{1}\n",
+            method, domain
+        ));
+    }
+
+    match enterprise.phase2() {
+        Some(phase2) => {
+            let phase2_method = match (enterprise.eap, phase2) {
+                (EapMethod::Ttls, Phase2Auth::Mschapv2) => "Tunneled-MSCHAPv2",
+                (EapMethod::Ttls, Phase2Auth::Mschap) => "Tunneled-MSCHAP",
+                (EapMethod::Ttls, Phase2Auth::Pap) => "Tunneled-PAP",
+                (EapMethod::Ttls, Phase2Auth::Chap) => "Tunneled-CHAP",
+                (_, Phase2Auth::Gtc) => "GTC",
+                (_, Phase2Auth::Md5) => "MD5",
+                _ => "MSCHAPV2",
+            };
+
+            settings.push_str(&format!(
+                "EAP-{0}-Phase2-Method={1}\n\
+                 EAP-{0}-Phase2-Identity={2}\n\
+                 EAP-{0}-Phase2-Password={3}\n",
+                method, phase2_method, identity, passphrase
+            ));
+        }
+        None => {
+            if let Some(ref client_cert) = enterprise.client_cert {
+                settings.push_str(&format!("EAP-TLS-ClientCert={}\n", client_cert.display()));
+            }
+
+            if let Some(ref private_key) = enterprise.private_key {
+                settings.push_str(&format!("EAP-TLS-ClientKey={}\n", private_key.display()));
+            }
+
+            if !passphrase.is_empty() {
+                settings.push_str(&format!("EAP-TLS-ClientKeyPassphrase={}\n", passphrase));
+            }
+        }
+    }
+
+    settings
+}
+
+fn write_private_file(path: &FsPath, contents: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+
+    Ok(())
+}
+
+/// SSIDs with characters other than alphanumerics, ` `, `-` and `_` are stored
+/// by iwd hex encoded with a leading `=`
+fn encode_ssid_file_name(ssid: &str) -> String {
+    let is_plain = ssid
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_');
+
+    if is_plain {
+        ssid.to_string()
+    } else {
+        let hex: String = ssid.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("={}", hex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::thread::JoinHandle;
+
+    use dbus::{ConnectionItem, NameFlag};
+
+    use super::*;
+
+    const MOCK_DEVICE_PATH: &str = "/net/connman/iwd/0/3";
+    const MOCK_INTERFACE: &str = "wlan-mock";
+
+    const MOCK_NETWORKS: [MockNetwork; 2] = [
+        MockNetwork {
+            name: "Office",
+            kind: "psk",
+            signal: -5000,
+            passphrase: Some("secret12"),
+        },
+        MockNetwork {
+            name: "Guest",
+            kind: "open",
+            signal: -7000,
+            passphrase: None,
+        },
+    ];
+
+    /// Only one mock can own the `net.connman.iwd` name at a time
+    static MOCK_LOCK: Mutex<()> = Mutex::new(());
+
+    struct MockNetwork {
+        name: &'static str,
+        kind: &'static str,
+        signal: i16,
+        passphrase: Option<&'static str>,
+    }
+
+    impl MockNetwork {
+        fn path(&self) -> String {
+            format!("{}/{}_{}", MOCK_DEVICE_PATH, self.name, self.kind)
+        }
+
+        fn known_path(&self) -> String {
+            format!("/net/connman/iwd/{}_{}", self.name, self.kind)
+        }
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        access_point_mode: bool,
+        known: Vec<&'static str>,
+        connected: Option<&'static str>,
+        access_point: Option<(String, String)>,
+    }
+
+    /// `net.connman.iwd` service on the session bus with a single device
+    /// seeing `MOCK_NETWORKS`. Connecting succeeds if the provisioning file
+    /// holds the passphrase of the network.
+    struct MockIwd {
+        state: Arc<Mutex<MockState>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl MockIwd {
+        /// `None` if there is no session bus to run the mock on
+        fn start(storage_directory: &FsPath) -> Option<MockIwd> {
+            let lock = MOCK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+            let state = Arc::new(Mutex::new(MockState::default()));
+            let stop = Arc::new(AtomicBool::new(false));
+            let (ready_tx, ready_rx) = mpsc::channel();
+
+            let thread = {
+                let state = state.clone();
+                let stop = stop.clone();
+                let storage_directory = storage_directory.to_path_buf();
+                thread::spawn(move || serve(&state, &stop, &storage_directory, &ready_tx))
+            };
+
+            if !ready_rx.recv().unwrap_or(false) {
+                thread.join().unwrap();
+                return None;
+            }
+
+            Some(MockIwd {
+                state,
+                stop,
+                thread: Some(thread),
+                _lock: lock,
+            })
+        }
+
+        fn state(&self) -> MutexGuard<'_, MockState> {
+            self.state.lock().unwrap()
+        }
+    }
+
+    impl Drop for MockIwd {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn serve(
+        state: &Mutex<MockState>,
+        stop: &AtomicBool,
+        storage_directory: &FsPath,
+        ready: &Sender<bool>,
+    ) {
+        let connection = match Connection::get_private(BusType::Session) {
+            Ok(connection) => connection,
+            Err(_) => {
+                ready.send(false).unwrap();
+                return;
+            }
+        };
+
+        let flags = NameFlag::ReplaceExisting as u32 | NameFlag::DoNotQueue as u32;
+        connection.register_name(IWD_SERVICE, flags).unwrap();
+
+        let mut paths = vec!["/".to_string(), MOCK_DEVICE_PATH.to_string()];
+
+        for network in &MOCK_NETWORKS {
+            paths.push(network.path());
+            paths.push(network.known_path());
+        }
+
+        for path in &paths {
+            connection.register_object_path(path).unwrap();
+        }
+
+        ready.send(true).unwrap();
+
+        while !stop.load(Ordering::SeqCst) {
+            for item in connection.incoming(100) {
+                if let ConnectionItem::MethodCall(call) = item {
+                    let reply = state.lock().unwrap().reply(&call, storage_directory);
+                    connection.send(reply).unwrap();
+                }
+
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+        }
+    }
+
+    impl MockState {
+        fn reply(&mut self, call: &Message, storage_directory: &FsPath) -> Message {
+            let path = call.path().map(|path| path.to_string()).unwrap_or_default();
+            let interface = call
+                .interface()
+                .map(|interface| interface.to_string())
+                .unwrap_or_default();
+            let member = call
+                .member()
+                .map(|member| member.to_string())
+                .unwrap_or_default();
+
+            match (&interface as &str, &member as &str) {
+                (OBJECT_MANAGER_INTERFACE, "GetManagedObjects") => {
+                    call.method_return().append1(self.managed_objects())
+                }
+                (PROPERTIES_INTERFACE, "Get") => {
+                    let (_, property): (&str, &str) = call.read2().unwrap();
+                    self.property(call, &path, property)
+                }
+                (PROPERTIES_INTERFACE, "Set") => {
+                    let (_, _, mode): (&str, &str, Variant<&str>) = call.read3().unwrap();
+                    self.access_point_mode = mode.0 == "ap";
+                    call.method_return()
+                }
+                (STATION_INTERFACE, "Scan") => call.method_return(),
+                (STATION_INTERFACE, "GetOrderedNetworks") => {
+                    let networks: Vec<(Path<'static>, i16)> = MOCK_NETWORKS
+                        .iter()
+                        .map(|network| (Path::from(network.path()), network.signal))
+                        .collect();
+                    call.method_return().append1(networks)
+                }
+                (NETWORK_INTERFACE, "Connect") => self.connect(call, &path, storage_directory),
+                (ACCESS_POINT_INTERFACE, "Start") => {
+                    let (ssid, passphrase): (&str, &str) = call.read2().unwrap();
+                    self.access_point = Some((ssid.into(), passphrase.into()));
+                    call.method_return()
+                }
+                _ => Message::new_error(call, "org.freedesktop.DBus.Error.UnknownMethod", &member)
+                    .unwrap(),
+            }
+        }
+
+        fn managed_objects(
+            &self,
+        ) -> HashMap<Path<'static>, HashMap<String, HashMap<String, Variant<String>>>> {
+            let mode_interface = if self.access_point_mode {
+                ACCESS_POINT_INTERFACE
+            } else {
+                STATION_INTERFACE
+            };
+
+            let mut objects = HashMap::new();
+            objects.insert(
+                Path::from(MOCK_DEVICE_PATH),
+                interfaces(&[DEVICE_INTERFACE, mode_interface]),
+            );
+
+            for network in &MOCK_NETWORKS {
+                objects.insert(Path::from(network.path()), interfaces(&[NETWORK_INTERFACE]));
+
+                if self.known.contains(&network.name) {
+                    objects.insert(
+                        Path::from(network.known_path()),
+                        interfaces(&[KNOWN_NETWORK_INTERFACE]),
+                    );
+                }
+            }
+
+            objects
+        }
+
+        fn property(&self, call: &Message, path: &str, property: &str) -> Message {
+            let network = MOCK_NETWORKS
+                .iter()
+                .find(|network| network.path() == path || network.known_path() == path);
+
+            match (property, network) {
+                ("Name", Some(network)) => call.method_return().append1(Variant(network.name)),
+                ("Name", None) => call.method_return().append1(Variant(MOCK_INTERFACE)),
+                ("Type", Some(network)) => call.method_return().append1(Variant(network.kind)),
+                ("Scanning", None) => call.method_return().append1(Variant(false)),
+                ("State", None) => {
+                    let state = match self.connected {
+                        Some(_) => "connected",
+                        None => "disconnected",
+                    };
+                    call.method_return().append1(Variant(state))
+                }
+                _ => Message::new_error(call, "org.freedesktop.DBus.Error.InvalidArgs", property)
+                    .unwrap(),
+            }
+        }
+
+        fn connect(&mut self, call: &Message, path: &str, storage_directory: &FsPath) -> Message {
+            let network = MOCK_NETWORKS
+                .iter()
+                .find(|network| network.path() == path)
+                .unwrap();
+
+            if let Some(passphrase) = network.passphrase {
+                let file = storage_directory.join(format!("{}.psk", network.name));
+                let contents = fs::read_to_string(file).unwrap_or_default();
+
+                if !contents.contains(&format!("Passphrase={}\n", passphrase)) {
+                    return Message::new_error(call, IWD_FAILED_ERROR, "Operation failed").unwrap();
+                }
+            }
+
+            if !self.known.contains(&network.name) {
+                self.known.push(network.name);
+            }
+
+            self.connected = Some(network.name);
+
+            call.method_return()
+        }
+    }
+
+    fn interfaces(names: &[&str]) -> HashMap<String, HashMap<String, Variant<String>>> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), HashMap::new()))
+            .collect()
+    }
+
+    fn storage_directory(test: &str) -> PathBuf {
+        let directory =
+            env::temp_dir().join(format!("wifi-connect-iwd-{}-{}", test, process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn mock_backend(storage_directory: &FsPath) -> IwdBackend {
+        IwdBackend::new(BusType::Session, &None)
+            .unwrap()
+            .with_storage_directory(storage_directory.to_path_buf())
+    }
+
+    fn access_point(ssid: &str, security: Security) -> AccessPoint {
+        AccessPoint {
+            ssid: ssid.into(),
+            security,
+            strength: 0,
+            frequency: None,
+        }
+    }
+
+    fn connect_to(
+        backend: &mut IwdBackend,
+        ssid: &str,
+        security: Security,
+        passphrase: &str,
+    ) -> Result<()> {
+        backend.connect(
+            &access_point(ssid, security),
+            "",
+            passphrase,
+            &EnterpriseSettings::default(),
+            &IpSettings::default(),
+        )
+    }
+
+    // The mock tests need a session bus and pass without one
+    #[test]
+    fn scan_lists_networks() {
+        let directory = storage_directory("scan");
+        let _mock = match MockIwd::start(&directory) {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        let mut backend = mock_backend(&directory);
+        assert_eq!(backend.interface(), MOCK_INTERFACE);
+
+        let access_points = backend.scan().unwrap();
+
+        assert_eq!(
+            access_points,
+            [
+                AccessPoint {
+                    strength: dbm_to_strength(-50),
+                    ..access_point("Office", Security::Wpa)
+                },
+                AccessPoint {
+                    strength: dbm_to_strength(-70),
+                    ..access_point("Guest", Security::None)
+                },
+            ]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn connect_provisions_network() {
+        let directory = storage_directory("connect");
+        let _mock = match MockIwd::start(&directory) {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        let mut backend = mock_backend(&directory);
+
+        connect_to(&mut backend, "Office", Security::Wpa, "secret12").unwrap();
+
+        let contents = fs::read_to_string(directory.join("Office.psk")).unwrap();
+        assert_eq!(contents, "[Security]\nPassphrase=secret12\n");
+
+        assert!(backend.is_associated().unwrap());
+
+        let saved: Vec<String> = backend
+            .saved_networks()
+            .unwrap()
+            .into_iter()
+            .map(|network| network.ssid)
+            .collect();
+        assert_eq!(saved, ["Office"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase_restores_provisioning_file() {
+        let directory = storage_directory("wrong-passphrase");
+        let _mock = match MockIwd::start(&directory) {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        let previous = "[Security]\nPassphrase=previous\n";
+        fs::write(directory.join("Office.psk"), previous).unwrap();
+
+        let mut backend = mock_backend(&directory);
+
+        match connect_to(&mut backend, "Office", Security::Wpa, "wrong123") {
+            Err(Error(ErrorKind::WrongPassphrase(ssid), _)) => assert_eq!(ssid, "Office"),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let contents = fs::read_to_string(directory.join("Office.psk")).unwrap();
+        assert_eq!(contents, previous);
+        assert!(!backend.is_associated().unwrap());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn create_portal_starts_access_point() {
+        let directory = storage_directory("portal");
+        let mock = match MockIwd::start(&directory) {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        let mut backend = mock_backend(&directory);
+
+        // There is no `wlan-mock` interface to add the gateway address to
+        match backend.create_portal(
+            "WiFi Connect",
+            PortalSecurity::Wpa2,
+            Some("portal12"),
+            Ipv4Addr::new(192, 168, 42, 1),
+        ) {
+            Ok(())
+            | Err(Error(ErrorKind::InterfaceAddress(_), _))
+            | Err(Error(ErrorKind::Io(_), _)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        assert!(mock.state().access_point_mode);
+        assert_eq!(
+            mock.state().access_point,
+            Some(("WiFi Connect".into(), "portal12".into()))
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn plain_ssid_file_name() {
+        assert_eq!(encode_ssid_file_name("My_Network-5G"), "My_Network-5G");
+        assert_eq!(encode_ssid_file_name("My Network"), "My Network");
+    }
+
+    #[test]
+    fn encoded_ssid_file_name() {
+        assert_eq!(encode_ssid_file_name("a.b"), "=612e62");
+        assert_eq!(encode_ssid_file_name("caf\u{e9}"), "=636166c3a9");
+    }
+
+    #[test]
+    fn network_configuration_setting() {
+        assert!(network_configuration_enabled(
+            "[General]\nEnableNetworkConfiguration=true\n"
+        ));
+        assert!(network_configuration_enabled(
+            "# Comment\n[Scan]\nDisablePeriodicScan=true\n\n[General]\n EnableNetworkConfiguration = 1\n"
+        ));
+
+        assert!(!network_configuration_enabled(""));
+        assert!(!network_configuration_enabled(
+            "[General]\nEnableNetworkConfiguration=false\n"
+        ));
+        assert!(!network_configuration_enabled(
+            "[Network]\nEnableNetworkConfiguration=true\n"
+        ));
+        assert!(!network_configuration_enabled(
+            "[General]\n#EnableNetworkConfiguration=true\n"
+        ));
+    }
+
+    #[test]
+    fn rejects_line_breaks() {
+        let enterprise = EnterpriseSettings::default();
+
+        assert!(check_provisioning_values("user", "secret", &enterprise).is_ok());
+        assert!(check_provisioning_values("user", "secret\n[Settings]", &enterprise).is_err());
+        assert!(check_provisioning_values("user\r", "secret", &enterprise).is_err());
+        assert!(check_provisioning_values("user", "sec\0ret", &enterprise).is_err());
+
+        let enterprise = EnterpriseSettings {
+            domain_suffix_match: Some("example.com\nEAP-Method=MD5".into()),
+            ..EnterpriseSettings::default()
+        };
+
+        assert!(check_provisioning_values("user", "secret", &enterprise).is_err());
+    }
+}
@@ -0,0 +1,770 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use dbus::BusType;
+use nix::unistd::geteuid;
+
+use config::Config;
+use errors::*;
+use ssid::SsidTemplate;
+
+// Not selectable at runtime, drives the network thread in tests
+#[cfg(test)]
+pub mod fake;
+pub mod iwd;
+pub mod nm;
+pub mod wpa_ctrl;
+pub mod wpa_supplicant;
+
+/// Holds files with secrets and control sockets, unlike `/tmp` it cannot be
+/// prepared by other users
+pub const RUNTIME_DIRECTORY: &str = "/run/wifi-connect";
+
+/// Seconds a scan may take in any backend, during which the network thread
+/// does not pick up requests
+pub const SCAN_TIMEOUT: u64 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    NetworkManager,
+    Iwd,
+    WpaSupplicant,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "network-manager" => Ok(BackendKind::NetworkManager),
+            "iwd" => Ok(BackendKind::Iwd),
+            "wpa-supplicant" => Ok(BackendKind::WpaSupplicant),
+            _ => Err(format!("Unknown backend '{}'", s)),
+        }
+    }
+}
+
+impl BackendKind {
+    /// Fails for portal access points the backend cannot create, so that
+    /// they are rejected at startup rather than when the portal starts
+    pub fn check_portal_security(
+        &self,
+        security: PortalSecurity,
+        has_passphrase: bool,
+    ) -> Result<()> {
+        let unsupported = match (*self, security) {
+            // NetworkManager configures access points with a single key
+            // management
+            (BackendKind::NetworkManager, PortalSecurity::Wpa2Wpa3) => {
+                Some(("network-manager", "WPA2/WPA3 transition access points"))
+            }
+            // iwd only supports WPA2 protected access points
+            (BackendKind::Iwd, PortalSecurity::Wpa2) if !has_passphrase => {
+                Some(("iwd", "open access points"))
+            }
+            (BackendKind::Iwd, PortalSecurity::Wpa2Wpa3)
+            | (BackendKind::Iwd, PortalSecurity::Owe) => {
+                Some(("iwd", "WPA3 and OWE access points"))
+            }
+            _ => None,
+        };
+
+        if let Some((backend, feature)) = unsupported {
+            bail!(ErrorKind::UnsupportedByBackend(
+                backend.into(),
+                feature.into()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// What happens to the saved client WiFi profiles when a new network is
+/// connected. Existing profiles are never touched before the new connection
+/// works, so a failed attempt leaves them as they were.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SavedProfiles {
+    /// Deletes all other profiles once connected
+    Replace,
+    /// Keeps all profiles and gives the new one the highest autoconnect
+    /// priority
+    Keep,
+}
+
+impl FromStr for SavedProfiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(SavedProfiles::Replace),
+            "keep" => Ok(SavedProfiles::Keep),
+            _ => Err(format!("Unknown saved profiles mode '{}'", s)),
+        }
+    }
+}
+
+/// Security of a scanned access point, independent of the backend in use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+    None,
+    Wep,
+    /// WPA or WPA2 personal
+    Wpa,
+    /// WPA3 personal (SAE) only
+    Wpa3,
+    /// WPA2/WPA3 transition mode, accepting both PSK and SAE
+    Wpa2Wpa3,
+    /// Opportunistic Wireless Encryption, encrypted without a passphrase
+    Owe,
+    Enterprise,
+}
+
+impl FromStr for Security {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Security::None),
+            "wep" => Ok(Security::Wep),
+            "wpa" => Ok(Security::Wpa),
+            "wpa3" => Ok(Security::Wpa3),
+            "wpa2-wpa3" => Ok(Security::Wpa2Wpa3),
+            "owe" => Ok(Security::Owe),
+            "enterprise" => Ok(Security::Enterprise),
+            _ => Err(format!("Unknown security '{}'", s)),
+        }
+    }
+}
+
+impl Security {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Security::None => "none",
+            Security::Wep => "wep",
+            Security::Wpa => "wpa",
+            Security::Wpa3 => "wpa3",
+            Security::Wpa2Wpa3 => "wpa2-wpa3",
+            Security::Owe => "owe",
+            Security::Enterprise => "enterprise",
+        }
+    }
+
+    pub fn needs_passphrase(&self) -> bool {
+        match *self {
+            Security::None | Security::Owe => false,
+            _ => true,
+        }
+    }
+}
+
+/// EAP method of an enterprise network
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EapMethod {
+    Peap,
+    Ttls,
+    Tls,
+}
+
+impl FromStr for EapMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "peap" => Ok(EapMethod::Peap),
+            "ttls" => Ok(EapMethod::Ttls),
+            "tls" => Ok(EapMethod::Tls),
+            _ => Err(format!("Unknown EAP method '{}'", s)),
+        }
+    }
+}
+
+impl EapMethod {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EapMethod::Peap => "peap",
+            EapMethod::Ttls => "ttls",
+            EapMethod::Tls => "tls",
+        }
+    }
+}
+
+/// Inner authentication of PEAP and TTLS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase2Auth {
+    Mschapv2,
+    Mschap,
+    Pap,
+    Chap,
+    Gtc,
+    Md5,
+}
+
+impl FromStr for Phase2Auth {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "mschapv2" => Ok(Phase2Auth::Mschapv2),
+            "mschap" => Ok(Phase2Auth::Mschap),
+            "pap" => Ok(Phase2Auth::Pap),
+            "chap" => Ok(Phase2Auth::Chap),
+            "gtc" => Ok(Phase2Auth::Gtc),
+            "md5" => Ok(Phase2Auth::Md5),
+            _ => Err(format!("Unknown phase 2 authentication '{}'", s)),
+        }
+    }
+}
+
+impl Phase2Auth {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Phase2Auth::Mschapv2 => "mschapv2",
+            Phase2Auth::Mschap => "mschap",
+            Phase2Auth::Pap => "pap",
+            Phase2Auth::Chap => "chap",
+            Phase2Auth::Gtc => "gtc",
+            Phase2Auth::Md5 => "md5",
+        }
+    }
+}
+
+/// 802.1X settings of an enterprise network besides the identity and the
+/// passphrase, which is the private key password with TLS. The defaults are
+/// PEAP with MSCHAPv2 and no server validation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnterpriseSettings {
+    pub eap: EapMethod,
+    /// Defaults to MSCHAPv2, unused with TLS
+    pub phase2: Option<Phase2Auth>,
+    /// Outer identity of PEAP and TTLS, sent unencrypted
+    pub anonymous_identity: Option<String>,
+    /// Server certificates must be issued for this domain or a subdomain
+    pub domain_suffix_match: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+}
+
+impl Default for EnterpriseSettings {
+    fn default() -> Self {
+        EnterpriseSettings {
+            eap: EapMethod::Peap,
+            phase2: None,
+            anonymous_identity: None,
+            domain_suffix_match: None,
+            ca_cert: None,
+            client_cert: None,
+            private_key: None,
+        }
+    }
+}
+
+impl EnterpriseSettings {
+    /// Phase 2 authentication of PEAP and TTLS, `None` for TLS
+    pub fn phase2(&self) -> Option<Phase2Auth> {
+        match self.eap {
+            EapMethod::Tls => None,
+            _ => Some(self.phase2.unwrap_or(Phase2Auth::Mschapv2)),
+        }
+    }
+
+    /// Checks the combination of the EAP method with the other settings
+    pub fn validate(&self) -> ::std::result::Result<(), String> {
+        if let Some(phase2) = self.phase2 {
+            let valid = match phase2 {
+                Phase2Auth::Mschapv2 => self.eap != EapMethod::Tls,
+                Phase2Auth::Gtc | Phase2Auth::Md5 => self.eap == EapMethod::Peap,
+                Phase2Auth::Mschap | Phase2Auth::Pap | Phase2Auth::Chap => {
+                    self.eap == EapMethod::Ttls
+                }
+            };
+
+            if !valid {
+                return Err(format!(
+                    "Phase 2 authentication '{}' cannot be used with EAP method '{}'",
+                    phase2.as_str(),
+                    self.eap.as_str()
+                ));
+            }
+        }
+
+        let has_client_cert = self.client_cert.is_some() && self.private_key.is_some();
+
+        if self.eap == EapMethod::Tls && !has_client_cert {
+            return Err("EAP method 'tls' requires a client certificate and a private key".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Static IPv4 address of a client connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(prefix_mask_v4(self.prefix))
+    }
+
+    fn validate(&self) -> ::std::result::Result<(), String> {
+        let mask = prefix_mask_v4(self.prefix);
+        let address = u32::from(self.address);
+
+        if !is_unicast_v4(self.address) {
+            return Err(format!("'{}' is not a host address", self.address));
+        }
+
+        // /31 and /32 networks have no network and broadcast addresses
+        if self.prefix < 31 && (address & !mask == 0 || address | mask == !0) {
+            return Err(format!(
+                "'{}/{}' is the network or broadcast address of its subnet",
+                self.address, self.prefix
+            ));
+        }
+
+        if let Some(gateway) = self.gateway {
+            if !is_unicast_v4(gateway) || gateway == self.address {
+                return Err(format!("'{}' is not a valid gateway", gateway));
+            }
+
+            if u32::from(gateway) & mask != address & mask {
+                return Err(format!(
+                    "Gateway '{}' is outside of '{}/{}'",
+                    gateway, self.address, self.prefix
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Static IPv6 address of a client connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv6Config {
+    pub address: Ipv6Addr,
+    pub prefix: u8,
+    pub gateway: Option<Ipv6Addr>,
+}
+
+impl Ipv6Config {
+    fn validate(&self) -> ::std::result::Result<(), String> {
+        if !is_unicast_v6(self.address) {
+            return Err(format!("'{}' is not a host address", self.address));
+        }
+
+        if let Some(gateway) = self.gateway {
+            if !is_unicast_v6(gateway) || gateway == self.address {
+                return Err(format!("'{}' is not a valid gateway", gateway));
+            }
+
+            // Routers are usually reached by their link-local address
+            let mask = prefix_mask_v6(self.prefix);
+            let is_link_local = gateway.segments()[0] & 0xffc0 == 0xfe80;
+
+            if !is_link_local && u128::from(gateway) & mask != u128::from(self.address) & mask {
+                return Err(format!(
+                    "Gateway '{}' is neither link-local nor in '{}/{}'",
+                    gateway, self.address, self.prefix
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// IP configuration of a client connection. Families without a static
+/// address are configured automatically, DNS servers replace automatic ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpSettings {
+    pub ipv4: Option<Ipv4Config>,
+    pub ipv6: Option<Ipv6Config>,
+    pub dns: Vec<IpAddr>,
+}
+
+impl IpSettings {
+    pub fn is_automatic(&self) -> bool {
+        self.ipv4.is_none() && self.ipv6.is_none() && self.dns.is_empty()
+    }
+
+    pub fn dns_v4(&self) -> Vec<Ipv4Addr> {
+        self.dns
+            .iter()
+            .filter_map(|server| match *server {
+                IpAddr::V4(server) => Some(server),
+                IpAddr::V6(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn dns_v6(&self) -> Vec<Ipv6Addr> {
+        self.dns
+            .iter()
+            .filter_map(|server| match *server {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(server) => Some(server),
+            })
+            .collect()
+    }
+
+    /// Checks that addresses, gateways and DNS servers can be used together
+    pub fn validate(&self) -> ::std::result::Result<(), String> {
+        if let Some(ref ipv4) = self.ipv4 {
+            ipv4.validate()?;
+        }
+
+        if let Some(ref ipv6) = self.ipv6 {
+            ipv6.validate()?;
+        }
+
+        for server in &self.dns {
+            let valid = match *server {
+                IpAddr::V4(server) => is_unicast_v4(server),
+                IpAddr::V6(server) => is_unicast_v6(server),
+            };
+
+            if !valid {
+                return Err(format!("'{}' is not a valid DNS server", server));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn prefix_mask_v4(prefix: u8) -> u32 {
+    match prefix {
+        0 => 0,
+        prefix => !0 << (32 - u32::from(prefix.min(32))),
+    }
+}
+
+fn prefix_mask_v6(prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => !0 << (128 - u32::from(prefix.min(128))),
+    }
+}
+
+fn is_unicast_v4(address: Ipv4Addr) -> bool {
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_multicast()
+        || address.is_broadcast())
+}
+
+fn is_unicast_v6(address: Ipv6Addr) -> bool {
+    !(address.is_unspecified() || address.is_loopback() || address.is_multicast())
+}
+
+/// Security of the portal access point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortalSecurity {
+    /// WPA2 personal with a passphrase, open without
+    Wpa2,
+    /// WPA2/WPA3 transition mode, so that WPA3 capable clients use SAE. Only
+    /// the `wpa-supplicant` backend, through hostapd, offers it.
+    Wpa2Wpa3,
+    /// Encrypted without a passphrase, clients not supporting OWE cannot join.
+    /// Not offered by the `iwd` backend.
+    Owe,
+}
+
+impl FromStr for PortalSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "wpa2" => Ok(PortalSecurity::Wpa2),
+            "wpa2-wpa3" => Ok(PortalSecurity::Wpa2Wpa3),
+            "owe" => Ok(PortalSecurity::Owe),
+            _ => Err(format!("Unknown portal security '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessPoint {
+    pub ssid: String,
+    pub security: Security,
+    pub strength: u32,
+    /// Channel frequency in MHz, `None` for backends listing networks rather
+    /// than access points
+    pub frequency: Option<u32>,
+}
+
+/// Client WiFi profile saved on the device. Secrets are never read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SavedNetwork {
+    pub ssid: String,
+    /// Autoconnect priority, `None` for backends without priorities
+    pub priority: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connectivity {
+    Unknown,
+    None,
+    Portal,
+    Limited,
+    Full,
+}
+
+/// Operations the portal lifecycle needs from the system WiFi stack.
+///
+/// Implementations own any handles to the underlying service, so a backend
+/// is created and used on the network thread only.
+pub trait WifiBackend {
+    /// Name of the wireless interface driven by the backend
+    fn interface(&self) -> &str;
+
+    /// Returns the access points currently visible to the device. The list
+    /// may contain hidden and duplicate SSIDs.
+    fn scan(&mut self) -> Result<Vec<AccessPoint>>;
+
+    /// Scans while the portal hotspot is up, which only works with hardware
+    /// supporting concurrent AP and station operation. Fails with
+    /// `PortalScan` if the device cannot scan in AP mode.
+    fn scan_in_portal(&mut self) -> Result<Vec<AccessPoint>>;
+
+    /// Brings up the captive portal hotspot. `passphrase` is set for the
+    /// `Wpa2Wpa3` and unset for the `Owe` portal security, backends failing
+    /// with `UnsupportedByBackend` for the ones they cannot run.
+    fn create_portal(
+        &mut self,
+        ssid: &str,
+        security: PortalSecurity,
+        passphrase: Option<&str>,
+        gateway: Ipv4Addr,
+    ) -> Result<()>;
+
+    /// Tears down the hotspot created by `create_portal`
+    fn stop_portal(&mut self) -> Result<()>;
+
+    /// Deletes portal profiles left over by previous runs, with any
+    /// expansion of the SSID template
+    fn delete_portal_profiles(&mut self, ssid: &SsidTemplate) -> Result<()>;
+
+    /// Deletes all saved client WiFi profiles except the one added by the
+    /// last successful `connect`
+    fn delete_wifi_connections(&mut self) -> Result<()>;
+
+    /// Connects to `access_point` and fails with `WiFiConnectionFailed` if
+    /// the connection could not be activated, or with `WrongPassphrase` or
+    /// `AccessPointNotFound` where the backend can tell the cause. Other
+    /// saved profiles are left in place, also when connecting fails.
+    /// `enterprise` is only used for `Security::Enterprise` networks, `ip`
+    /// is saved with the profile.
+    fn connect(
+        &mut self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()>;
+
+    /// Connects to a hidden network, which is not in the scan results, by
+    /// adding a profile marked as hidden. Fails like `connect`.
+    fn connect_hidden(
+        &mut self,
+        ssid: &str,
+        security: Security,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()>;
+
+    /// Deletes the profile added by the last successful `connect`, restoring
+    /// a saved profile of the same network it replaced
+    fn forget_connection(&mut self) -> Result<()>;
+
+    /// Gives the profile added by the last successful `connect` a higher
+    /// autoconnect priority than all other saved profiles
+    fn prioritize_connection(&mut self) -> Result<()>;
+
+    /// Lists the saved client WiFi profiles
+    fn saved_networks(&mut self) -> Result<Vec<SavedNetwork>>;
+
+    /// Deletes all saved client WiFi profiles of `ssid` and fails with
+    /// `SavedNetworkNotFound` if there are none
+    fn delete_saved_network(&mut self, ssid: &str) -> Result<()>;
+
+    /// Sets the autoconnect priority of all saved client WiFi profiles of
+    /// `ssid` and fails with `SavedNetworkNotFound` if there are none
+    fn set_saved_network_priority(&mut self, ssid: &str, priority: i32) -> Result<()>;
+
+    /// Disconnects the active client connection without deleting its profile
+    fn disconnect(&mut self) -> Result<()>;
+
+    /// Whether the interface is associated with an access point as a client,
+    /// regardless of its addresses. Never while the portal is up.
+    fn is_associated(&self) -> Result<bool>;
+
+    fn connectivity(&self) -> Result<Connectivity>;
+}
+
+pub fn create_backend(config: &Config) -> Result<Box<dyn WifiBackend>> {
+    match config.backend {
+        BackendKind::NetworkManager => {
+            let backend = nm::NetworkManagerBackend::new(&config.interface)?;
+            Ok(Box::new(backend))
+        }
+        BackendKind::Iwd => {
+            let backend = iwd::IwdBackend::new(BusType::System, &config.interface)?;
+            Ok(Box::new(backend))
+        }
+        BackendKind::WpaSupplicant => {
+            let backend = wpa_supplicant::WpaSupplicantBackend::new(
+                Path::new(wpa_supplicant::DEFAULT_CTRL_DIRECTORY),
+                &config.interface,
+            )?;
+            Ok(Box::new(backend))
+        }
+    }
+}
+
+/// Checks `/proc/net/route` for a default route through `interface`, for
+/// backends that cannot report Internet connectivity themselves
+pub fn has_default_route(interface: &str) -> Result<bool> {
+    let routes = BufReader::new(File::open("/proc/net/route")?);
+
+    for line in routes.lines().skip(1) {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+
+        if fields.next() == Some(interface) && fields.next() == Some("00000000") {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Whether `interface` has an IPv4 or IPv6 address that is not link-local
+pub fn has_global_address(interface: &str) -> Result<bool> {
+    let output = Command::new("ip")
+        .args(&["-o", "address", "show", "dev", interface, "scope", "global"])
+        .output()?;
+
+    Ok(output.status.success() && !output.stdout.is_empty())
+}
+
+/// Creates `RUNTIME_DIRECTORY` if missing and checks that it is a directory
+/// only accessible by us
+pub fn runtime_directory() -> Result<PathBuf> {
+    let path = PathBuf::from(RUNTIME_DIRECTORY);
+
+    match fs::DirBuilder::new().mode(0o700).create(&path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(e).chain_err(|| ErrorKind::RuntimeDirectory(RUNTIME_DIRECTORY.into()))
+        }
+    }
+
+    let metadata = fs::symlink_metadata(&path)
+        .chain_err(|| ErrorKind::RuntimeDirectory(RUNTIME_DIRECTORY.into()))?;
+
+    if !metadata.is_dir() || metadata.uid() != geteuid().as_raw() || metadata.mode() & 0o077 != 0 {
+        bail!(ErrorKind::RuntimeDirectory(RUNTIME_DIRECTORY.into()));
+    }
+
+    Ok(path)
+}
+
+/// Adds (`action` = `add`) or removes (`del`) the portal gateway address on
+/// `interface` for backends that leave IP configuration to WiFi Connect
+pub fn set_interface_address(interface: &str, action: &str, gateway: Ipv4Addr) -> Result<()> {
+    let status = Command::new("ip")
+        .args(&[
+            "address",
+            action,
+            &format!("{}/24", gateway),
+            "dev",
+            interface,
+        ])
+        .status()?;
+
+    if !status.success() {
+        bail!(ErrorKind::InterfaceAddress(interface.into()));
+    }
+
+    Ok(())
+}
+
+/// Maps a signal level in dBm to a 0-100 quality
+pub fn dbm_to_strength(dbm: i32) -> u32 {
+    let quality = 2 * (dbm + 100);
+
+    quality.max(0).min(100) as u32
+}
+
+/// Band of a channel frequency in MHz, e.g. `5GHz`
+pub fn frequency_band(frequency: u32) -> Option<&'static str> {
+    match frequency {
+        2401..=2495 => Some("2.4GHz"),
+        5150..=5895 => Some("5GHz"),
+        5925..=7125 => Some("6GHz"),
+        _ => None,
+    }
+}
+
+/// Channel number of a frequency in MHz, numbered per band
+pub fn frequency_channel(frequency: u32) -> Option<u32> {
+    match frequency {
+        2484 => Some(14),
+        2412..=2472 => Some((frequency - 2407) / 5),
+        5150..=5895 => Some((frequency - 5000) / 5),
+        // Channel 2 is the only one off the 5 MHz grid of the 6 GHz band
+        5935 => Some(2),
+        5955..=7115 => Some((frequency - 5950) / 5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portal_security_support() {
+        let nm = BackendKind::NetworkManager;
+        assert!(nm
+            .check_portal_security(PortalSecurity::Wpa2, false)
+            .is_ok());
+        assert!(nm.check_portal_security(PortalSecurity::Owe, false).is_ok());
+        assert!(nm
+            .check_portal_security(PortalSecurity::Wpa2Wpa3, true)
+            .is_err());
+
+        let iwd = BackendKind::Iwd;
+        assert!(iwd
+            .check_portal_security(PortalSecurity::Wpa2, true)
+            .is_ok());
+        assert!(iwd
+            .check_portal_security(PortalSecurity::Wpa2, false)
+            .is_err());
+        assert!(iwd
+            .check_portal_security(PortalSecurity::Owe, false)
+            .is_err());
+
+        let wpa_supplicant = BackendKind::WpaSupplicant;
+        assert!(wpa_supplicant
+            .check_portal_security(PortalSecurity::Wpa2Wpa3, true)
+            .is_ok());
+    }
+}
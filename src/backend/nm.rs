@@ -0,0 +1,1146 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path as FsPath;
+use std::thread;
+use std::time::Duration;
+
+use dbus;
+use dbus::arg::{ArgType, Iter, RefArg, Variant};
+use dbus::{BusType, Message, Path};
+use network_manager;
+use network_manager::{
+    AccessPointCredentials, Connection, ConnectionState, Device, DeviceState, DeviceType,
+    NetworkManager, ServiceState,
+};
+
+use backend::{
+    AccessPoint, Connectivity, EnterpriseSettings, IpSettings, PortalSecurity, SavedNetwork,
+    Security, WifiBackend, SCAN_TIMEOUT,
+};
+use errors::*;
+use ssid::SsidTemplate;
+
+const NM_SERVICE: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_DEVICE_INTERFACE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_WIRELESS_INTERFACE: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_ACCESS_POINT_INTERFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const NM_SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+const NM_SETTINGS_INTERFACE: &str = "org.freedesktop.NetworkManager.Settings";
+const NM_CONNECTION_INTERFACE: &str = "org.freedesktop.NetworkManager.Settings.Connection";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+// Settings holding the secrets that `GetSettings` leaves out
+const SECRET_SETTINGS: &[&str] = &["802-11-wireless-security", "802-1x"];
+
+// Milliseconds
+const METHOD_TIMEOUT: i32 = 5_000;
+
+// Seconds
+const ACTIVATION_TIMEOUT: u64 = 60;
+
+// `NMDeviceStateReason` values
+const REASON_IP_CONFIG_UNAVAILABLE: u32 = 5;
+const REASON_NO_SECRETS: u32 = 7;
+const REASON_SUPPLICANT_DISCONNECT: u32 = 8;
+const REASON_SUPPLICANT_TIMEOUT: u32 = 11;
+const REASON_DHCP_START_FAILED: u32 = 15;
+const REASON_DHCP_FAILED: u32 = 17;
+const REASON_SSID_NOT_FOUND: u32 = 53;
+
+// `NM80211ApFlags` and `NM80211ApSecurityFlags` values
+const AP_FLAGS_PRIVACY: u32 = 0x1;
+const AP_SEC_KEY_MGMT_PSK: u32 = 0x100;
+const AP_SEC_KEY_MGMT_802_1X: u32 = 0x200;
+const AP_SEC_KEY_MGMT_SAE: u32 = 0x400;
+const AP_SEC_KEY_MGMT_OWE: u32 = 0x800;
+const AP_SEC_KEY_MGMT_OWE_TM: u32 = 0x1000;
+
+/// Connection settings as passed to `AddAndActivateConnection`
+type Settings = HashMap<&'static str, HashMap<&'static str, Variant<Box<dyn RefArg>>>>;
+
+/// Settings of a saved connection as returned by `GetSettings`
+type SavedSettings = HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>;
+
+pub struct NetworkManagerBackend {
+    manager: NetworkManager,
+    device: Device,
+    portal_connection: Option<Connection>,
+    client_connection: Option<Connection>,
+}
+
+impl NetworkManagerBackend {
+    pub fn new(interface: &Option<String>) -> Result<Self> {
+        start_network_manager_service()?;
+
+        let manager = NetworkManager::new();
+        let device = find_device(&manager, interface)?;
+
+        Ok(NetworkManagerBackend {
+            manager,
+            device,
+            portal_connection: None,
+            client_connection: None,
+        })
+    }
+
+    fn find_access_point(&self, ssid: &str) -> Result<Option<network_manager::AccessPoint>> {
+        let wifi_device = self.device.as_wifi_device().unwrap();
+
+        for access_point in wifi_device.get_access_points()? {
+            if let Ok(access_point_ssid) = access_point.ssid().as_str() {
+                if access_point_ssid == ssid {
+                    return Ok(Some(access_point));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Waits until the device is activated or the activation failed
+    fn wait_for_activation(&self) -> Result<bool> {
+        let mut activating = false;
+
+        for _ in 0..ACTIVATION_TIMEOUT {
+            thread::sleep(Duration::from_secs(1));
+
+            match self.device.get_state()? {
+                DeviceState::Activated => return Ok(true),
+                DeviceState::Failed => return Ok(false),
+                // Still disconnected from the portal right after the request
+                DeviceState::Disconnected if activating => return Ok(false),
+                DeviceState::Disconnected => {}
+                _ => activating = true,
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Error of a failed activation, told apart by the reason of the last
+    /// device state change
+    fn connection_failure(&self, ssid: &str) -> Error {
+        let ssid = ssid.to_string();
+
+        match device_state_reason(self.device.interface()) {
+            Ok(REASON_NO_SECRETS)
+            | Ok(REASON_SUPPLICANT_DISCONNECT)
+            | Ok(REASON_SUPPLICANT_TIMEOUT) => ErrorKind::WrongPassphrase(ssid).into(),
+            Ok(REASON_IP_CONFIG_UNAVAILABLE)
+            | Ok(REASON_DHCP_START_FAILED..=REASON_DHCP_FAILED) => {
+                ErrorKind::DhcpTimeout(ssid).into()
+            }
+            Ok(REASON_SSID_NOT_FOUND) => ErrorKind::AccessPointNotFound(ssid).into(),
+            Ok(reason) => {
+                debug!("Device state reason: {}", reason);
+                ErrorKind::WiFiConnectionFailed.into()
+            }
+            Err(e) => {
+                warn!("Getting device state reason failed: {}", e);
+                ErrorKind::WiFiConnectionFailed.into()
+            }
+        }
+    }
+
+    /// Adds a connection with `settings` and activates it on the device,
+    /// returning it once activated. A connection that failed to activate is
+    /// deleted and `None` returned.
+    fn add_and_activate(&self, settings: Settings, uuid: &str) -> Result<Option<Connection>> {
+        let connection = dbus::Connection::get_private(BusType::System)?;
+        let device_path = device_path(&connection, self.device.interface())?;
+
+        let message =
+            Message::new_method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "AddAndActivateConnection")?
+                .append3(settings, device_path, Path::from("/"));
+
+        connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+
+        let activated = self.wait_for_activation()?;
+
+        let added_connection = self
+            .manager
+            .get_connections()?
+            .into_iter()
+            .find(|connection| connection.settings().uuid == uuid);
+
+        if activated {
+            return Ok(added_connection);
+        }
+
+        if let Some(added_connection) = added_connection {
+            if let Err(err) = added_connection.delete() {
+                error!("Deleting connection object failed: {}", err)
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Connects with settings of our own, for networks the network-manager
+    /// crate cannot connect to
+    fn connect_with_settings(
+        &mut self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+        hidden: bool,
+    ) -> Result<()> {
+        let ssid = &access_point.ssid;
+        let uuid = random_uuid()?;
+        let mut settings = client_connection_settings(
+            ssid,
+            access_point.security,
+            identity,
+            passphrase,
+            enterprise,
+            &uuid,
+            hidden,
+        );
+        insert_ip_settings(&mut settings, ip);
+
+        match self.add_and_activate(settings, &uuid) {
+            Ok(Some(connection)) => {
+                self.client_connection = Some(connection);
+                Ok(())
+            }
+            Ok(None) => Err(self.connection_failure(ssid)),
+            Err(e) => {
+                warn!("Error connecting to access point '{}': {}", ssid, e);
+                bail!(ErrorKind::WiFiConnectionFailed)
+            }
+        }
+    }
+
+    fn client_connections(&self) -> Result<Vec<Connection>> {
+        Ok(self
+            .manager
+            .get_connections()?
+            .into_iter()
+            .filter(|connection| {
+                is_wifi_connection(connection) && !is_access_point_connection(connection)
+            })
+            .collect())
+    }
+
+    /// Client connections of `ssid`, failing with `SavedNetworkNotFound` if
+    /// there are none
+    fn saved_connections(&self, ssid: &str) -> Result<Vec<Connection>> {
+        let connections: Vec<Connection> = self
+            .client_connections()?
+            .into_iter()
+            .filter(|connection| connection_ssid_as_str(connection) == Some(ssid))
+            .collect();
+
+        if connections.is_empty() {
+            bail!(ErrorKind::SavedNetworkNotFound(ssid.into()));
+        }
+
+        Ok(connections)
+    }
+}
+
+impl WifiBackend for NetworkManagerBackend {
+    fn interface(&self) -> &str {
+        self.device.interface()
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPoint>> {
+        let connection = dbus::Connection::get_private(BusType::System)?;
+        let device_path = device_path(&connection, self.device.interface())?;
+
+        let message = Message::new_method_call(
+            NM_SERVICE,
+            device_path,
+            NM_WIRELESS_INTERFACE,
+            "GetAllAccessPoints",
+        )?;
+        let reply = connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+        let paths: Vec<Path> = reply
+            .read1()
+            .chain_err(|| ErrorKind::NetworkManagerProperty("GetAllAccessPoints".into()))?;
+
+        let mut access_points = Vec::new();
+
+        for path in &paths {
+            // Access points may vanish while they are read
+            match read_access_point(&connection, path) {
+                Ok(Some(access_point)) => access_points.push(access_point),
+                Ok(None) => {}
+                Err(e) => debug!("Reading access point {:?} failed: {}", path, e),
+            }
+        }
+
+        Ok(access_points)
+    }
+
+    fn scan_in_portal(&mut self) -> Result<Vec<AccessPoint>> {
+        let connection = dbus::Connection::get_private(BusType::System)?;
+        let device_path = device_path(&connection, self.device.interface())?;
+
+        let last_scan: i64 =
+            get_property(&connection, &device_path, NM_WIRELESS_INTERFACE, "LastScan")?;
+
+        // Refused with `NotAllowed` by devices that cannot scan in AP mode
+        let options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        let message = Message::new_method_call(
+            NM_SERVICE,
+            device_path.clone(),
+            NM_WIRELESS_INTERFACE,
+            "RequestScan",
+        )?
+        .append1(options);
+        connection
+            .send_with_reply_and_block(message, METHOD_TIMEOUT)
+            .chain_err(|| ErrorKind::PortalScan)?;
+
+        for _ in 0..SCAN_TIMEOUT {
+            thread::sleep(Duration::from_secs(1));
+
+            let scanned: i64 =
+                get_property(&connection, &device_path, NM_WIRELESS_INTERFACE, "LastScan")?;
+
+            if scanned != last_scan {
+                return self.scan();
+            }
+        }
+
+        bail!(ErrorKind::PortalScan)
+    }
+
+    fn create_portal(
+        &mut self,
+        ssid: &str,
+        security: PortalSecurity,
+        passphrase: Option<&str>,
+        gateway: Ipv4Addr,
+    ) -> Result<()> {
+        match security {
+            PortalSecurity::Wpa2 => {
+                let wifi_device = self.device.as_wifi_device().unwrap();
+                let (portal_connection, _) =
+                    wifi_device.create_hotspot(ssid, passphrase, Some(gateway))?;
+                self.portal_connection = Some(portal_connection);
+            }
+            // NetworkManager configures access points with a single key
+            // management
+            PortalSecurity::Wpa2Wpa3 => bail!(ErrorKind::UnsupportedByBackend(
+                "network-manager".into(),
+                "WPA2/WPA3 transition access points".into()
+            )),
+            PortalSecurity::Owe => {
+                let uuid = random_uuid()?;
+                let settings = owe_portal_settings(ssid, gateway, &uuid);
+
+                match self.add_and_activate(settings, &uuid)? {
+                    Some(portal_connection) => self.portal_connection = Some(portal_connection),
+                    None => bail!(ErrorKind::CreateCaptivePortal),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn stop_portal(&mut self) -> Result<()> {
+        if let Some(connection) = self.portal_connection.take() {
+            connection.deactivate()?;
+            connection.delete()?;
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        Ok(())
+    }
+
+    fn delete_portal_profiles(&mut self, ssid: &SsidTemplate) -> Result<()> {
+        for connection in &self.manager.get_connections()? {
+            if is_access_point_connection(connection) && is_portal_ssid(connection, ssid) {
+                info!(
+                    "Deleting already created by WiFi Connect access point connection profile: {:?}",
+                    connection.settings().ssid,
+                );
+                connection.delete()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_wifi_connections(&mut self) -> Result<()> {
+        let client_uuid = self
+            .client_connection
+            .as_ref()
+            .map(|connection| connection.settings().uuid.clone());
+
+        for connection in &self.client_connections()? {
+            if Some(&connection.settings().uuid) != client_uuid.as_ref() {
+                info!(
+                    "Deleting existing WiFi connection: {:?}",
+                    connection.settings().ssid,
+                );
+
+                if let Err(e) = connection.delete() {
+                    error!("Deleting existing WiFi connection failed: {}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        // The network-manager crate only knows WPA and WPA2 key management,
+        // PEAP with MSCHAPv2 for 802.1X and automatic addresses
+        let own_settings = match access_point.security {
+            Security::Wpa3 | Security::Owe | Security::Enterprise => true,
+            _ => !ip.is_automatic(),
+        };
+
+        if own_settings {
+            return self.connect_with_settings(
+                access_point,
+                identity,
+                passphrase,
+                enterprise,
+                ip,
+                false,
+            );
+        }
+
+        let nm_access_point = match self.find_access_point(&access_point.ssid)? {
+            Some(nm_access_point) => nm_access_point,
+            None => bail!(ErrorKind::AccessPointNotFound(access_point.ssid.clone())),
+        };
+
+        let wifi_device = self.device.as_wifi_device().unwrap();
+        let credentials = init_access_point_credentials(access_point, identity, passphrase);
+
+        match wifi_device.connect(&nm_access_point, &credentials) {
+            Ok((connection, state)) => {
+                if state == ConnectionState::Activated || state == ConnectionState::Activating {
+                    self.client_connection = Some(connection);
+                    return Ok(());
+                }
+
+                error!("Wrong connection state: {:?}", state);
+
+                // connection not activated - delete
+                if let Err(err) = connection.delete() {
+                    error!("Deleting connection object failed: {}", err)
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Error connecting to access point '{}': {}",
+                    access_point.ssid, e
+                );
+            }
+        }
+
+        Err(self.connection_failure(&access_point.ssid))
+    }
+
+    fn connect_hidden(
+        &mut self,
+        ssid: &str,
+        security: Security,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        let access_point = AccessPoint {
+            ssid: ssid.to_string(),
+            security,
+            strength: 0,
+            frequency: None,
+        };
+
+        // The network-manager crate only connects to scanned access points
+        self.connect_with_settings(&access_point, identity, passphrase, enterprise, ip, true)
+    }
+
+    fn forget_connection(&mut self) -> Result<()> {
+        // Profiles of the same network are separate connections, which were
+        // not touched by `connect`
+        if let Some(connection) = self.client_connection.take() {
+            info!("Deleting WiFi connection: {:?}", connection.settings().ssid);
+            connection.delete()?;
+        }
+
+        Ok(())
+    }
+
+    fn prioritize_connection(&mut self) -> Result<()> {
+        let uuid = match self.client_connection {
+            Some(ref connection) => connection.settings().uuid.clone(),
+            None => return Ok(()),
+        };
+
+        let bus = dbus::Connection::get_private(BusType::System)?;
+
+        let mut priorities = Vec::new();
+        for connection in self.client_connections()? {
+            let other = &connection.settings().uuid;
+            if *other != uuid {
+                priorities.push(autoconnect_priority(&bus, other)?);
+            }
+        }
+
+        let priority = priorities
+            .into_iter()
+            .max()
+            .map_or(0, |priority| priority + 1);
+
+        set_autoconnect_priority(&bus, &uuid, priority)?;
+
+        info!("WiFi connection autoconnect priority set to {}", priority);
+
+        Ok(())
+    }
+
+    fn saved_networks(&mut self) -> Result<Vec<SavedNetwork>> {
+        let bus = dbus::Connection::get_private(BusType::System)?;
+
+        let mut saved = Vec::new();
+        for connection in self.client_connections()? {
+            if let Some(ssid) = connection_ssid_as_str(&connection) {
+                saved.push(SavedNetwork {
+                    ssid: ssid.to_string(),
+                    priority: Some(autoconnect_priority(&bus, &connection.settings().uuid)?),
+                });
+            }
+        }
+
+        Ok(saved)
+    }
+
+    fn delete_saved_network(&mut self, ssid: &str) -> Result<()> {
+        let connections = self.saved_connections(ssid)?;
+
+        for connection in &connections {
+            info!("Deleting saved WiFi connection: {:?}", ssid);
+            connection.delete()?;
+        }
+
+        let is_client_connection = self
+            .client_connection
+            .as_ref()
+            .map_or(false, |client| connection_ssid_as_str(client) == Some(ssid));
+
+        if is_client_connection {
+            self.client_connection = None;
+        }
+
+        Ok(())
+    }
+
+    fn set_saved_network_priority(&mut self, ssid: &str, priority: i32) -> Result<()> {
+        let bus = dbus::Connection::get_private(BusType::System)?;
+
+        for connection in &self.saved_connections(ssid)? {
+            set_autoconnect_priority(&bus, &connection.settings().uuid, priority)?;
+        }
+
+        info!(
+            "Saved WiFi connection {:?} autoconnect priority set to {}",
+            ssid, priority
+        );
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        self.device.disconnect()?;
+        Ok(())
+    }
+
+    fn is_associated(&self) -> Result<bool> {
+        // The device is activated as access point as well
+        if self.portal_connection.is_some() {
+            return Ok(false);
+        }
+
+        match self.device.get_state()? {
+            DeviceState::IpConfig
+            | DeviceState::IpCheck
+            | DeviceState::Secondaries
+            | DeviceState::Activated => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        let connectivity = match self.manager.get_connectivity()? {
+            network_manager::Connectivity::Unknown => Connectivity::Unknown,
+            network_manager::Connectivity::None => Connectivity::None,
+            network_manager::Connectivity::Portal => Connectivity::Portal,
+            network_manager::Connectivity::Limited => Connectivity::Limited,
+            network_manager::Connectivity::Full => Connectivity::Full,
+        };
+
+        Ok(connectivity)
+    }
+}
+
+/// D-Bus object of the saved connection `uuid`
+fn connection_path(connection: &dbus::Connection, uuid: &str) -> Result<Path<'static>> {
+    let message = Message::new_method_call(
+        NM_SERVICE,
+        NM_SETTINGS_PATH,
+        NM_SETTINGS_INTERFACE,
+        "GetConnectionByUuid",
+    )?
+    .append1(uuid);
+    let reply = connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+    let path: Path = reply
+        .read1()
+        .chain_err(|| ErrorKind::NetworkManagerProperty("GetConnectionByUuid".into()))?;
+
+    Ok(path.into_static())
+}
+
+/// Settings of a saved connection by calling `method`, `GetSettings` or
+/// `GetSecrets` with the name of a setting
+fn saved_settings(
+    connection: &dbus::Connection,
+    path: &Path<'static>,
+    method: &str,
+    setting: Option<&str>,
+) -> Result<SavedSettings> {
+    let message =
+        Message::new_method_call(NM_SERVICE, path.clone(), NM_CONNECTION_INTERFACE, method)?;
+    let message = match setting {
+        Some(setting) => message.append1(setting),
+        None => message,
+    };
+    let reply = connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+    let settings: SavedSettings = reply
+        .read1()
+        .chain_err(|| ErrorKind::NetworkManagerProperty(method.into()))?;
+
+    Ok(settings)
+}
+
+/// Autoconnect priority of the saved connection `uuid`. NetworkManager
+/// leaves out the default of 0.
+fn autoconnect_priority(connection: &dbus::Connection, uuid: &str) -> Result<i32> {
+    let path = connection_path(connection, uuid)?;
+    let settings = saved_settings(connection, &path, "GetSettings", None)
+        .chain_err(|| ErrorKind::ReadConnectionPriorities)?;
+
+    let priority = settings
+        .get("connection")
+        .and_then(|connection| connection.get("autoconnect-priority"))
+        .and_then(|priority| priority.0.as_i64())
+        .map_or(0, |priority| priority as i32);
+
+    Ok(priority)
+}
+
+/// Changes a single setting of the saved connection `uuid`. `Update`
+/// replaces all settings, so the secrets `GetSettings` leaves out are read
+/// and passed back as well.
+fn set_autoconnect_priority(
+    connection: &dbus::Connection,
+    uuid: &str,
+    priority: i32,
+) -> Result<()> {
+    let path = connection_path(connection, uuid)?;
+
+    let update = || -> Result<()> {
+        let mut settings = saved_settings(connection, &path, "GetSettings", None)?;
+
+        for name in SECRET_SETTINGS {
+            if !settings.contains_key(*name) {
+                continue;
+            }
+
+            let secrets = saved_settings(connection, &path, "GetSecrets", Some(*name))?;
+
+            for (setting, values) in secrets {
+                settings
+                    .entry(setting)
+                    .or_insert_with(HashMap::new)
+                    .extend(values);
+            }
+        }
+
+        settings
+            .entry("connection".into())
+            .or_insert_with(HashMap::new)
+            .insert("autoconnect-priority".into(), variant(priority));
+
+        let message =
+            Message::new_method_call(NM_SERVICE, path.clone(), NM_CONNECTION_INTERFACE, "Update")?
+                .append1(settings);
+        connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+
+        Ok(())
+    };
+
+    update().chain_err(|| ErrorKind::ConnectionPriority(uuid.into()))
+}
+
+/// Reason of the last state change of the device, which the network-manager
+/// crate does not expose
+fn device_state_reason(interface: &str) -> Result<u32> {
+    let connection = dbus::Connection::get_private(BusType::System)?;
+    let device_path = device_path(&connection, interface)?;
+
+    let message = Message::new_method_call(NM_SERVICE, device_path, PROPERTIES_INTERFACE, "Get")?
+        .append2(NM_DEVICE_INTERFACE, "StateReason");
+    let reply = connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+    let state_reason: Variant<Iter> = reply
+        .read1()
+        .chain_err(|| ErrorKind::NetworkManagerProperty("StateReason".into()))?;
+
+    // (state, reason)
+    let mut state_reason = state_reason.0;
+    let reason = state_reason
+        .recurse(ArgType::Struct)
+        .and_then(|mut fields| {
+            fields.next();
+            fields.get::<u32>()
+        });
+
+    match reason {
+        Some(reason) => Ok(reason),
+        None => bail!(ErrorKind::NetworkManagerProperty("StateReason".into())),
+    }
+}
+
+fn device_path(connection: &dbus::Connection, interface: &str) -> Result<Path<'static>> {
+    let message = Message::new_method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "GetDeviceByIpIface")?
+        .append1(interface);
+    let reply = connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+    let device_path: Path = reply
+        .read1()
+        .chain_err(|| ErrorKind::NetworkManagerProperty("GetDeviceByIpIface".into()))?;
+
+    Ok(device_path.into_static())
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+/// Settings of a client connection, with the same security defaults the
+/// network-manager crate uses for the access points it connects to
+fn client_connection_settings(
+    ssid: &str,
+    security: Security,
+    identity: &str,
+    passphrase: &str,
+    enterprise: &EnterpriseSettings,
+    uuid: &str,
+    hidden: bool,
+) -> Settings {
+    let mut settings = HashMap::new();
+
+    let mut connection = HashMap::new();
+    connection.insert("id", variant(ssid.to_string()));
+    connection.insert("uuid", variant(uuid.to_string()));
+    connection.insert("type", variant("802-11-wireless".to_string()));
+    settings.insert("connection", connection);
+
+    let mut wireless = HashMap::new();
+    wireless.insert("ssid", variant(ssid.as_bytes().to_vec()));
+    wireless.insert("mode", variant("infrastructure".to_string()));
+    wireless.insert("hidden", variant(hidden));
+    settings.insert("802-11-wireless", wireless);
+
+    let mut wireless_security = HashMap::new();
+
+    match security {
+        Security::None => {}
+        Security::Wep => {
+            wireless_security.insert("key-mgmt", variant("none".to_string()));
+            wireless_security.insert("wep-key0", variant(passphrase.to_string()));
+            // Hex or ASCII key, not a passphrase to be hashed
+            wireless_security.insert("wep-key-type", variant(1u32));
+        }
+        // WPA2 clients of transition mode access points
+        Security::Wpa | Security::Wpa2Wpa3 => {
+            wireless_security.insert("key-mgmt", variant("wpa-psk".to_string()));
+            wireless_security.insert("psk", variant(passphrase.to_string()));
+        }
+        Security::Wpa3 => {
+            wireless_security.insert("key-mgmt", variant("sae".to_string()));
+            wireless_security.insert("psk", variant(passphrase.to_string()));
+        }
+        Security::Owe => {
+            wireless_security.insert("key-mgmt", variant("owe".to_string()));
+        }
+        Security::Enterprise => {
+            wireless_security.insert("key-mgmt", variant("wpa-eap".to_string()));
+
+            settings.insert("802-1x", eap_settings(identity, passphrase, enterprise));
+        }
+    }
+
+    if !wireless_security.is_empty() {
+        settings.insert("802-11-wireless-security", wireless_security);
+    }
+
+    settings
+}
+
+/// Static addresses and DNS servers, families not set are left to DHCP and
+/// router advertisements
+fn insert_ip_settings(settings: &mut Settings, ip: &IpSettings) {
+    let mut ipv4 = HashMap::new();
+
+    if let Some(ref config) = ip.ipv4 {
+        let address = address_data(config.address.to_string(), config.prefix);
+        ipv4.insert("method", variant("manual".to_string()));
+        ipv4.insert("address-data", variant(vec![address]));
+
+        if let Some(gateway) = config.gateway {
+            ipv4.insert("gateway", variant(gateway.to_string()));
+        }
+    }
+
+    let mut ipv6 = HashMap::new();
+
+    if let Some(ref config) = ip.ipv6 {
+        let address = address_data(config.address.to_string(), config.prefix);
+        ipv6.insert("method", variant("manual".to_string()));
+        ipv6.insert("address-data", variant(vec![address]));
+
+        if let Some(gateway) = config.gateway {
+            ipv6.insert("gateway", variant(gateway.to_string()));
+        }
+    }
+
+    if !ip.dns.is_empty() {
+        // IPv4 servers in network byte order
+        let dns_v4: Vec<u32> = ip
+            .dns_v4()
+            .iter()
+            .map(|server| u32::from_ne_bytes(server.octets()))
+            .collect();
+        let dns_v6: Vec<Vec<u8>> = ip
+            .dns_v6()
+            .iter()
+            .map(|server| server.octets().to_vec())
+            .collect();
+
+        ipv4.insert("dns", variant(dns_v4));
+        ipv4.insert("ignore-auto-dns", variant(true));
+        ipv6.insert("dns", variant(dns_v6));
+        ipv6.insert("ignore-auto-dns", variant(true));
+    }
+
+    if !ipv4.is_empty() {
+        settings.insert("ipv4", ipv4);
+    }
+
+    if !ipv6.is_empty() {
+        settings.insert("ipv6", ipv6);
+    }
+}
+
+fn address_data(address: String, prefix: u8) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+    let mut data = HashMap::new();
+    data.insert("address".to_string(), variant(address));
+    data.insert("prefix".to_string(), variant(u32::from(prefix)));
+    data
+}
+
+/// `802-1x` settings of an enterprise network
+fn eap_settings(
+    identity: &str,
+    passphrase: &str,
+    enterprise: &EnterpriseSettings,
+) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+    let mut settings = HashMap::new();
+    settings.insert("eap", variant(vec![enterprise.eap.as_str().to_string()]));
+    settings.insert("identity", variant(identity.to_string()));
+
+    if let Some(ref anonymous_identity) = enterprise.anonymous_identity {
+        settings.insert("anonymous-identity", variant(anonymous_identity.clone()));
+    }
+
+    if let Some(ref domain) = enterprise.domain_suffix_match {
+        settings.insert("domain-suffix-match", variant(domain.clone()));
+    }
+
+    // With TLS the passphrase unlocks the private key
+    match enterprise.phase2() {
+        Some(phase2) => {
+            settings.insert("password", variant(passphrase.to_string()));
+            settings.insert("phase2-auth", variant(phase2.as_str().to_string()));
+        }
+        None => {
+            settings.insert("private-key-password", variant(passphrase.to_string()));
+        }
+    }
+
+    if let Some(ref ca_cert) = enterprise.ca_cert {
+        settings.insert("ca-cert", variant(certificate_path(ca_cert)));
+    }
+
+    if let Some(ref client_cert) = enterprise.client_cert {
+        settings.insert("client-cert", variant(certificate_path(client_cert)));
+    }
+
+    if let Some(ref private_key) = enterprise.private_key {
+        settings.insert("private-key", variant(certificate_path(private_key)));
+    }
+
+    settings
+}
+
+/// Certificates are referenced as NUL terminated `file://` URIs
+fn certificate_path(path: &FsPath) -> Vec<u8> {
+    let mut uri = b"file://".to_vec();
+    uri.extend_from_slice(path.as_os_str().as_bytes());
+    uri.push(0);
+    uri
+}
+
+/// Settings of an OWE access point, otherwise configured like the hotspots
+/// of the network-manager crate
+fn owe_portal_settings(ssid: &str, gateway: Ipv4Addr, uuid: &str) -> Settings {
+    let mut settings = HashMap::new();
+
+    let mut connection = HashMap::new();
+    connection.insert("id", variant(ssid.to_string()));
+    connection.insert("uuid", variant(uuid.to_string()));
+    connection.insert("type", variant("802-11-wireless".to_string()));
+    connection.insert("autoconnect", variant(false));
+    settings.insert("connection", connection);
+
+    let mut wireless = HashMap::new();
+    wireless.insert("ssid", variant(ssid.as_bytes().to_vec()));
+    wireless.insert("mode", variant("ap".to_string()));
+    wireless.insert("band", variant("bg".to_string()));
+    settings.insert("802-11-wireless", wireless);
+
+    let mut wireless_security = HashMap::new();
+    wireless_security.insert("key-mgmt", variant("owe".to_string()));
+    // OWE requires management frame protection
+    wireless_security.insert("pmf", variant(3i32));
+    settings.insert("802-11-wireless-security", wireless_security);
+
+    // Address, prefix and gateway in network byte order
+    let address = u32::from_ne_bytes(gateway.octets());
+
+    let mut ipv4 = HashMap::new();
+    ipv4.insert("method", variant("manual".to_string()));
+    ipv4.insert("addresses", variant(vec![vec![address, 24u32, 0u32]]));
+    settings.insert("ipv4", ipv4);
+
+    let mut ipv6 = HashMap::new();
+    ipv6.insert("method", variant("ignore".to_string()));
+    settings.insert("ipv6", ipv6);
+
+    settings
+}
+
+/// Version 4 UUID, used to find the connection added by NetworkManager
+fn random_uuid() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    ))
+}
+
+fn init_access_point_credentials(
+    access_point: &AccessPoint,
+    identity: &str,
+    passphrase: &str,
+) -> AccessPointCredentials {
+    match access_point.security {
+        Security::Enterprise => AccessPointCredentials::Enterprise {
+            identity: identity.to_string(),
+            passphrase: passphrase.to_string(),
+        },
+        // Only the WPA2 side of transition mode access points is known to the
+        // network-manager crate, the others are connected to without it
+        Security::Wpa | Security::Wpa2Wpa3 | Security::Wpa3 => AccessPointCredentials::Wpa {
+            passphrase: passphrase.to_string(),
+        },
+        Security::Wep => AccessPointCredentials::Wep {
+            passphrase: passphrase.to_string(),
+        },
+        Security::None | Security::Owe => AccessPointCredentials::None,
+    }
+}
+
+/// Reads the properties of an access point, `None` if its SSID is random bytes
+/// and not a UTF-8 encoded string
+fn read_access_point(connection: &dbus::Connection, path: &Path) -> Result<Option<AccessPoint>> {
+    let ssid: Vec<u8> = get_property(connection, path, NM_ACCESS_POINT_INTERFACE, "Ssid")?;
+
+    let ssid = match String::from_utf8(ssid) {
+        Ok(ssid) => ssid,
+        Err(_) => return Ok(None),
+    };
+
+    let strength: u8 = get_property(connection, path, NM_ACCESS_POINT_INTERFACE, "Strength")?;
+    let frequency: u32 = get_property(connection, path, NM_ACCESS_POINT_INTERFACE, "Frequency")?;
+    let flags: u32 = get_property(connection, path, NM_ACCESS_POINT_INTERFACE, "Flags")?;
+    let wpa_flags: u32 = get_property(connection, path, NM_ACCESS_POINT_INTERFACE, "WpaFlags")?;
+    let rsn_flags: u32 = get_property(connection, path, NM_ACCESS_POINT_INTERFACE, "RsnFlags")?;
+
+    Ok(Some(AccessPoint {
+        ssid,
+        security: get_security(flags, wpa_flags, rsn_flags),
+        strength: u32::from(strength),
+        frequency: Some(frequency),
+    }))
+}
+
+fn get_property<T>(
+    connection: &dbus::Connection,
+    path: &Path,
+    interface: &str,
+    property: &str,
+) -> Result<T>
+where
+    T: for<'a> dbus::arg::Get<'a> + dbus::arg::Arg,
+{
+    let message = Message::new_method_call(NM_SERVICE, path.clone(), PROPERTIES_INTERFACE, "Get")?
+        .append2(interface, property);
+    let reply = connection.send_with_reply_and_block(message, METHOD_TIMEOUT)?;
+
+    let value: Variant<T> = reply
+        .read1()
+        .chain_err(|| ErrorKind::NetworkManagerProperty(property.into()))?;
+
+    Ok(value.0)
+}
+
+/// Classification of the network-manager crate, told apart further by the SAE
+/// and OWE key management flags
+fn get_security(flags: u32, wpa_flags: u32, rsn_flags: u32) -> Security {
+    let key_mgmt = wpa_flags | rsn_flags;
+
+    if key_mgmt & AP_SEC_KEY_MGMT_802_1X != 0 {
+        Security::Enterprise
+    } else if key_mgmt & AP_SEC_KEY_MGMT_SAE != 0 && key_mgmt & AP_SEC_KEY_MGMT_PSK != 0 {
+        Security::Wpa2Wpa3
+    } else if key_mgmt & AP_SEC_KEY_MGMT_SAE != 0 {
+        Security::Wpa3
+    } else if key_mgmt & AP_SEC_KEY_MGMT_OWE != 0 {
+        Security::Owe
+    } else if key_mgmt & AP_SEC_KEY_MGMT_OWE_TM != 0 && flags & AP_FLAGS_PRIVACY == 0 {
+        // Open access point of an OWE transition mode pair
+        Security::None
+    } else if wpa_flags != 0 || rsn_flags != 0 {
+        Security::Wpa
+    } else if flags & AP_FLAGS_PRIVACY != 0 {
+        Security::Wep
+    } else {
+        Security::None
+    }
+}
+
+fn find_device(manager: &NetworkManager, interface: &Option<String>) -> Result<Device> {
+    if let Some(ref interface) = *interface {
+        let device = manager
+            .get_device_by_interface(interface)
+            .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
+
+        info!("Targeted WiFi device: {}", interface);
+
+        if *device.device_type() != DeviceType::WiFi {
+            bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
+        }
+
+        if device.get_state()? == DeviceState::Unmanaged {
+            bail!(ErrorKind::UnmanagedDevice(interface.clone()))
+        }
+
+        Ok(device)
+    } else {
+        let devices = manager.get_devices()?;
+
+        if let Some(device) = find_wifi_managed_device(devices)? {
+            info!("WiFi device: {}", device.interface());
+            Ok(device)
+        } else {
+            bail!(ErrorKind::NoWiFiDevice)
+        }
+    }
+}
+
+fn find_wifi_managed_device(devices: Vec<Device>) -> Result<Option<Device>> {
+    for device in devices {
+        if *device.device_type() == DeviceType::WiFi
+            && device.get_state()? != DeviceState::Unmanaged
+        {
+            return Ok(Some(device));
+        }
+    }
+
+    Ok(None)
+}
+
+fn start_network_manager_service() -> Result<()> {
+    let state = match NetworkManager::get_service_state() {
+        Ok(state) => state,
+        _ => {
+            info!("Cannot get the NetworkManager service state");
+            return Ok(());
+        }
+    };
+
+    if state != ServiceState::Active {
+        let state =
+            NetworkManager::start_service(15).chain_err(|| ErrorKind::StartNetworkManager)?;
+        if state != ServiceState::Active {
+            bail!(ErrorKind::StartActiveNetworkManager);
+        } else {
+            info!("NetworkManager service started successfully");
+        }
+    } else {
+        debug!("NetworkManager service already running");
+    }
+
+    Ok(())
+}
+
+fn is_portal_ssid(connection: &Connection, ssid: &SsidTemplate) -> bool {
+    connection_ssid_as_str(connection)
+        .map_or(false, |connection_ssid| ssid.matches(connection_ssid))
+}
+
+fn connection_ssid_as_str(connection: &Connection) -> Option<&str> {
+    // An access point SSID could be random bytes and not a UTF-8 encoded string
+    connection.settings().ssid.as_str().ok()
+}
+
+fn is_access_point_connection(connection: &Connection) -> bool {
+    is_wifi_connection(connection) && connection.settings().mode == "ap"
+}
+
+fn is_wifi_connection(connection: &Connection) -> bool {
+    connection.settings().kind == "802-11-wireless"
+}
@@ -0,0 +1,277 @@
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use backend::runtime_directory;
+use errors::*;
+
+const REPLY_BUFFER_SIZE: usize = 4096;
+
+// Seconds
+const REQUEST_TIMEOUT: u64 = 10;
+
+static SOCKET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Client for the `wpa_ctrl` protocol spoken by wpa_supplicant and hostapd
+/// over UNIX datagram sockets.
+///
+/// Every client binds its own socket, so that the daemon has an address to
+/// send replies and, once attached, unsolicited events to.
+pub struct WpaCtrl {
+    socket: UnixDatagram,
+    local_path: Option<PathBuf>,
+}
+
+impl WpaCtrl {
+    pub fn open(ctrl_path: &Path) -> Result<Self> {
+        let local_path = runtime_directory()?.join(format!(
+            "wpa-ctrl-{}-{}",
+            process::id(),
+            SOCKET_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let _ = fs::remove_file(&local_path);
+
+        let socket = UnixDatagram::bind(&local_path)
+            .and_then(|socket| socket.connect(ctrl_path).map(|_| socket))
+            .chain_err(|| ErrorKind::WpaCtrlConnect(ctrl_path.display().to_string()))?;
+
+        socket.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)))?;
+
+        Ok(WpaCtrl {
+            socket,
+            local_path: Some(local_path),
+        })
+    }
+
+    /// Client on one end of a `UnixDatagram::pair`, the test plays the daemon
+    #[cfg(test)]
+    pub fn from_socket(socket: UnixDatagram) -> Self {
+        WpaCtrl {
+            socket,
+            local_path: None,
+        }
+    }
+
+    /// Sends `command` and returns the reply with trailing new lines removed
+    pub fn request(&self, command: &str) -> Result<String> {
+        self.socket
+            .send(command.as_bytes())
+            .chain_err(|| ErrorKind::WpaCtrlRequest(command_name(command)))?;
+
+        loop {
+            let message = self
+                .receive()
+                .chain_err(|| ErrorKind::WpaCtrlRequest(command_name(command)))?;
+
+            // Events are prefixed with their priority, e.g. `<3>CTRL-EVENT-...`
+            if !message.starts_with('<') {
+                return Ok(message.trim_end().to_string());
+            }
+        }
+    }
+
+    /// Sends `command` and fails unless the reply is `OK`
+    pub fn request_ok(&self, command: &str) -> Result<()> {
+        let reply = self.request(command)?;
+
+        if reply != "OK" {
+            bail!(ErrorKind::WpaCtrlReply(command_name(command), reply));
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes this client to unsolicited events
+    pub fn attach(&self) -> Result<()> {
+        self.request_ok("ATTACH")
+    }
+
+    /// Waits for the first event containing one of `events` and returns it
+    pub fn wait_for_event(&self, events: &[&str], timeout: Duration) -> Result<Option<String>> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            let message = match self.receive() {
+                Ok(message) => message,
+                Err(ref e) if is_timeout(e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if events.iter().any(|event| message.contains(event)) {
+                return Ok(Some(message.trim_end().to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn receive(&self) -> ::std::io::Result<String> {
+        let mut buffer = [0; REPLY_BUFFER_SIZE];
+        let size = self.socket.recv(&mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer[..size]).into_owned())
+    }
+}
+
+impl Drop for WpaCtrl {
+    fn drop(&mut self) {
+        if let Some(ref local_path) = self.local_path {
+            let _ = fs::remove_file(local_path);
+        }
+    }
+}
+
+/// Parses `key=value` lines as returned by `STATUS`
+pub fn parse_status(reply: &str) -> Vec<(&str, &str)> {
+    reply
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Some((key, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Decodes an SSID escaped by wpa_supplicant's `printf_encode`
+pub fn decode_ssid(encoded: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut chars = encoded.bytes();
+
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b'r') => bytes.push(b'\r'),
+            Some(b't') => bytes.push(b'\t'),
+            Some(b'e') => bytes.push(0x1b),
+            Some(b'x') => {
+                let hex: Vec<u8> = chars.by_ref().take(2).collect();
+                let hex = String::from_utf8_lossy(&hex).into_owned();
+                if let Ok(byte) = u8::from_str_radix(&hex, 16) {
+                    bytes.push(byte);
+                }
+            }
+            Some(other) => bytes.push(other),
+            None => bytes.push(b'\\'),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Hex encodes `value`, which wpa_supplicant accepts in place of a quoted
+/// string for SSIDs
+pub fn encode_hex(value: &str) -> String {
+    value.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Commands may contain secrets, so only their name is used in errors
+fn command_name(command: &str) -> String {
+    command
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn is_timeout(e: &::std::io::Error) -> bool {
+    e.kind() == ::std::io::ErrorKind::WouldBlock || e.kind() == ::std::io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (WpaCtrl, UnixDatagram) {
+        let (client, daemon) = UnixDatagram::pair().unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        (WpaCtrl::from_socket(client), daemon)
+    }
+
+    fn received(daemon: &UnixDatagram) -> String {
+        let mut buffer = [0; REPLY_BUFFER_SIZE];
+        let size = daemon.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..size]).into_owned()
+    }
+
+    #[test]
+    fn request_skips_events() {
+        let (ctrl, daemon) = pair();
+        daemon.send(b"<3>CTRL-EVENT-SCAN-STARTED ").unwrap();
+        daemon.send(b"OK\n").unwrap();
+
+        assert_eq!(ctrl.request("SCAN").unwrap(), "OK");
+        assert_eq!(received(&daemon), "SCAN");
+    }
+
+    #[test]
+    fn request_ok_hides_arguments() {
+        let (ctrl, daemon) = pair();
+        daemon.send(b"FAIL\n").unwrap();
+
+        let e = ctrl.request_ok("SET_NETWORK 0 psk \"secret\"").unwrap_err();
+
+        match *e.kind() {
+            ErrorKind::WpaCtrlReply(ref command, ref reply) => {
+                assert_eq!(command, "SET_NETWORK");
+                assert_eq!(reply, "FAIL");
+            }
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn request_times_out() {
+        let (ctrl, _daemon) = pair();
+
+        assert!(ctrl.request("STATUS").is_err());
+    }
+
+    #[test]
+    fn wait_for_event_skips_others() {
+        let (ctrl, daemon) = pair();
+        daemon.send(b"<3>CTRL-EVENT-SCAN-STARTED ").unwrap();
+        daemon.send(b"<3>CTRL-EVENT-SCAN-RESULTS ").unwrap();
+
+        let event = ctrl
+            .wait_for_event(&["CTRL-EVENT-SCAN-RESULTS"], Duration::from_secs(1))
+            .unwrap();
+
+        assert_eq!(event, Some("<3>CTRL-EVENT-SCAN-RESULTS".into()));
+        assert_eq!(
+            ctrl.wait_for_event(&["CTRL-EVENT-SCAN-RESULTS"], Duration::from_millis(200))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_status() {
+        assert_eq!(
+            parse_status("bssid=00:11:22:33:44:55\nssid=a=b\nwpa_state=COMPLETED"),
+            vec![
+                ("bssid", "00:11:22:33:44:55"),
+                ("ssid", "a=b"),
+                ("wpa_state", "COMPLETED"),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_ssid() {
+        assert_eq!(decode_ssid("My Network"), "My Network");
+        assert_eq!(decode_ssid("caf\\xc3\\xa9\\\\"), "caf\u{e9}\\");
+    }
+}
@@ -0,0 +1,855 @@
+use std::fs;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::wpa_ctrl::{decode_ssid, encode_hex, parse_status, WpaCtrl};
+use backend::{
+    dbm_to_strength, has_default_route, runtime_directory, set_interface_address, AccessPoint,
+    Connectivity, EnterpriseSettings, IpSettings, PortalSecurity, SavedNetwork, Security,
+    WifiBackend, SCAN_TIMEOUT,
+};
+use errors::*;
+use ssid::SsidTemplate;
+
+pub const DEFAULT_CTRL_DIRECTORY: &str = "/var/run/wpa_supplicant";
+
+const HOSTAPD_CONFIG_FILE: &str = "hostapd.conf";
+const HOSTAPD_CHANNEL: u8 = 6;
+
+// Seconds
+const CONNECT_TIMEOUT: u64 = 30;
+
+/// Backend for images without NetworkManager: client connections go
+/// through the wpa_supplicant control socket of the interface, while the
+/// portal access point is run by a hostapd child process.
+///
+/// IP configuration of client connections is left to the system, e.g. a
+/// `wpa_cli -a` action script running a DHCP client.
+pub struct WpaSupplicantBackend {
+    interface: String,
+    ctrl_path: PathBuf,
+    ctrl: WpaCtrl,
+    hostapd: Option<Child>,
+    portal_gateway: Option<Ipv4Addr>,
+    network_id: Option<String>,
+    /// wpa_supplicant was told to disconnect while the portal is up
+    reconnect: bool,
+}
+
+/// Saved network as listed by `LIST_NETWORKS`
+struct ListedNetwork {
+    id: String,
+    ssid: String,
+    flags: String,
+}
+
+impl WpaSupplicantBackend {
+    /// `ctrl_directory` holds one control socket per interface managed by
+    /// wpa_supplicant, as configured with its `ctrl_interface` option
+    pub fn new(ctrl_directory: &Path, interface: &Option<String>) -> Result<Self> {
+        let interface = match *interface {
+            Some(ref interface) => interface.clone(),
+            None => find_interface(ctrl_directory)?,
+        };
+
+        let ctrl_path = ctrl_directory.join(&interface);
+
+        if !ctrl_path.exists() {
+            bail!(ErrorKind::DeviceByInterface(interface));
+        }
+
+        info!("WiFi device: {}", interface);
+
+        let ctrl = WpaCtrl::open(&ctrl_path)?;
+
+        Ok(WpaSupplicantBackend {
+            interface,
+            ctrl_path,
+            ctrl,
+            hostapd: None,
+            portal_gateway: None,
+            network_id: None,
+            reconnect: false,
+        })
+    }
+
+    /// Returns `false` if the scan failed or timed out, in which case only
+    /// earlier results are available
+    fn request_scan(&self) -> Result<bool> {
+        let monitor = WpaCtrl::open(&self.ctrl_path)?;
+        monitor.attach()?;
+
+        let reply = self.ctrl.request("SCAN")?;
+
+        // A scan already in progress will deliver results as well
+        if reply != "OK" && reply != "FAIL-BUSY" {
+            bail!(ErrorKind::WpaCtrlReply("SCAN".into(), reply));
+        }
+
+        let event = monitor.wait_for_event(
+            &["CTRL-EVENT-SCAN-RESULTS", "CTRL-EVENT-SCAN-FAILED"],
+            Duration::from_secs(SCAN_TIMEOUT),
+        )?;
+
+        match event {
+            Some(ref event) if event.contains("CTRL-EVENT-SCAN-FAILED") => {
+                warn!("Scan failed: {}", event);
+                Ok(false)
+            }
+            Some(_) => Ok(true),
+            None => {
+                warn!("Timeout reached in waiting for scan to complete");
+                Ok(false)
+            }
+        }
+    }
+
+    fn scan_results(&self) -> Result<Vec<AccessPoint>> {
+        let results = self.ctrl.request("SCAN_RESULTS")?;
+
+        // bssid / frequency / signal level / flags / ssid
+        Ok(results
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.splitn(5, '\t').collect();
+
+                if fields.len() != 5 {
+                    return None;
+                }
+
+                Some(AccessPoint {
+                    ssid: decode_ssid(fields[4]),
+                    security: get_security(fields[3]),
+                    strength: dbm_to_strength(fields[2].parse().unwrap_or(-100)),
+                    frequency: fields[1].parse().ok(),
+                })
+            })
+            .collect())
+    }
+
+    /// Adds a network for `access_point` and selects it, hidden networks are
+    /// probed for by SSID
+    fn connect_network(
+        &mut self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        hidden: bool,
+    ) -> Result<()> {
+        let id = self.add_network(access_point, identity, passphrase, enterprise, hidden)?;
+
+        // `SELECT_NETWORK` disables all other networks, which are enabled
+        // again afterwards as fallback
+        let enabled: Vec<String> = self
+            .list_networks()?
+            .into_iter()
+            .filter(|network| network.id != id && !network.flags.contains("[DISABLED]"))
+            .map(|network| network.id)
+            .collect();
+
+        let monitor = WpaCtrl::open(&self.ctrl_path)?;
+        monitor.attach()?;
+
+        self.ctrl.request_ok(&format!("SELECT_NETWORK {}", id))?;
+
+        let deadline = Instant::now() + Duration::from_secs(CONNECT_TIMEOUT);
+
+        // Saved networks may still be connecting after the portal's
+        // `RECONNECT`, their events are skipped
+        let event = loop {
+            let event = monitor.wait_for_event(
+                &[
+                    "CTRL-EVENT-CONNECTED",
+                    "CTRL-EVENT-SSID-TEMP-DISABLED",
+                    "CTRL-EVENT-NETWORK-NOT-FOUND",
+                ],
+                deadline.saturating_duration_since(Instant::now()),
+            )?;
+
+            match event {
+                Some(ref event) if event_network_id(event).map_or(false, |other| other != id) => {}
+                _ => break event,
+            }
+        };
+
+        self.enable_networks(&enabled);
+
+        match event {
+            Some(ref event) if event.contains("CTRL-EVENT-CONNECTED") => {
+                self.network_id = Some(id);
+                self.save_config();
+                Ok(())
+            }
+            _ => {
+                let event = event.unwrap_or_else(|| "timeout".into());
+
+                warn!(
+                    "Error connecting to access point '{}': {}",
+                    access_point.ssid, event
+                );
+
+                self.remove_network(&id);
+
+                let ssid = access_point.ssid.clone();
+
+                if event.contains("reason=WRONG_KEY") || event.contains("reason=AUTH_FAILED") {
+                    bail!(ErrorKind::WrongPassphrase(ssid))
+                } else if event.contains("CTRL-EVENT-NETWORK-NOT-FOUND") {
+                    bail!(ErrorKind::AccessPointNotFound(ssid))
+                } else {
+                    bail!(ErrorKind::WiFiConnectionFailed)
+                }
+            }
+        }
+    }
+
+    fn add_network(
+        &self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        hidden: bool,
+    ) -> Result<String> {
+        let id = self.ctrl.request("ADD_NETWORK")?;
+
+        if id.parse::<u32>().is_err() {
+            bail!(ErrorKind::WpaCtrlReply("ADD_NETWORK".into(), id));
+        }
+
+        let mut settings = vec![("ssid", encode_hex(&access_point.ssid))];
+
+        if hidden {
+            settings.push(("scan_ssid", "1".into()));
+        }
+
+        match access_point.security {
+            Security::None => {
+                settings.push(("key_mgmt", "NONE".into()));
+            }
+            Security::Wep => {
+                settings.push(("key_mgmt", "NONE".into()));
+                settings.push(("wep_key0", format_wep_key(passphrase)));
+                settings.push(("wep_tx_keyidx", "0".into()));
+            }
+            Security::Wpa => {
+                settings.push(("key_mgmt", "WPA-PSK".into()));
+                settings.push(("psk", quote(passphrase)));
+            }
+            // The passphrase is used as SAE password as well, SAE requires
+            // management frame protection
+            Security::Wpa3 => {
+                settings.push(("key_mgmt", "SAE".into()));
+                settings.push(("psk", quote(passphrase)));
+                settings.push(("ieee80211w", "2".into()));
+            }
+            Security::Wpa2Wpa3 => {
+                settings.push(("key_mgmt", "WPA-PSK SAE".into()));
+                settings.push(("psk", quote(passphrase)));
+                settings.push(("ieee80211w", "1".into()));
+            }
+            Security::Owe => {
+                settings.push(("key_mgmt", "OWE".into()));
+                settings.push(("ieee80211w", "2".into()));
+            }
+            Security::Enterprise => {
+                settings.push(("key_mgmt", "WPA-EAP".into()));
+                settings.push(("eap", enterprise.eap.as_str().to_uppercase()));
+                settings.push(("identity", quote(identity)));
+
+                if let Some(ref anonymous_identity) = enterprise.anonymous_identity {
+                    settings.push(("anonymous_identity", quote(anonymous_identity)));
+                }
+
+                // With TLS the passphrase unlocks the private key
+                match enterprise.phase2() {
+                    Some(phase2) => {
+                        settings.push(("password", quote(passphrase)));
+                        settings.push((
+                            "phase2",
+                            quote(&format!("auth={}", phase2.as_str().to_uppercase())),
+                        ));
+                    }
+                    None => {
+                        if !passphrase.is_empty() {
+                            settings.push(("private_key_passwd", quote(passphrase)));
+                        }
+                    }
+                }
+
+                if let Some(ref ca_cert) = enterprise.ca_cert {
+                    settings.push(("ca_cert", quote_path(ca_cert)));
+                }
+
+                if let Some(ref client_cert) = enterprise.client_cert {
+                    settings.push(("client_cert", quote_path(client_cert)));
+                }
+
+                if let Some(ref private_key) = enterprise.private_key {
+                    settings.push(("private_key", quote_path(private_key)));
+                }
+
+                if let Some(ref domain) = enterprise.domain_suffix_match {
+                    settings.push(("domain_suffix_match", quote(domain)));
+                }
+            }
+        }
+
+        for (name, value) in settings {
+            if let Err(e) = self
+                .ctrl
+                .request_ok(&format!("SET_NETWORK {} {} {}", id, name, value))
+            {
+                self.remove_network(&id);
+                return Err(e);
+            }
+        }
+
+        Ok(id)
+    }
+
+    fn list_networks(&self) -> Result<Vec<ListedNetwork>> {
+        let networks = self.ctrl.request("LIST_NETWORKS")?;
+
+        // network id / ssid / bssid / flags
+        Ok(networks
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+
+                if fields.len() < 2 {
+                    return None;
+                }
+
+                Some(ListedNetwork {
+                    id: fields[0].to_string(),
+                    ssid: decode_ssid(fields[1]),
+                    flags: fields.get(3).cloned().unwrap_or_default().to_string(),
+                })
+            })
+            .collect())
+    }
+
+    fn network_priority(&self, id: &str) -> Result<Option<i32>> {
+        let priority = self.ctrl.request(&format!("GET_NETWORK {} priority", id))?;
+
+        Ok(priority.parse().ok())
+    }
+
+    /// Ids of the saved networks of `ssid`, failing with
+    /// `SavedNetworkNotFound` if there are none
+    fn saved_network_ids(&self, ssid: &str) -> Result<Vec<String>> {
+        let ids: Vec<String> = self
+            .list_networks()?
+            .into_iter()
+            .filter(|network| network.ssid == ssid)
+            .map(|network| network.id)
+            .collect();
+
+        if ids.is_empty() {
+            bail!(ErrorKind::SavedNetworkNotFound(ssid.into()));
+        }
+
+        Ok(ids)
+    }
+
+    fn enable_networks(&self, ids: &[String]) {
+        for id in ids {
+            if let Err(e) = self.ctrl.request_ok(&format!("ENABLE_NETWORK {}", id)) {
+                error!("Enabling network {} failed: {}", id, e);
+            }
+        }
+    }
+
+    fn remove_network(&self, id: &str) {
+        if let Err(e) = self.ctrl.request_ok(&format!("REMOVE_NETWORK {}", id)) {
+            error!("Deleting network {} failed: {}", id, e);
+        }
+    }
+
+    fn save_config(&self) {
+        // Fails if wpa_supplicant runs without `update_config=1`, in which case
+        // networks are only kept until it restarts
+        if let Err(e) = self.ctrl.request_ok("SAVE_CONFIG") {
+            warn!("Saving wpa_supplicant configuration failed: {}", e);
+        }
+    }
+
+    fn wpa_state(&self) -> Result<String> {
+        let status = self.ctrl.request("STATUS")?;
+
+        Ok(parse_status(&status)
+            .into_iter()
+            .find(|&(key, _)| key == "wpa_state")
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default())
+    }
+
+    fn start_hostapd(
+        &self,
+        ssid: &str,
+        security: PortalSecurity,
+        passphrase: Option<&str>,
+    ) -> Result<Child> {
+        let mut config = format!(
+            "interface={}\n\
+             driver=nl80211\n\
+             ssid2={}\n\
+             hw_mode=g\n\
+             channel={}\n",
+            self.interface,
+            encode_hex(ssid),
+            HOSTAPD_CHANNEL
+        );
+
+        match (security, passphrase) {
+            (PortalSecurity::Wpa2, Some(passphrase)) => config.push_str(&format!(
+                "wpa=2\n\
+                 wpa_key_mgmt=WPA-PSK\n\
+                 rsn_pairwise=CCMP\n\
+                 wpa_passphrase={}\n",
+                passphrase
+            )),
+            // Management frame protection is optional for WPA2 clients
+            (PortalSecurity::Wpa2Wpa3, Some(passphrase)) => config.push_str(&format!(
+                "wpa=2\n\
+                 wpa_key_mgmt=WPA-PSK SAE\n\
+                 rsn_pairwise=CCMP\n\
+                 ieee80211w=1\n\
+                 sae_require_mfp=1\n\
+                 wpa_passphrase={}\n",
+                passphrase
+            )),
+            (PortalSecurity::Owe, _) => config.push_str(
+                "wpa=2\n\
+                 wpa_key_mgmt=OWE\n\
+                 rsn_pairwise=CCMP\n\
+                 ieee80211w=2\n",
+            ),
+            (_, None) => {}
+        }
+
+        let config_path = runtime_directory()?.join(HOSTAPD_CONFIG_FILE);
+
+        let _ = fs::remove_file(&config_path);
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&config_path)?;
+        file.write_all(config.as_bytes())?;
+
+        let mut hostapd = Command::new("hostapd")
+            .arg(&config_path)
+            .spawn()
+            .chain_err(|| ErrorKind::Hostapd)?;
+
+        // hostapd exits right away if the interface cannot be put in AP mode
+        thread::sleep(Duration::from_secs(1));
+
+        if hostapd.try_wait()?.is_some() {
+            bail!(ErrorKind::Hostapd);
+        }
+
+        Ok(hostapd)
+    }
+}
+
+impl WifiBackend for WpaSupplicantBackend {
+    fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPoint>> {
+        self.request_scan()?;
+        self.scan_results()
+    }
+
+    fn scan_in_portal(&mut self) -> Result<Vec<AccessPoint>> {
+        // hostapd owns the interface, drivers without scanning in AP mode
+        // reject the scan
+        if !self.request_scan()? {
+            bail!(ErrorKind::PortalScan);
+        }
+
+        self.scan_results()
+    }
+
+    fn create_portal(
+        &mut self,
+        ssid: &str,
+        security: PortalSecurity,
+        passphrase: Option<&str>,
+        gateway: Ipv4Addr,
+    ) -> Result<()> {
+        // Keep wpa_supplicant from reconnecting while hostapd owns the interface
+        self.ctrl.request_ok("DISCONNECT")?;
+        self.reconnect = true;
+
+        self.hostapd = Some(self.start_hostapd(ssid, security, passphrase)?);
+
+        set_interface_address(&self.interface, "add", gateway)?;
+        self.portal_gateway = Some(gateway);
+
+        Ok(())
+    }
+
+    fn stop_portal(&mut self) -> Result<()> {
+        if let Some(mut hostapd) = self.hostapd.take() {
+            hostapd.kill()?;
+            hostapd.wait()?;
+        }
+
+        if let Some(gateway) = self.portal_gateway.take() {
+            if let Err(e) = set_interface_address(&self.interface, "del", gateway) {
+                warn!("Removing access point address failed: {}", e);
+            }
+        }
+
+        // Saved networks are connected again, a following `SELECT_NETWORK`
+        // takes precedence
+        if self.reconnect {
+            self.ctrl.request_ok("RECONNECT")?;
+            self.reconnect = false;
+        }
+
+        Ok(())
+    }
+
+    fn delete_portal_profiles(&mut self, _ssid: &SsidTemplate) -> Result<()> {
+        // The hostapd configuration is written anew for every portal
+        Ok(())
+    }
+
+    fn delete_wifi_connections(&mut self) -> Result<()> {
+        for network in self.list_networks()? {
+            if Some(&network.id) == self.network_id.as_ref() {
+                continue;
+            }
+
+            info!("Deleting existing WiFi connection: {:?}", network.ssid);
+
+            self.remove_network(&network.id);
+        }
+
+        self.save_config();
+
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        access_point: &AccessPoint,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        require_automatic_ip(ip)?;
+
+        self.connect_network(access_point, identity, passphrase, enterprise, false)
+    }
+
+    fn connect_hidden(
+        &mut self,
+        ssid: &str,
+        security: Security,
+        identity: &str,
+        passphrase: &str,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<()> {
+        require_automatic_ip(ip)?;
+
+        let access_point = AccessPoint {
+            ssid: ssid.to_string(),
+            security,
+            strength: 0,
+            frequency: None,
+        };
+
+        self.connect_network(&access_point, identity, passphrase, enterprise, true)
+    }
+
+    fn forget_connection(&mut self) -> Result<()> {
+        if let Some(id) = self.network_id.take() {
+            self.remove_network(&id);
+            self.save_config();
+        }
+
+        Ok(())
+    }
+
+    fn prioritize_connection(&mut self) -> Result<()> {
+        let id = match self.network_id {
+            Some(ref id) => id.clone(),
+            None => return Ok(()),
+        };
+
+        let mut highest = None;
+
+        for network in self.list_networks()? {
+            if network.id == id {
+                continue;
+            }
+
+            highest = highest.max(self.network_priority(&network.id)?);
+        }
+
+        let priority = highest.map_or(0, |priority| priority + 1);
+
+        self.ctrl
+            .request_ok(&format!("SET_NETWORK {} priority {}", id, priority))?;
+        self.save_config();
+
+        info!("WiFi connection priority set to {}", priority);
+
+        Ok(())
+    }
+
+    fn saved_networks(&mut self) -> Result<Vec<SavedNetwork>> {
+        let mut saved_networks = Vec::new();
+
+        for network in self.list_networks()? {
+            saved_networks.push(SavedNetwork {
+                priority: self.network_priority(&network.id)?,
+                ssid: network.ssid,
+            });
+        }
+
+        Ok(saved_networks)
+    }
+
+    fn delete_saved_network(&mut self, ssid: &str) -> Result<()> {
+        for id in self.saved_network_ids(ssid)? {
+            info!("Deleting saved WiFi connection: {:?}", ssid);
+
+            self.ctrl.request_ok(&format!("REMOVE_NETWORK {}", id))?;
+
+            if self.network_id.as_ref() == Some(&id) {
+                self.network_id = None;
+            }
+        }
+
+        self.save_config();
+
+        Ok(())
+    }
+
+    fn set_saved_network_priority(&mut self, ssid: &str, priority: i32) -> Result<()> {
+        for id in self.saved_network_ids(ssid)? {
+            self.ctrl
+                .request_ok(&format!("SET_NETWORK {} priority {}", id, priority))?;
+        }
+
+        self.save_config();
+
+        info!(
+            "Saved WiFi connection {:?} priority set to {}",
+            ssid, priority
+        );
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        self.ctrl.request_ok("DISCONNECT")
+    }
+
+    fn is_associated(&self) -> Result<bool> {
+        Ok(self.wpa_state()? == "COMPLETED")
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        if !self.is_associated()? {
+            Ok(Connectivity::None)
+        } else if has_default_route(&self.interface)? {
+            Ok(Connectivity::Full)
+        } else {
+            Ok(Connectivity::Limited)
+        }
+    }
+}
+
+impl Drop for WpaSupplicantBackend {
+    fn drop(&mut self) {
+        let _ = self.stop_portal();
+    }
+}
+
+/// Picks the first interface with a control socket
+fn find_interface(ctrl_directory: &Path) -> Result<String> {
+    let mut interfaces = Vec::new();
+
+    for entry in fs::read_dir(ctrl_directory).chain_err(|| ErrorKind::NoWiFiDevice)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+
+        // Sockets of P2P devices are named e.g. `p2p-dev-wlan0`
+        if !name.starts_with("p2p-") {
+            interfaces.push(name);
+        }
+    }
+
+    interfaces.sort();
+
+    match interfaces.into_iter().next() {
+        Some(interface) => Ok(interface),
+        None => bail!(ErrorKind::NoWiFiDevice),
+    }
+}
+
+/// Network id of `CTRL-EVENT-CONNECTED` (`[id=0 id_str=]`) and
+/// `CTRL-EVENT-SSID-TEMP-DISABLED` (`id=0`) events
+fn event_network_id(event: &str) -> Option<&str> {
+    event
+        .split_whitespace()
+        .filter_map(|word| word.trim_start_matches('[').strip_prefix("id="))
+        .next()
+}
+
+/// Flags look like `[WPA2-PSK+SAE-CCMP][ESS]`. Open access points announcing an
+/// OWE transition mode access point carry `[OWE-TRANS-OPEN]`.
+fn get_security(flags: &str) -> Security {
+    if flags.contains("EAP") {
+        Security::Enterprise
+    } else if flags.contains("SAE") && flags.contains("PSK") {
+        Security::Wpa2Wpa3
+    } else if flags.contains("SAE") {
+        Security::Wpa3
+    } else if flags.contains("PSK") {
+        Security::Wpa
+    } else if flags.contains("-OWE") {
+        Security::Owe
+    } else if flags.contains("WEP") {
+        Security::Wep
+    } else {
+        Security::None
+    }
+}
+
+/// Addresses are managed by the DHCP client of the system, not by
+/// wpa_supplicant
+fn require_automatic_ip(ip: &IpSettings) -> Result<()> {
+    if !ip.is_automatic() {
+        bail!(ErrorKind::UnsupportedByBackend(
+            "wpa-supplicant".into(),
+            "static IP configuration".into()
+        ));
+    }
+
+    Ok(())
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value)
+}
+
+fn quote_path(path: &Path) -> String {
+    quote(&path.to_string_lossy())
+}
+
+/// WEP keys of 10 or 26 hex digits are used as is, others as ASCII keys
+fn format_wep_key(key: &str) -> String {
+    let is_hex = (key.len() == 10 || key.len() == 26) && key.chars().all(|c| c.is_ascii_hexdigit());
+
+    if is_hex {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixDatagram;
+
+    fn backend() -> (WpaSupplicantBackend, UnixDatagram) {
+        let (client, daemon) = UnixDatagram::pair().unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        daemon.set_nonblocking(true).unwrap();
+
+        let backend = WpaSupplicantBackend {
+            interface: "wlan0".into(),
+            ctrl_path: PathBuf::new(),
+            ctrl: WpaCtrl::from_socket(client),
+            hostapd: None,
+            portal_gateway: None,
+            network_id: None,
+            reconnect: false,
+        };
+
+        (backend, daemon)
+    }
+
+    fn received(daemon: &UnixDatagram) -> Option<String> {
+        let mut buffer = [0; 4096];
+        daemon
+            .recv(&mut buffer)
+            .ok()
+            .map(|size| String::from_utf8_lossy(&buffer[..size]).into_owned())
+    }
+
+    #[test]
+    fn stop_portal_reconnects() {
+        let (mut backend, daemon) = backend();
+        backend.reconnect = true;
+        daemon.send(b"OK\n").unwrap();
+
+        backend.stop_portal().unwrap();
+
+        assert_eq!(received(&daemon), Some("RECONNECT".into()));
+        assert!(!backend.reconnect);
+
+        backend.stop_portal().unwrap();
+
+        assert_eq!(received(&daemon), None);
+    }
+
+    #[test]
+    fn stop_portal_without_portal() {
+        let (mut backend, daemon) = backend();
+
+        backend.stop_portal().unwrap();
+
+        assert_eq!(received(&daemon), None);
+    }
+
+    #[test]
+    fn network_id_of_events() {
+        assert_eq!(
+            event_network_id(
+                "<3>CTRL-EVENT-CONNECTED - Connection to 00:11:22:33:44:55 completed [id=2 id_str=]"
+            ),
+            Some("2")
+        );
+        assert_eq!(
+            event_network_id(
+                "<3>CTRL-EVENT-SSID-TEMP-DISABLED id=0 ssid=\"office\" auth_failures=1 duration=10 reason=WRONG_KEY"
+            ),
+            Some("0")
+        );
+        assert_eq!(event_network_id("<3>CTRL-EVENT-NETWORK-NOT-FOUND "), None);
+    }
+
+    #[test]
+    fn security_from_flags() {
+        assert_eq!(get_security("[WPA2-PSK+SAE-CCMP][ESS]"), Security::Wpa2Wpa3);
+        assert_eq!(get_security("[RSN-SAE-CCMP][ESS]"), Security::Wpa3);
+        assert_eq!(get_security("[WPA2-EAP-CCMP][ESS]"), Security::Enterprise);
+        assert_eq!(get_security("[RSN--OWE-CCMP][ESS]"), Security::Owe);
+        assert_eq!(get_security("[ESS]"), Security::None);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use backend::wpa_ctrl::encode_hex;
+use backend::EnterpriseSettings;
+use errors::*;
+
+/// Copies the certificates and private key uploaded to the portal into
+/// `directory`, which is only accessible by root, and points `enterprise` at
+/// the copies. The backends read them from there whenever they connect, so
+/// they outlive the upload.
+///
+/// Every connection attempt gets files of its own, named after the hex
+/// encoded SSID, a random attempt id and the kind of file, e.g.
+/// `4f6666696365-9c1d3a5e7f20b846-ca-cert`. Files of saved profiles are
+/// never overwritten, and those of an attempt that fails are removed with
+/// `remove_certificates`.
+pub fn store_certificates(
+    directory: &Path,
+    ssid: &str,
+    enterprise: &mut EnterpriseSettings,
+) -> Result<()> {
+    let attempt = attempt_id()?;
+
+    let uploads = vec![
+        ("ca-cert", &mut enterprise.ca_cert),
+        ("client-cert", &mut enterprise.client_cert),
+        ("private-key", &mut enterprise.private_key),
+    ];
+
+    let mut stored = Vec::new();
+
+    for (name, upload) in uploads {
+        if let Some(ref mut path) = *upload {
+            let target = directory.join(format!("{}-{}-{}", encode_hex(ssid), attempt, name));
+
+            if let Err(e) = copy_private(directory, path, &target) {
+                remove_files(&stored);
+                return Err(e)
+                    .chain_err(|| ErrorKind::StoreCertificate(target.display().to_string()));
+            }
+
+            debug!("Stored {} of '{}' in {}", name, ssid, target.display());
+
+            stored.push(target.clone());
+            *path = target;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the files stored by `store_certificates` for an attempt that
+/// failed or was rejected
+pub fn remove_certificates(enterprise: &EnterpriseSettings) {
+    let paths: Vec<PathBuf> = vec![
+        &enterprise.ca_cert,
+        &enterprise.client_cert,
+        &enterprise.private_key,
+    ]
+    .into_iter()
+    .filter_map(|path| path.clone())
+    .collect();
+
+    remove_files(&paths);
+}
+
+fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(()) => debug!("Removed {}", path.display()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Removing {} failed: {}", path.display(), e),
+        }
+    }
+}
+
+/// Random hex id of a connection attempt
+fn attempt_id() -> Result<String> {
+    let mut bytes = [0u8; 8];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn copy_private(directory: &Path, upload: &Path, path: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)?;
+
+    // An existing directory keeps its mode otherwise
+    fs::set_permissions(directory, fs::Permissions::from_mode(0o700))?;
+
+    let mut source = fs::File::open(upload)?;
+
+    // Never replaces a file, so the mode always applies
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+
+    if let Err(e) = io::copy(&mut source, &mut file) {
+        let _ = fs::remove_file(path);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn attempts_store_separate_files() {
+        let directory =
+            env::temp_dir().join(format!("wifi-connect-certificates-{}", process::id()));
+        let upload = env::temp_dir().join(format!("wifi-connect-upload-{}", process::id()));
+        fs::write(&upload, "certificate").unwrap();
+
+        let mut first = EnterpriseSettings {
+            ca_cert: Some(upload.clone()),
+            ..EnterpriseSettings::default()
+        };
+        store_certificates(&directory, "Office", &mut first).unwrap();
+
+        let mut second = EnterpriseSettings {
+            ca_cert: Some(upload.clone()),
+            ..EnterpriseSettings::default()
+        };
+        store_certificates(&directory, "Office", &mut second).unwrap();
+
+        let first_path = first.ca_cert.clone().unwrap();
+        let second_path = second.ca_cert.clone().unwrap();
+
+        assert_ne!(first_path, second_path);
+        assert!(first_path.starts_with(&directory));
+
+        let mode = fs::metadata(&second_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        remove_certificates(&second);
+
+        assert!(first_path.exists());
+        assert!(!second_path.exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+        fs::remove_file(&upload).unwrap();
+    }
+}
@@ -1,35 +1,704 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
 
+use iron::url::Url;
+use toml;
+use toml::Spanned;
+
+use backend::{BackendKind, PortalSecurity, SavedProfiles};
+use errors::*;
+use passphrase::PassphraseGenerator;
+use ssid::SsidTemplate;
+use verify::VerifyPolicy;
+
 const DEFAULT_GATEWAY: &str = "192.168.42.1";
 const DEFAULT_DHCP_RANGE: &str = "192.168.42.2,192.168.42.254";
 const DEFAULT_SSID: &str = "WiFi Connect";
 const DEFAULT_ACTIVITY_TIMEOUT: &str = "0";
+const DEFAULT_SCAN_INTERVAL: &str = "0";
 const DEFAULT_UI_DIRECTORY: &str = "ui";
 const DEFAULT_LISTENING_PORT: &str = "80";
+const DEFAULT_BACKEND: &str = "network-manager";
+const DEFAULT_DAEMON_GRACE_PERIOD: &str = "60";
+const DEFAULT_SAVED_PROFILES: &str = "replace";
+const DEFAULT_PORTAL_SECURITY: &str = "wpa2";
+const DEFAULT_SERIAL_FILE: &str = "/etc/machine-id";
+const DEFAULT_PASSPHRASE_FILE: &str = "/var/lib/wifi-connect/passphrase";
+const DEFAULT_CERTIFICATE_DIRECTORY: &str = "/var/lib/wifi-connect/certificates";
+const DEFAULT_VERIFY: &str = "limited";
+const DEFAULT_VERIFY_TIMEOUT: &str = "30";
+const DEFAULT_LOGIN_CHECK_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+
+// Portal access points are created with a /24 address
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+// Shortest lease time accepted by dnsmasq
+const MIN_LEASE_TIME: u64 = 120;
 
 #[derive(Clone)]
 pub struct Config {
     pub interface: Option<String>,
+    /// Expansion of `ssid_template` once the interface is known
     pub ssid: String,
+    pub ssid_template: SsidTemplate,
+    pub serial_file: PathBuf,
+    /// Configured or generated by `generate_passphrase`
     pub passphrase: Option<String>,
+    pub generate_passphrase: Option<PassphraseGenerator>,
+    pub passphrase_file: PathBuf,
+    pub portal_security: PortalSecurity,
+    pub qr_code: Option<PathBuf>,
+    pub print_qr_code: bool,
     pub gateway: Ipv4Addr,
-    pub dhcp_range: String,
+    pub dhcp_range: DhcpRange,
     pub listening_port: u16,
+    /// `https://` URI of the Captive Portal API advertised with DHCP option
+    /// 114, `None` if not advertised
+    pub captive_portal_api: Option<Url>,
+    /// `https://` URL of the portal page returned by the Captive Portal API,
+    /// `None` to return the gateway
+    pub user_portal_url: Option<Url>,
     pub activity_timeout: u64,
+    pub scan_interval: u64,
     pub ui_directory: PathBuf,
+    pub backend: BackendKind,
+    pub saved_profiles: SavedProfiles,
+    pub certificate_directory: PathBuf,
+    /// Expected HTTP status and body are part of HTTP probes
+    pub verify: VerifyPolicy,
+    pub verify_timeout: u64,
+    /// Answers with 204 No Content unless a captive portal of the connected
+    /// network intercepts the request, `None` if disabled
+    pub login_check_url: Option<Url>,
+    pub daemon: bool,
+    pub daemon_grace_period: u64,
+}
+
+#[cfg(test)]
+impl Config {
+    /// Default settings with the portal SSID expanded, for tests driving the
+    /// network thread
+    pub fn for_tests() -> Self {
+        Config {
+            interface: None,
+            ssid: DEFAULT_SSID.into(),
+            ssid_template: SsidTemplate::parse(DEFAULT_SSID).unwrap(),
+            serial_file: DEFAULT_SERIAL_FILE.into(),
+            passphrase: None,
+            generate_passphrase: None,
+            passphrase_file: DEFAULT_PASSPHRASE_FILE.into(),
+            portal_security: DEFAULT_PORTAL_SECURITY.parse().unwrap(),
+            qr_code: None,
+            print_qr_code: false,
+            gateway: DEFAULT_GATEWAY.parse().unwrap(),
+            dhcp_range: DhcpRange {
+                start: Ipv4Addr::new(192, 168, 42, 2),
+                end: Ipv4Addr::new(192, 168, 42, 254),
+                netmask: DEFAULT_NETMASK,
+                lease_time: None,
+            },
+            listening_port: DEFAULT_LISTENING_PORT.parse().unwrap(),
+            captive_portal_api: None,
+            user_portal_url: None,
+            activity_timeout: 0,
+            scan_interval: 0,
+            ui_directory: DEFAULT_UI_DIRECTORY.into(),
+            backend: BackendKind::NetworkManager,
+            saved_profiles: SavedProfiles::Replace,
+            certificate_directory: DEFAULT_CERTIFICATE_DIRECTORY.into(),
+            verify: DEFAULT_VERIFY.parse().unwrap(),
+            verify_timeout: DEFAULT_VERIFY_TIMEOUT.parse().unwrap(),
+            login_check_url: None,
+            daemon: false,
+            daemon_grace_period: DEFAULT_DAEMON_GRACE_PERIOD.parse().unwrap(),
+        }
+    }
+}
+
+/// DHCP range of the portal network, given as
+/// `start,end[,netmask][,lease_time]` like dnsmasq's `--dhcp-range`
+#[derive(Clone)]
+pub struct DhcpRange {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub lease_time: Option<String>,
+}
+
+impl fmt::Display for DhcpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.start, self.end, self.netmask)?;
+
+        if let Some(ref lease_time) = self.lease_time {
+            write!(f, ",{}", lease_time)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Number,
+    Flag,
+}
+
+/// A setting given as command line option, environment variable or in the
+/// configuration file, in this order of precedence. Its configuration file
+/// key is the name of the `Config` field.
+struct Setting {
+    key: &'static str,
+    arg: &'static str,
+    env: &'static str,
+    kind: Kind,
+    default: Option<&'static str>,
+    secret: bool,
+}
+
+impl Setting {
+    const fn new(
+        key: &'static str,
+        arg: &'static str,
+        env: &'static str,
+        kind: Kind,
+        default: Option<&'static str>,
+    ) -> Self {
+        Setting {
+            key,
+            arg,
+            env,
+            kind,
+            default,
+            secret: false,
+        }
+    }
+
+    /// Not shown by `--print-config`
+    const fn secret(self) -> Self {
+        Setting {
+            secret: true,
+            ..self
+        }
+    }
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting::new(
+        "interface",
+        "portal-interface",
+        "PORTAL_INTERFACE",
+        Kind::Text,
+        None,
+    ),
+    Setting::new(
+        "ssid",
+        "portal-ssid",
+        "PORTAL_SSID",
+        Kind::Text,
+        Some(DEFAULT_SSID),
+    ),
+    Setting::new(
+        "serial_file",
+        "serial-file",
+        "PORTAL_SERIAL_FILE",
+        Kind::Text,
+        Some(DEFAULT_SERIAL_FILE),
+    ),
+    Setting::new(
+        "passphrase",
+        "portal-passphrase",
+        "PORTAL_PASSPHRASE",
+        Kind::Text,
+        None,
+    )
+    .secret(),
+    Setting::new(
+        "generate_passphrase",
+        "generate-passphrase",
+        "PORTAL_GENERATE_PASSPHRASE",
+        Kind::Text,
+        None,
+    ),
+    Setting::new(
+        "passphrase_file",
+        "passphrase-file",
+        "PORTAL_PASSPHRASE_FILE",
+        Kind::Text,
+        Some(DEFAULT_PASSPHRASE_FILE),
+    ),
+    Setting::new(
+        "portal_security",
+        "portal-security",
+        "PORTAL_SECURITY",
+        Kind::Text,
+        Some(DEFAULT_PORTAL_SECURITY),
+    ),
+    Setting::new("qr_code", "qr-code", "PORTAL_QR_CODE", Kind::Text, None),
+    Setting::new(
+        "print_qr_code",
+        "print-qr-code",
+        "PORTAL_PRINT_QR_CODE",
+        Kind::Flag,
+        Some("false"),
+    ),
+    Setting::new(
+        "gateway",
+        "portal-gateway",
+        "PORTAL_GATEWAY",
+        Kind::Text,
+        Some(DEFAULT_GATEWAY),
+    ),
+    Setting::new(
+        "dhcp_range",
+        "portal-dhcp-range",
+        "PORTAL_DHCP_RANGE",
+        Kind::Text,
+        Some(DEFAULT_DHCP_RANGE),
+    ),
+    Setting::new(
+        "listening_port",
+        "portal-listening-port",
+        "PORTAL_LISTENING_PORT",
+        Kind::Number,
+        Some(DEFAULT_LISTENING_PORT),
+    ),
+    Setting::new(
+        "captive_portal_api",
+        "captive-portal-api",
+        "PORTAL_CAPTIVE_PORTAL_API",
+        Kind::Text,
+        None,
+    ),
+    // Defaults to the root of the Captive Portal API host, see
+    // `user_portal_url`
+    Setting::new(
+        "user_portal_url",
+        "user-portal-url",
+        "PORTAL_USER_PORTAL_URL",
+        Kind::Text,
+        None,
+    ),
+    Setting::new(
+        "activity_timeout",
+        "activity-timeout",
+        "ACTIVITY_TIMEOUT",
+        Kind::Number,
+        Some(DEFAULT_ACTIVITY_TIMEOUT),
+    ),
+    Setting::new(
+        "scan_interval",
+        "scan-interval",
+        "WIFI_CONNECT_SCAN_INTERVAL",
+        Kind::Number,
+        Some(DEFAULT_SCAN_INTERVAL),
+    ),
+    // The default depends on the install location, see `get_ui_directory`
+    Setting::new(
+        "ui_directory",
+        "ui-directory",
+        "UI_DIRECTORY",
+        Kind::Text,
+        None,
+    ),
+    Setting::new(
+        "backend",
+        "backend",
+        "WIFI_CONNECT_BACKEND",
+        Kind::Text,
+        Some(DEFAULT_BACKEND),
+    ),
+    Setting::new(
+        "saved_profiles",
+        "saved-profiles",
+        "WIFI_CONNECT_SAVED_PROFILES",
+        Kind::Text,
+        Some(DEFAULT_SAVED_PROFILES),
+    ),
+    Setting::new(
+        "certificate_directory",
+        "certificate-directory",
+        "WIFI_CONNECT_CERTIFICATE_DIRECTORY",
+        Kind::Text,
+        Some(DEFAULT_CERTIFICATE_DIRECTORY),
+    ),
+    Setting::new(
+        "verify",
+        "verify",
+        "WIFI_CONNECT_VERIFY",
+        Kind::Text,
+        Some(DEFAULT_VERIFY),
+    ),
+    Setting::new(
+        "verify_timeout",
+        "verify-timeout",
+        "WIFI_CONNECT_VERIFY_TIMEOUT",
+        Kind::Number,
+        Some(DEFAULT_VERIFY_TIMEOUT),
+    ),
+    Setting::new(
+        "verify_status",
+        "verify-status",
+        "WIFI_CONNECT_VERIFY_STATUS",
+        Kind::Number,
+        None,
+    ),
+    Setting::new(
+        "verify_body",
+        "verify-body",
+        "WIFI_CONNECT_VERIFY_BODY",
+        Kind::Text,
+        None,
+    ),
+    Setting::new(
+        "login_check_url",
+        "login-check-url",
+        "WIFI_CONNECT_LOGIN_CHECK_URL",
+        Kind::Text,
+        Some(DEFAULT_LOGIN_CHECK_URL),
+    ),
+    Setting::new(
+        "daemon",
+        "daemon",
+        "WIFI_CONNECT_DAEMON",
+        Kind::Flag,
+        Some("false"),
+    ),
+    Setting::new(
+        "daemon_grace_period",
+        "daemon-grace-period",
+        "WIFI_CONNECT_DAEMON_GRACE_PERIOD",
+        Kind::Number,
+        Some(DEFAULT_DAEMON_GRACE_PERIOD),
+    ),
+];
+
+#[derive(Clone)]
+enum Source {
+    CommandLine,
+    Environment(&'static str),
+    File(PathBuf, usize),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::Environment(name) => write!(f, "${}", name),
+            Source::File(ref path, line) => write!(f, "{}:{}", path.display(), line),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+struct SettingValue {
+    value: String,
+    source: Source,
+}
+
+/// Effective values of all settings, merged from their sources
+struct Settings {
+    values: BTreeMap<&'static str, SettingValue>,
+}
+
+impl Settings {
+    fn load(matches: &ArgMatches) -> Result<Self> {
+        let config_file = matches
+            .get_one::<String>("config")
+            .cloned()
+            .or_else(|| env::var("WIFI_CONNECT_CONFIG_FILE").ok());
+
+        let mut file_values = match config_file {
+            Some(path) => read_config_file(Path::new(&path))?,
+            None => BTreeMap::new(),
+        };
+
+        let mut values = BTreeMap::new();
+
+        for setting in SETTINGS {
+            let command_line = match setting.kind {
+                Kind::Flag if matches.get_flag(setting.arg) => Some("true".to_string()),
+                Kind::Flag => None,
+                _ => matches.get_one::<String>(setting.arg).cloned(),
+            };
+
+            let value = if let Some(value) = command_line {
+                Some(SettingValue {
+                    value,
+                    source: Source::CommandLine,
+                })
+            } else if let Ok(value) = env::var(setting.env) {
+                Some(SettingValue {
+                    value,
+                    source: Source::Environment(setting.env),
+                })
+            } else if let Some(value) = file_values.remove(setting.key) {
+                Some(value)
+            } else {
+                setting.default.map(|value| SettingValue {
+                    value: value.to_string(),
+                    source: Source::Default,
+                })
+            };
+
+            if let Some(value) = value {
+                values.insert(setting.key, value);
+            }
+        }
+
+        values
+            .entry("ui_directory")
+            .or_insert_with(|| SettingValue {
+                value: get_ui_directory().display().to_string(),
+                source: Source::Default,
+            });
+
+        Ok(Settings { values })
+    }
+
+    fn text(&self, key: &str) -> Option<String> {
+        self.values.get(key).map(|value| value.value.clone())
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Result<T> {
+        let value = match self.values.get(key) {
+            Some(value) => value,
+            None => bail!(ErrorKind::MissingConfigValue(key.into())),
+        };
+
+        value.value.parse::<T>().map_err(|_| {
+            ErrorKind::InvalidConfigValue(key.into(), value.value.clone(), value.source.to_string())
+                .into()
+        })
+    }
+
+    fn flag(&self, key: &str) -> Result<bool> {
+        let value = match self.values.get(key) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+
+        match value.value.as_str() {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => bail!(ErrorKind::InvalidConfigValue(
+                key.into(),
+                value.value.clone(),
+                value.source.to_string()
+            )),
+        }
+    }
+
+    /// Verification policy with the expected status and body of HTTP probes
+    fn verify_policy(&self) -> Result<VerifyPolicy> {
+        let mut verify: VerifyPolicy = self.parse("verify")?;
+
+        let expected_status: Option<u16> = match self.values.get("verify_status") {
+            Some(_) => Some(self.parse("verify_status")?),
+            None => None,
+        };
+        let expected_body = self.text("verify_body");
+
+        match verify {
+            VerifyPolicy::Http {
+                ref mut status,
+                ref mut body,
+                ..
+            } => {
+                if let Some(expected_status) = expected_status {
+                    *status = expected_status;
+                }
+
+                *body = expected_body;
+            }
+            _ if expected_status.is_some() => {
+                bail!(ErrorKind::VerifyOptionNotUsed("verify_status".into()))
+            }
+            _ if expected_body.is_some() => {
+                bail!(ErrorKind::VerifyOptionNotUsed("verify_body".into()))
+            }
+            _ => {}
+        }
+
+        Ok(verify)
+    }
+
+    /// An empty URL disables the login check
+    fn login_check_url(&self) -> Result<Option<Url>> {
+        let value = match self.values.get("login_check_url") {
+            Some(value) if !value.value.is_empty() => value,
+            _ => return Ok(None),
+        };
+
+        match Url::parse(&value.value) {
+            Ok(ref url) if url.scheme() == "http" && url.host_str().is_some() => {
+                Ok(Some(url.clone()))
+            }
+            _ => bail!(ErrorKind::InvalidConfigValue(
+                "login_check_url".into(),
+                value.value.clone(),
+                value.source.to_string()
+            )),
+        }
+    }
+
+    /// RFC 8908 requires the API and the portal page it points to to be
+    /// served over TLS, which has to be done by a proxy in front of the server
+    fn https_url(&self, key: &str) -> Result<Option<Url>> {
+        let value = match self.values.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        match Url::parse(&value.value) {
+            Ok(ref url) if url.scheme() == "https" && url.host_str().is_some() => {
+                Ok(Some(url.clone()))
+            }
+            _ => bail!(ErrorKind::InvalidConfigValue(
+                key.into(),
+                value.value.clone(),
+                value.source.to_string()
+            )),
+        }
+    }
+
+    /// The proxy serving the Captive Portal API is assumed to serve the
+    /// portal page at the root of the same host unless configured otherwise
+    fn user_portal_url(&self, captive_portal_api: Option<&Url>) -> Result<Option<Url>> {
+        match self.https_url("user_portal_url")? {
+            Some(url) => Ok(Some(url)),
+            None => Ok(captive_portal_api.and_then(|url| url.join("/").ok())),
+        }
+    }
+
+    fn config(&self) -> Result<Config> {
+        let (gateway, dhcp_range) = parse_portal_network(
+            &self.parse::<String>("gateway")?,
+            &self.parse::<String>("dhcp_range")?,
+        )?;
+
+        let ssid: String = self.parse("ssid")?;
+        let ssid_template = SsidTemplate::parse(&ssid)?;
+
+        let passphrase = self.text("passphrase");
+        let generate_passphrase = match self.values.get("generate_passphrase") {
+            Some(_) => Some(self.parse("generate_passphrase")?),
+            None => None,
+        };
+
+        if passphrase.is_some() && generate_passphrase.is_some() {
+            bail!(ErrorKind::PassphraseConflict);
+        }
+
+        let portal_security = self.parse("portal_security")?;
+        let has_passphrase = passphrase.is_some() || generate_passphrase.is_some();
+
+        match portal_security {
+            PortalSecurity::Wpa2Wpa3 if !has_passphrase => {
+                bail!(ErrorKind::PortalPassphraseRequired("wpa2-wpa3".into()))
+            }
+            PortalSecurity::Owe if has_passphrase => {
+                bail!(ErrorKind::PortalPassphraseNotUsed("owe".into()))
+            }
+            _ => {}
+        }
+
+        let backend: BackendKind = self.parse("backend")?;
+        backend.check_portal_security(portal_security, has_passphrase)?;
+
+        let verify = self.verify_policy()?;
+
+        let captive_portal_api = self.https_url("captive_portal_api")?;
+        let user_portal_url = self.user_portal_url(captive_portal_api.as_ref())?;
+
+        Ok(Config {
+            interface: self.text("interface"),
+            ssid,
+            ssid_template,
+            serial_file: self.parse("serial_file")?,
+            passphrase,
+            generate_passphrase,
+            passphrase_file: self.parse("passphrase_file")?,
+            portal_security,
+            qr_code: self.text("qr_code").map(PathBuf::from),
+            print_qr_code: self.flag("print_qr_code")?,
+            gateway,
+            dhcp_range,
+            listening_port: self.parse("listening_port")?,
+            captive_portal_api,
+            user_portal_url,
+            activity_timeout: self.parse("activity_timeout")?,
+            scan_interval: self.parse("scan_interval")?,
+            ui_directory: self.parse("ui_directory")?,
+            backend,
+            saved_profiles: self.parse("saved_profiles")?,
+            certificate_directory: self.parse("certificate_directory")?,
+            verify,
+            verify_timeout: self.parse("verify_timeout")?,
+            login_check_url: self.login_check_url()?,
+            daemon: self.flag("daemon")?,
+            daemon_grace_period: self.parse("daemon_grace_period")?,
+        })
+    }
 }
 
-pub fn get_config() -> Config {
+/// Prints settings in configuration file format with the source of each
+/// value as comment
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for setting in SETTINGS {
+            let value = match self.values.get(setting.key) {
+                Some(value) => value,
+                None => {
+                    writeln!(f, "# {} is not set", setting.key)?;
+                    continue;
+                }
+            };
+
+            let formatted = if setting.secret {
+                toml::Value::String("<redacted>".into()).to_string()
+            } else {
+                match setting.kind {
+                    Kind::Text => toml::Value::String(value.value.clone()).to_string(),
+                    Kind::Number | Kind::Flag => value.value.clone(),
+                }
+            };
+
+            writeln!(f, "{} = {} # {}", setting.key, formatted, value.source)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn get_config() -> Result<Config> {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("config_file")
+                .help("TOML configuration file, overridden by environment variables and command line options")
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the effective configuration with the source of each value and exit")
+        )
         .arg(
             Arg::new("portal-interface")
                 .short('i')
@@ -47,6 +716,15 @@ pub fn get_config() -> Config {
                     DEFAULT_SSID
                 ))
         )
+        .arg(
+            Arg::new("serial-file")
+                .long("serial-file")
+                .value_name("serial_file")
+                .help(&format!(
+                    "File with the device serial number for the {{serial}} SSID placeholder (default: {})",
+                    DEFAULT_SERIAL_FILE
+                ))
+        )
         .arg(
             Arg::new("portal-passphrase")
                 .short('p')
@@ -54,6 +732,44 @@ pub fn get_config() -> Config {
                 .value_name("passphrase")
                 .help("WPA2 Passphrase of the captive portal WiFi network (default: none)")
         )
+        .arg(
+            Arg::new("generate-passphrase")
+                .long("generate-passphrase")
+                .value_name("generator")
+                .value_parser(["derived", "random"])
+                .help("Generate a per-device portal passphrase, derived from the serial number or random")
+        )
+        .arg(
+            Arg::new("passphrase-file")
+                .long("passphrase-file")
+                .value_name("passphrase_file")
+                .help(&format!(
+                    "File keeping the random portal passphrase (default: {})",
+                    DEFAULT_PASSPHRASE_FILE
+                ))
+        )
+        .arg(
+            Arg::new("portal-security")
+                .long("portal-security")
+                .value_name("portal_security")
+                .value_parser(["wpa2", "wpa2-wpa3", "owe"])
+                .help(&format!(
+                    "Security of the captive portal WiFi network, wpa2-wpa3 with the wpa-supplicant backend only (default: {})",
+                    DEFAULT_PORTAL_SECURITY
+                ))
+        )
+        .arg(
+            Arg::new("qr-code")
+                .long("qr-code")
+                .value_name("qr_code")
+                .help("Write a QR code joining the portal network to a .png or .svg file")
+        )
+        .arg(
+            Arg::new("print-qr-code")
+                .long("print-qr-code")
+                .action(ArgAction::SetTrue)
+                .help("Print a QR code joining the portal network to the terminal")
+        )
         .arg(
             Arg::new("portal-gateway")
                 .short('g')
@@ -84,6 +800,18 @@ pub fn get_config() -> Config {
                     DEFAULT_LISTENING_PORT
                 ))
         )
+        .arg(
+            Arg::new("captive-portal-api")
+                .long("captive-portal-api")
+                .value_name("captive_portal_api")
+                .help("https:// URI of the Captive Portal API advertised with DHCP option 114 (default: none)")
+        )
+        .arg(
+            Arg::new("user-portal-url")
+                .long("user-portal-url")
+                .value_name("user_portal_url")
+                .help("https:// URL of the portal page returned by the Captive Portal API (default: root of the API host)")
+        )
         .arg(
             Arg::new("activity-timeout")
                 .short('a')
@@ -91,6 +819,12 @@ pub fn get_config() -> Config {
                 .value_name("activity_timeout")
                 .help("Exit if no activity for the specified time (seconds) (default: none)")
         )
+        .arg(
+            Arg::new("scan-interval")
+                .long("scan-interval")
+                .value_name("scan_interval")
+                .help("Scan with the captive portal up every specified time (seconds) (default: none)")
+        )
         .arg(
             Arg::new("ui-directory")
                 .short('u')
@@ -101,86 +835,282 @@ pub fn get_config() -> Config {
                     DEFAULT_UI_DIRECTORY
                 ))
         )
+        .arg(
+            Arg::new("backend")
+                .short('b')
+                .long("backend")
+                .value_name("backend")
+                .value_parser(["network-manager", "iwd", "wpa-supplicant"])
+                .help(&format!(
+                    "Service managing the wireless interface (default: {})",
+                    DEFAULT_BACKEND
+                ))
+        )
+        .arg(
+            Arg::new("saved-profiles")
+                .long("saved-profiles")
+                .value_name("saved_profiles")
+                .value_parser(["replace", "keep"])
+                .help(&format!(
+                    "Replace saved WiFi profiles once connected, or keep them with lower priority (default: {})",
+                    DEFAULT_SAVED_PROFILES
+                ))
+        )
+        .arg(
+            Arg::new("certificate-directory")
+                .long("certificate-directory")
+                .value_name("certificate_directory")
+                .help(&format!(
+                    "Directory keeping the 802.1X certificates uploaded in the portal (default: {})",
+                    DEFAULT_CERTIFICATE_DIRECTORY
+                ))
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .value_name("verify")
+                .help(&format!(
+                    "When a connection works: associated, ip, limited, full, an http:// URL, tcp:host:port or dns:host (default: {})",
+                    DEFAULT_VERIFY
+                ))
+        )
+        .arg(
+            Arg::new("verify-timeout")
+                .long("verify-timeout")
+                .value_name("verify_timeout")
+                .help(&format!(
+                    "Time (seconds) a new connection has to pass the verification (default: {})",
+                    DEFAULT_VERIFY_TIMEOUT
+                ))
+        )
+        .arg(
+            Arg::new("verify-status")
+                .long("verify-status")
+                .value_name("verify_status")
+                .help("HTTP status expected from an HTTP verification probe (default: 200)")
+        )
+        .arg(
+            Arg::new("verify-body")
+                .long("verify-body")
+                .value_name("verify_body")
+                .help("Text the body of an HTTP verification probe must contain (default: any body)")
+        )
+        .arg(
+            Arg::new("login-check-url")
+                .long("login-check-url")
+                .value_name("login_check_url")
+                .help(&format!(
+                    "http:// URL answering 204 No Content, used to detect captive portals of connected networks, empty to disable (default: {})",
+                    DEFAULT_LOGIN_CHECK_URL
+                ))
+        )
+        .arg(
+            Arg::new("daemon")
+                .long("daemon")
+                .action(ArgAction::SetTrue)
+                .help("Keep running after connecting and restart the portal when connectivity is lost")
+        )
+        .arg(
+            Arg::new("daemon-grace-period")
+                .long("daemon-grace-period")
+                .value_name("daemon_grace_period")
+                .help(&format!(
+                    "Time (seconds) without connectivity before a daemon restarts the portal (default: {})",
+                    DEFAULT_DAEMON_GRACE_PERIOD
+                ))
+        )
         .get_matches();
 
-    let interface: Option<String> = matches
-        .get_one::<String>("portal-interface")
-        .map_or_else(|| env::var("PORTAL_INTERFACE").ok(), |v| Some(v.clone()));
+    let settings = Settings::load(&matches)?;
 
-        let ssid: String = matches
-        .get_one::<String>("portal-ssid")
-        .map_or_else(
-            || env::var("PORTAL_SSID").unwrap_or_else(|_| DEFAULT_SSID.to_string()),
-            |v| v.clone(),
-        );
+    if matches.get_flag("print-config") {
+        print!("{}", settings);
+        process::exit(0);
+    }
 
-    let passphrase: Option<String> = matches
-        .get_one::<String>("portal-passphrase")
-        .map_or_else(|| env::var("PORTAL_PASSPHRASE").ok(), |v| Some(v.clone()));
-
-    let gateway = Ipv4Addr::from_str(
-        &matches
-            .get_one::<String>("portal-gateway")
-            .map_or_else(
-                || env::var("PORTAL_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string()),
-                |v| v.clone(),
-            ),
-    )
-    .expect("Cannot parse gateway address");
+    settings.config()
+}
 
-    let dhcp_range = matches
-        .get_one::<String>("portal-dhcp-range")
-        .map_or_else(
-            || env::var("PORTAL_DHCP_RANGE").unwrap_or_else(|_| DEFAULT_DHCP_RANGE.to_string()),
-            |v| v.clone(),
-        );
+/// Reads the configuration file, keeping the line each value is defined on
+fn read_config_file(path: &Path) -> Result<BTreeMap<String, SettingValue>> {
+    let content = fs::read_to_string(path)
+        .chain_err(|| ErrorKind::ReadConfigFile(path.display().to_string()))?;
 
-    let listening_port = matches
-        .get_one::<String>("portal-listening-port")
-        .map_or_else(
-            || {
-                env::var("PORTAL_LISTENING_PORT")
-                    .unwrap_or_else(|_| DEFAULT_LISTENING_PORT.to_string())
-            },
-            |v| v.clone(),
-        )
-        .parse::<u16>()
-        .expect("Cannot parse listening port number");
-
-    let activity_timeout = u64::from_str(
-        &matches
-            .get_one::<String>("activity-timeout")
-            .map_or_else(
-                || env::var("ACTIVITY_TIMEOUT").unwrap_or_else(|_| DEFAULT_ACTIVITY_TIMEOUT.to_string()),
-                |v| v.clone(),
-            ),
-    )
-    .expect("Cannot parse activity timeout");
+    let table: BTreeMap<Spanned<String>, Spanned<toml::Value>> = toml::from_str(&content)
+        .map_err(|e| ErrorKind::ParseConfigFile(path.display().to_string(), e.to_string()))?;
+
+    let mut values = BTreeMap::new();
+
+    for (key, value) in table {
+        let line = content[..key.span().start].matches('\n').count() + 1;
+        let source = Source::File(path.to_path_buf(), line);
+        let key = key.into_inner();
+
+        let setting = match SETTINGS.iter().find(|setting| setting.key == key) {
+            Some(setting) => setting,
+            None => bail!(ErrorKind::UnknownConfigKey(key, source.to_string())),
+        };
+
+        let value = match (setting.kind, value.into_inner()) {
+            (Kind::Text, toml::Value::String(value)) => value,
+            (Kind::Number, toml::Value::Integer(value)) => value.to_string(),
+            (Kind::Flag, toml::Value::Boolean(value)) => value.to_string(),
+            (_, value) => bail!(ErrorKind::InvalidConfigValue(
+                key,
+                value.to_string(),
+                source.to_string()
+            )),
+        };
+
+        values.insert(key, SettingValue { value, source });
+    }
+
+    Ok(values)
+}
+
+/// Parses and validates the portal gateway and DHCP range. All problems are
+/// reported at once: the first one determines the exit code, the others are
+/// chained to it.
+fn parse_portal_network(gateway: &str, dhcp_range: &str) -> Result<(Ipv4Addr, DhcpRange)> {
+    let mut problems = Vec::new();
+
+    let gateway = parse_address(gateway, ErrorKind::InvalidGateway, &mut problems);
+
+    // Backends give the access point a /24 address
+    if let Some(gateway) = gateway {
+        if !is_host_address(gateway, DEFAULT_NETMASK) {
+            problems.push(ErrorKind::GatewayNotHost(gateway.to_string()));
+        }
+    }
+
+    let fields: Vec<&str> = dhcp_range.split(',').map(str::trim).collect();
+
+    if fields.len() < 2 || fields.len() > 4 {
+        problems.push(ErrorKind::InvalidDhcpRange(dhcp_range.into()));
+        return Err(chain_problems(problems));
+    }
+
+    let start = parse_address(fields[0], ErrorKind::InvalidDhcpRange, &mut problems);
+    let end = parse_address(fields[1], ErrorKind::InvalidDhcpRange, &mut problems);
+
+    let mut netmask = DEFAULT_NETMASK;
+    let mut lease_time = None;
 
+    // The netmask and lease time are both optional, only the former has dots
+    for field in &fields[2..] {
+        if field.contains('.') {
+            match Ipv4Addr::from_str(field) {
+                Ok(address) if address == DEFAULT_NETMASK => netmask = address,
+                // Backends give the access point a /24 address
+                Ok(address) if is_netmask(address) => {
+                    problems.push(ErrorKind::UnsupportedNetmask(field.to_string()))
+                }
+                _ => problems.push(ErrorKind::InvalidNetmask(field.to_string())),
+            }
+        } else if is_lease_time(field) {
+            lease_time = Some(field.to_string());
+        } else {
+            problems.push(ErrorKind::InvalidLeaseTime(field.to_string()));
+        }
+    }
+
+    if let (Some(gateway), Some(start), Some(end)) = (gateway, start, end) {
+        for address in &[start, end] {
+            if !is_same_subnet(*address, gateway, netmask) {
+                problems.push(ErrorKind::DhcpRangeOutsideSubnet(
+                    address.to_string(),
+                    gateway.to_string(),
+                    netmask.to_string(),
+                ));
+            }
+        }
+
+        if start > end {
+            problems.push(ErrorKind::DhcpRangeNotOrdered(
+                start.to_string(),
+                end.to_string(),
+            ));
+        } else if start <= gateway && gateway <= end {
+            problems.push(ErrorKind::DhcpRangeContainsGateway(gateway.to_string()));
+        }
 
-    let ui_directory = get_ui_directory(matches.get_one::<String>("ui-directory").map(String::as_str));
+        if problems.is_empty() {
+            let dhcp_range = DhcpRange {
+                start,
+                end,
+                netmask,
+                lease_time,
+            };
 
-    Config {
-        interface,
-        ssid,
-        passphrase,
-        gateway,
-        dhcp_range,
-        listening_port,
-        activity_timeout,
-        ui_directory,
+            return Ok((gateway, dhcp_range));
+        }
     }
+
+    Err(chain_problems(problems))
 }
 
-fn get_ui_directory(cmd_ui_directory: Option<&str>) -> PathBuf {
-    if let Some(ui_directory) = cmd_ui_directory {
-        return PathBuf::from(ui_directory);
+fn parse_address<F>(value: &str, kind: F, problems: &mut Vec<ErrorKind>) -> Option<Ipv4Addr>
+where
+    F: FnOnce(String) -> ErrorKind,
+{
+    match Ipv4Addr::from_str(value) {
+        Ok(address) => Some(address),
+        Err(_) => {
+            problems.push(kind(value.into()));
+            None
+        }
     }
+}
 
-    if let Ok(ui_directory) = env::var("UI_DIRECTORY") {
-        return PathBuf::from(ui_directory);
+fn chain_problems(mut problems: Vec<ErrorKind>) -> Error {
+    let mut error: Error = problems.pop().expect("no problems to report").into();
+
+    while let Some(problem) = problems.pop() {
+        error = Error::with_chain(error, problem);
     }
 
+    error
+}
+
+/// Neither the network nor the broadcast address of its subnet
+fn is_host_address(address: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+    let host = u32::from(address) & !u32::from(netmask);
+
+    host != 0 && host != !u32::from(netmask)
+}
+
+/// Contiguous, non-empty netmask
+fn is_netmask(address: Ipv4Addr) -> bool {
+    let bits = u32::from(address);
+    bits != 0 && bits.leading_ones() + bits.trailing_zeros() == 32
+}
+
+fn is_same_subnet(address: Ipv4Addr, gateway: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+    let netmask = u32::from(netmask);
+    u32::from(address) & netmask == u32::from(gateway) & netmask
+}
+
+/// `infinite` or a duration in seconds, or with a `m`, `h`, `d` or `w` suffix
+fn is_lease_time(value: &str) -> bool {
+    if value == "infinite" {
+        return true;
+    }
+
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 60 * 60),
+        Some((index, 'd')) => (&value[..index], 24 * 60 * 60),
+        Some((index, 'w')) => (&value[..index], 7 * 24 * 60 * 60),
+        _ => (value, 1),
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) => number.saturating_mul(unit) >= MIN_LEASE_TIME,
+        Err(_) => false,
+    }
+}
+
+fn get_ui_directory() -> PathBuf {
     if let Some(install_ui_directory) = get_install_ui_directory() {
         return install_ui_directory;
     }
@@ -219,3 +1149,124 @@ fn get_install_ui_directory() -> Option<PathBuf> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problem(gateway: &str, dhcp_range: &str) -> i32 {
+        exit_code(&parse_portal_network(gateway, dhcp_range).err().unwrap())
+    }
+
+    fn settings(values: &[(&'static str, &str)]) -> Settings {
+        Settings {
+            values: values
+                .iter()
+                .map(|&(key, value)| {
+                    let value = SettingValue {
+                        value: value.into(),
+                        source: Source::Default,
+                    };
+                    (key, value)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn user_portal_url_of_api_host() {
+        let api = Url::parse("https://portal.example.com/captive-portal/api").unwrap();
+
+        assert_eq!(
+            settings(&[]).user_portal_url(Some(&api)).unwrap(),
+            Some(Url::parse("https://portal.example.com/").unwrap())
+        );
+        assert_eq!(settings(&[]).user_portal_url(None).unwrap(), None);
+
+        let configured = settings(&[("user_portal_url", "https://setup.example.com/wifi")]);
+        assert_eq!(
+            configured.user_portal_url(Some(&api)).unwrap(),
+            Some(Url::parse("https://setup.example.com/wifi").unwrap())
+        );
+
+        let plain = settings(&[("user_portal_url", "http://192.168.42.1/")]);
+        assert!(plain.user_portal_url(Some(&api)).is_err());
+    }
+
+    #[test]
+    fn portal_network_netmask() {
+        let (_, range) = parse_portal_network(
+            "192.168.42.1",
+            "192.168.42.2,192.168.42.254,255.255.255.0,1h",
+        )
+        .unwrap();
+
+        assert_eq!(range.netmask, DEFAULT_NETMASK);
+        assert_eq!(range.lease_time, Some("1h".into()));
+
+        assert_eq!(
+            problem("10.0.0.1", "10.0.1.2,10.0.1.254,255.255.0.0"),
+            exit_code(&ErrorKind::UnsupportedNetmask(String::new()).into())
+        );
+        assert_eq!(
+            problem("10.0.0.1", "10.0.0.2,10.0.0.254,255.0.255.0"),
+            exit_code(&ErrorKind::InvalidNetmask(String::new()).into())
+        );
+    }
+
+    #[test]
+    fn portal_network_gateway() {
+        assert_eq!(
+            problem("192.168.42.0", "192.168.42.2,192.168.42.254"),
+            exit_code(&ErrorKind::GatewayNotHost(String::new()).into())
+        );
+        assert_eq!(
+            problem("192.168.42.255", "192.168.42.1,192.168.42.254"),
+            exit_code(&ErrorKind::GatewayNotHost(String::new()).into())
+        );
+        assert_eq!(
+            problem("192.168.42.100", "192.168.42.2,192.168.42.254"),
+            exit_code(&ErrorKind::DhcpRangeContainsGateway(String::new()).into())
+        );
+
+        assert!(parse_portal_network("192.168.42.254", "192.168.42.1,192.168.42.253").is_ok());
+    }
+
+    #[test]
+    fn portal_network_range() {
+        assert_eq!(
+            problem("192.168.42.1", "192.168.42.254,192.168.42.2"),
+            exit_code(&ErrorKind::DhcpRangeNotOrdered(String::new(), String::new()).into())
+        );
+        assert_eq!(
+            problem("192.168.42.1", "192.168.42.2,192.168.43.254"),
+            exit_code(
+                &ErrorKind::DhcpRangeOutsideSubnet(String::new(), String::new(), String::new())
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn portal_network_problems_reported_together() {
+        let error = parse_portal_network("192.168.42.0", "10.0.0.2,192.168.42.x,255.255.0.0,1s")
+            .err()
+            .unwrap();
+
+        let problems: Vec<String> = error.iter().map(ToString::to_string).collect();
+
+        assert_eq!(
+            problems,
+            [
+                "Gateway 192.168.42.0 is the network or broadcast address of the access point subnet 255.255.255.0",
+                "Invalid DHCP range, expected start,end[,netmask][,lease_time]: 192.168.42.x",
+                "Unsupported DHCP range netmask, the access point subnet is 255.255.255.0: 255.255.0.0",
+                "Invalid DHCP lease time, expected at least 2m or infinite: 1s",
+            ]
+        );
+        assert_eq!(
+            exit_code(&error),
+            exit_code(&ErrorKind::GatewayNotHost(String::new()).into())
+        );
+    }
+}
@@ -1,25 +1,28 @@
 use std::process::{Child, Command};
 
-use network_manager::Device;
-
 use config::Config;
 use errors::*;
 
-pub fn start_dnsmasq(config: &Config, device: &Device) -> Result<Child> {
-    let args = [
-        &format!("--address=/#/{}", config.gateway),
-        &format!("--dhcp-range={}", config.dhcp_range),
-        &format!("--dhcp-option=option:router,{}", config.gateway),
-        &format!("--interface={}", device.interface()),
-        "--keep-in-foreground",
-        "--bind-interfaces",
-        "--except-interface=lo",
-        "--conf-file",
-        "--no-hosts",
+pub fn start_dnsmasq(config: &Config, interface: &str) -> Result<Child> {
+    let mut args = vec![
+        format!("--address=/#/{}", config.gateway),
+        format!("--dhcp-range={}", config.dhcp_range),
+        format!("--dhcp-option=option:router,{}", config.gateway),
+        format!("--interface={}", interface),
+        "--keep-in-foreground".into(),
+        "--bind-interfaces".into(),
+        "--except-interface=lo".into(),
+        "--conf-file".into(),
+        "--no-hosts".into(),
     ];
 
+    // RFC 8910 captive portal API URI
+    if let Some(ref url) = config.captive_portal_api {
+        args.push(format!("--dhcp-option=114,{}", url));
+    }
+
     Command::new("dnsmasq")
-        .args(args)
+        .args(&args)
         .spawn()
         .chain_err(|| ErrorKind::Dnsmasq)
 }
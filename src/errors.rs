@@ -8,6 +8,7 @@ error_chain! {
         Recv(::std::sync::mpsc::RecvError);
         SendNetworkCommand(::std::sync::mpsc::SendError<network::NetworkCommand>);
         Nix(::nix::Error);
+        DBus(::dbus::Error);
     }
 
     links {
@@ -15,14 +16,6 @@ error_chain! {
     }
 
     errors {
-        RecvAccessPointSSIDs {
-            description("Receiving access point SSIDs failed")
-        }
-
-        SendAccessPointSSIDs {
-            description("Sending access point SSIDs failed")
-        }
-
         SerializeAccessPointSSIDs {
             description("Serializing access point SSIDs failed")
         }
@@ -110,14 +103,276 @@ error_chain! {
         WiFiConnectionFailed {
             description("Establishing WiFi connection failed")
         }
+
+        UnsupportedByBackend(backend: String, feature: String) {
+            description("Feature not supported by the WiFi backend")
+            display("The {} backend does not support {}", backend, feature)
+        }
+
+        IwdProperty(property: String) {
+            description("Reading iwd D-Bus reply failed")
+            display("Reading iwd D-Bus reply failed: {}", property)
+        }
+
+        IwdMode(mode: String) {
+            description("Switching iwd device mode failed")
+            display("Switching iwd device to {} mode failed", mode)
+        }
+
+        RuntimeDirectory(path: String) {
+            description("Preparing the runtime directory failed")
+            display("Preparing the runtime directory failed, it must be a directory only accessible by its owner: {}", path)
+        }
+
+        TooManyEventStreams {
+            description("Too many clients subscribed to portal events")
+        }
+
+        InvalidProvisioningValue(setting: String) {
+            description("Network setting contains a line break or NUL character")
+            display("The {} contains a line break or NUL character", setting)
+        }
+
+        InterfaceAddress(interface: String) {
+            description("Configuring interface address failed")
+            display("Configuring address of interface '{}' failed", interface)
+        }
+
+        WpaCtrlConnect(path: String) {
+            description("Connecting to wpa_supplicant control socket failed")
+            display("Connecting to wpa_supplicant control socket '{}' failed", path)
+        }
+
+        WpaCtrlRequest(command: String) {
+            description("wpa_supplicant control request failed")
+            display("wpa_supplicant control request {} failed", command)
+        }
+
+        WpaCtrlReply(command: String, reply: String) {
+            description("Unexpected wpa_supplicant control reply")
+            display("Unexpected wpa_supplicant reply to {}: {}", command, reply)
+        }
+
+        Hostapd {
+            description("Spawning hostapd failed")
+        }
+
+        InvalidStateTransition(state: String, event: String) {
+            description("Invalid state transition")
+            display("Invalid state transition from {} on {}", state, event)
+        }
+
+        SerializeState {
+            description("Serializing the portal state failed")
+        }
+
+        RecvNetworkCommandReply {
+            description("Network thread did not reply")
+        }
+
+        NetworkManagerProperty(property: String) {
+            description("Getting NetworkManager property failed")
+            display("Getting NetworkManager property failed: {}", property)
+        }
+
+        WrongPassphrase(ssid: String) {
+            description("Wrong passphrase or credentials")
+            display("Wrong passphrase or credentials for access point '{}'", ssid)
+        }
+
+        AccessPointNotFound(ssid: String) {
+            description("Access point not found")
+            display("Access point '{}' not found", ssid)
+        }
+
+        DhcpTimeout(ssid: String) {
+            description("Timeout reached in waiting for an IP address")
+            display("Timeout reached in waiting for an IP address from '{}'", ssid)
+        }
+
+        ReadConfigFile(path: String) {
+            description("Reading configuration file failed")
+            display("Reading configuration file failed: {}", path)
+        }
+
+        ParseConfigFile(path: String, message: String) {
+            description("Parsing configuration file failed")
+            display("Parsing configuration file {} failed: {}", path, message)
+        }
+
+        UnknownConfigKey(key: String, location: String) {
+            description("Unknown configuration key")
+            display("Unknown configuration key '{}' at {}", key, location)
+        }
+
+        InvalidConfigValue(key: String, value: String, source: String) {
+            description("Invalid configuration value")
+            display("Invalid value {:?} for '{}' from {}", value, key, source)
+        }
+
+        MissingConfigValue(key: String) {
+            description("Missing configuration value")
+            display("Missing configuration value: {}", key)
+        }
+
+        InvalidGateway(gateway: String) {
+            description("Invalid gateway address")
+            display("Invalid gateway address: {}", gateway)
+        }
+
+        InvalidDhcpRange(dhcp_range: String) {
+            description("Invalid DHCP range")
+            display("Invalid DHCP range, expected start,end[,netmask][,lease_time]: {}", dhcp_range)
+        }
+
+        InvalidNetmask(netmask: String) {
+            description("Invalid DHCP range netmask")
+            display("Invalid DHCP range netmask: {}", netmask)
+        }
+
+        UnsupportedNetmask(netmask: String) {
+            description("Unsupported DHCP range netmask")
+            display("Unsupported DHCP range netmask, the access point subnet is 255.255.255.0: {}", netmask)
+        }
+
+        InvalidLeaseTime(lease_time: String) {
+            description("Invalid DHCP lease time")
+            display("Invalid DHCP lease time, expected at least 2m or infinite: {}", lease_time)
+        }
+
+        DhcpRangeOutsideSubnet(address: String, gateway: String, netmask: String) {
+            description("DHCP range outside of the gateway subnet")
+            display("DHCP range address {} is outside of the subnet of gateway {} with netmask {}", address, gateway, netmask)
+        }
+
+        DhcpRangeNotOrdered(start: String, end: String) {
+            description("DHCP range start after its end")
+            display("DHCP range start {} is after its end {}", start, end)
+        }
+
+        DhcpRangeContainsGateway(gateway: String) {
+            description("DHCP range contains the gateway")
+            display("DHCP range contains the gateway address {}", gateway)
+        }
+
+        GatewayNotHost(gateway: String) {
+            description("Gateway is not a host address")
+            display("Gateway {} is the network or broadcast address of the access point subnet 255.255.255.0", gateway)
+        }
+
+        InvalidSsidTemplate(template: String) {
+            description("Invalid SSID template")
+            display("Invalid SSID template, known placeholders are {{mac4}}, {{mac6}}, {{hostname}} and {{serial}}: {}", template)
+        }
+
+        SsidPlaceholder(placeholder: String) {
+            description("Expanding SSID placeholder failed")
+            display("Expanding SSID placeholder failed: {{{}}}", placeholder)
+        }
+
+        InvalidSsid(ssid: String) {
+            description("Invalid SSID")
+            display("Invalid SSID, expected 1 to 32 bytes: {:?}", ssid)
+        }
+
+        EmptyFile(path: String) {
+            description("Empty file")
+            display("Empty file: {}", path)
+        }
+
+        PassphraseConflict {
+            description("Both a portal passphrase and a passphrase generator are set")
+            display("Both a portal passphrase and a passphrase generator are set, only one of them can be used")
+        }
+
+        GeneratePassphrase {
+            description("Generating the portal passphrase failed")
+        }
+
+        WritePassphraseFile(path: String) {
+            description("Writing the portal passphrase file failed")
+            display("Writing the portal passphrase file failed: {}", path)
+        }
+
+        QrCode {
+            description("Encoding the portal QR code failed")
+        }
+
+        QrCodeFormat(path: String) {
+            description("Unknown QR code image format")
+            display("Unknown QR code image format, expected a .png or .svg file: {}", path)
+        }
+
+        WriteQrCode(path: String) {
+            description("Writing the QR code image failed")
+            display("Writing the QR code image failed: {}", path)
+        }
+
+        ConnectionPriority(connection: String) {
+            description("Setting the connection autoconnect priority failed")
+            display("Setting the autoconnect priority of connection {} failed", connection)
+        }
+
+        ReadConnectionPriorities {
+            description("Reading the connection autoconnect priorities failed")
+        }
+
+        SavedNetworkNotFound(ssid: String) {
+            description("Saved network not found")
+            display("Saved network not found: {}", ssid)
+        }
+
+        SavedNetworksCommand {
+            description("Sending NetworkCommand::SavedNetworks failed")
+        }
+
+        SerializeSavedNetworks {
+            description("Serializing saved networks failed")
+        }
+
+        PortalScan {
+            description("Scanning while the access point is up failed")
+        }
+
+        ScanCommand {
+            description("Sending NetworkCommand::Scan failed")
+        }
+
+        PortalPassphraseRequired(security: String) {
+            description("Portal security requires a passphrase")
+            display("Portal security '{}' requires a portal passphrase or a passphrase generator", security)
+        }
+
+        PortalPassphraseNotUsed(security: String) {
+            description("Portal security does not use a passphrase")
+            display("Portal security '{}' does not use a passphrase, remove the portal passphrase and passphrase generator", security)
+        }
+
+        StoreCertificate(path: String) {
+            description("Storing the uploaded certificate failed")
+            display("Storing the uploaded certificate failed: {}", path)
+        }
+
+        ConnectionNotVerified(ssid: String, policy: String) {
+            description("Timeout reached in verifying the connection")
+            display("Timeout reached in verifying the connection to '{}': {}", ssid, policy)
+        }
+
+        VerifyOptionNotUsed(key: String) {
+            description("Setting only used by HTTP verification probes")
+            display("'{}' is only used when verifying connections with an http:// URL", key)
+        }
+
+        UnexpectedState(state: String) {
+            description("Unexpected state in the network thread")
+            display("Unexpected state in the network thread: {}", state)
+        }
     }
 }
 
 pub fn exit_code(e: &Error) -> i32 {
     match *e.kind() {
         ErrorKind::Dnsmasq => 3,
-        ErrorKind::RecvAccessPointSSIDs => 4,
-        ErrorKind::SendAccessPointSSIDs => 5,
         ErrorKind::SerializeAccessPointSSIDs => 6,
         ErrorKind::RecvNetworkCommand => 7,
         ErrorKind::SendNetworkCommandActivate => 8,
@@ -138,6 +393,61 @@ pub fn exit_code(e: &Error) -> i32 {
         ErrorKind::UnmanagedDevice(_) => 24,
         ErrorKind::RestartCommand => 25,
         ErrorKind::WiFiConnectionFailed => 26,
+        ErrorKind::UnsupportedByBackend(_, _) => 27,
+        ErrorKind::IwdProperty(_) => 28,
+        ErrorKind::IwdMode(_) => 29,
+        ErrorKind::InterfaceAddress(_) => 30,
+        ErrorKind::WpaCtrlConnect(_) => 31,
+        ErrorKind::WpaCtrlRequest(_) => 32,
+        ErrorKind::WpaCtrlReply(_, _) => 33,
+        ErrorKind::Hostapd => 34,
+        ErrorKind::InvalidStateTransition(_, _) => 35,
+        ErrorKind::UnexpectedState(_) => 36,
+        ErrorKind::SerializeState => 37,
+        ErrorKind::WrongPassphrase(_) => 38,
+        ErrorKind::AccessPointNotFound(_) => 39,
+        ErrorKind::DhcpTimeout(_) => 40,
+        ErrorKind::NetworkManagerProperty(_) => 41,
+        ErrorKind::RecvNetworkCommandReply => 42,
+        ErrorKind::ReadConfigFile(_) => 43,
+        ErrorKind::ParseConfigFile(_, _) => 44,
+        ErrorKind::UnknownConfigKey(_, _) => 45,
+        ErrorKind::InvalidConfigValue(_, _, _) => 46,
+        ErrorKind::MissingConfigValue(_) => 47,
+        ErrorKind::InvalidGateway(_) => 48,
+        ErrorKind::InvalidDhcpRange(_) => 49,
+        ErrorKind::InvalidNetmask(_) => 50,
+        ErrorKind::InvalidLeaseTime(_) => 51,
+        ErrorKind::DhcpRangeOutsideSubnet(_, _, _) => 52,
+        ErrorKind::DhcpRangeNotOrdered(_, _) => 53,
+        ErrorKind::DhcpRangeContainsGateway(_) => 54,
+        ErrorKind::InvalidSsidTemplate(_) => 55,
+        ErrorKind::SsidPlaceholder(_) => 56,
+        ErrorKind::InvalidSsid(_) => 57,
+        ErrorKind::EmptyFile(_) => 58,
+        ErrorKind::PassphraseConflict => 59,
+        ErrorKind::GeneratePassphrase => 60,
+        ErrorKind::WritePassphraseFile(_) => 61,
+        ErrorKind::QrCode => 62,
+        ErrorKind::QrCodeFormat(_) => 63,
+        ErrorKind::WriteQrCode(_) => 64,
+        ErrorKind::ConnectionPriority(_) => 65,
+        ErrorKind::ReadConnectionPriorities => 66,
+        ErrorKind::SavedNetworkNotFound(_) => 67,
+        ErrorKind::SavedNetworksCommand => 68,
+        ErrorKind::SerializeSavedNetworks => 69,
+        ErrorKind::PortalScan => 70,
+        ErrorKind::ScanCommand => 71,
+        ErrorKind::PortalPassphraseRequired(_) => 72,
+        ErrorKind::PortalPassphraseNotUsed(_) => 73,
+        ErrorKind::StoreCertificate(_) => 74,
+        ErrorKind::ConnectionNotVerified(_, _) => 75,
+        ErrorKind::VerifyOptionNotUsed(_) => 76,
+        ErrorKind::InvalidProvisioningValue(_) => 77,
+        ErrorKind::RuntimeDirectory(_) => 78,
+        ErrorKind::TooManyEventStreams => 79,
+        ErrorKind::UnsupportedNetmask(_) => 80,
+        ErrorKind::GatewayNotHost(_) => 81,
         _ => 1,
     }
 }
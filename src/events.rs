@@ -0,0 +1,57 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use exit::ExitEvent;
+use network::Network;
+use state::{ConnectionAttempt, PortalState};
+
+/// Events pushed to `GET /events` subscribers
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum PortalEvent {
+    Networks {
+        networks: Vec<Network>,
+    },
+    State {
+        state: PortalState,
+        previous: PortalState,
+    },
+    Connection(ConnectionAttempt),
+    Exit {
+        reason: ExitEvent,
+    },
+}
+
+impl PortalEvent {
+    /// Server-Sent Events `event` field
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PortalEvent::Networks { .. } => "networks",
+            PortalEvent::State { .. } => "state",
+            PortalEvent::Connection(_) => "connection",
+            PortalEvent::Exit { .. } => "exit",
+        }
+    }
+}
+
+/// Fans out events from the network thread to every subscriber
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<PortalEvent>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<PortalEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: PortalEvent) {
+        // Subscribers that went away are dropped on the first failed send
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
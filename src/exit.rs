@@ -3,14 +3,37 @@ use nix::sys::signal::{SigSet, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
 
 use errors::*;
 
+/// Exit code after connecting to a network that has a captive portal of its
+/// own, kept apart from the error codes
+pub const LOGIN_REQUIRED_EXIT_CODE: i32 = 100;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ExitEvent {
     ExitSignal,
     Timeout,
+    #[serde(rename = "wifi-connected")]
     WiFiConnected,
+    /// Connected, but the network has a captive portal of its own
+    LoginRequired,
     InternetConnected,
     UnexpectedExit,
 }
 
+impl ExitEvent {
+    /// Events that move a daemon back to monitoring the connection instead
+    /// of terminating the process
+    pub fn is_daemon_transition(&self) -> bool {
+        match *self {
+            ExitEvent::Timeout
+            | ExitEvent::WiFiConnected
+            | ExitEvent::LoginRequired
+            | ExitEvent::InternetConnected => true,
+            ExitEvent::ExitSignal | ExitEvent::UnexpectedExit => false,
+        }
+    }
+}
+
 pub type ExitResult = Result<ExitEvent>;
 
 pub fn exit(exit_tx: &Sender<ExitResult>, error: Error) {
@@ -10,7 +10,10 @@ extern crate error_chain;
 extern crate serde_derive;
 
 extern crate clap;
+extern crate dbus;
 extern crate env_logger;
+extern crate hmac;
+extern crate image;
 extern crate iron;
 extern crate iron_cors;
 extern crate mount;
@@ -18,18 +21,29 @@ extern crate network_manager;
 extern crate nix;
 extern crate params;
 extern crate persistent;
+extern crate qrcode;
 extern crate router;
 extern crate serde_json;
+extern crate sha2;
 extern crate staticfile;
+extern crate toml;
 
+mod backend;
+mod certificates;
 mod config;
 mod dnsmasq;
 mod errors;
+mod events;
 mod exit;
 mod logger;
 mod network;
+mod passphrase;
 mod privileges;
+mod qr;
 mod server;
+mod ssid;
+mod state;
+mod verify;
 
 use std::io::Write;
 use std::path;
@@ -40,9 +54,11 @@ use config::get_config;
 use std::sync::mpsc::channel;
 use errors::*;
 use exit::block_exit_signals;
-use exit::ExitEvent;
+use exit::{ExitEvent, LOGIN_REQUIRED_EXIT_CODE};
 use network::{network_init, network_thread};
+use passphrase::generate_passphrase;
 use privileges::require_root;
+use qr::show_qr_code;
 
 fn main() {
     if let Err(ref e) = run() {
@@ -64,30 +80,37 @@ fn run() -> Result<()> {
 
     logger::init();
 
+    // Before requiring root, so that `--help` and `--print-config` work for
+    // any user
+    let mut config = get_config()?;
+
     require_root()?;
 
     // Channels to signal exit events across threads
     let (exit_tx, exit_rx) = channel();
 
     // Starts network manger & deletes current AP
-    network_init(&get_config())?;
+    network_init(&mut config)?;
+
+    if let Some(generator) = config.generate_passphrase {
+        config.passphrase = Some(generate_passphrase(
+            generator,
+            &config.serial_file,
+            &config.passphrase_file,
+        )?);
+    }
 
-    let config = get_config();
+    // Once the SSID is expanded and the passphrase known
+    show_qr_code(&config)?;
 
     let network_thread_handle = thread::spawn(move || {
         network_thread(&config, &exit_tx);
     });
 
     // Blocks unit a thread send an exit event
-    match exit_rx.recv() {
+    let event = match exit_rx.recv() {
         Ok(result) => match result {
-            Ok(event) => match event {
-                ExitEvent::ExitSignal => info!("Exiting: Signal"),
-                ExitEvent::InternetConnected => info!("Exiting: Internet connected"),
-                ExitEvent::WiFiConnected => info!("Exiting: WiFi connected"),
-                ExitEvent::Timeout => info!("Exiting: Timeout"),
-                ExitEvent::UnexpectedExit => info!("Exiting: Unexpectedly"),
-            },
+            Ok(event) => event,
             Err(e) => {
                 error!("Exiting: Error {}", e.to_string());
                 return Err(e.into());
@@ -97,10 +120,24 @@ fn run() -> Result<()> {
             error!("Exiting: Receive Error {}", e.to_string());
             return Err(e.to_string().into());
         }
+    };
+
+    match event {
+        ExitEvent::ExitSignal => info!("Exiting: Signal"),
+        ExitEvent::InternetConnected => info!("Exiting: Internet connected"),
+        ExitEvent::WiFiConnected => info!("Exiting: WiFi connected"),
+        ExitEvent::LoginRequired => info!("Exiting: WiFi connected, login required"),
+        ExitEvent::Timeout => info!("Exiting: Timeout"),
+        ExitEvent::UnexpectedExit => info!("Exiting: Unexpectedly"),
     }
 
     // Join the network thread to ensure it completes gracefully
     let _ = network_thread_handle.join();
 
+    // Connected, but not successfully enough for exit code 0
+    if let ExitEvent::LoginRequired = event {
+        process::exit(LOGIN_REQUIRED_EXIT_CODE);
+    }
+
     Ok(())
 }
@@ -1,56 +1,241 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
 use std::process;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use network_manager::{
-    AccessPoint, AccessPointCredentials, Connection, ConnectionState, Connectivity, Device,
-    DeviceState, DeviceType, NetworkManager, Security, ServiceState,
+use backend::{
+    create_backend, frequency_band, frequency_channel, AccessPoint, Connectivity,
+    EnterpriseSettings, IpSettings, PortalSecurity, SavedNetwork, SavedProfiles, Security,
+    WifiBackend,
 };
-
+use certificates::remove_certificates;
 use config::Config;
 use dnsmasq::{start_dnsmasq, stop_dnsmasq};
 use errors::*;
+use events::{EventBus, PortalEvent};
 use exit::{exit, trap_exit_signals, ExitEvent, ExitResult};
 use server::start_server;
+use state::{
+    AttemptStatus, FailureReason, LastAttempt, PortalState, PortalTimer, StateEvent, StateHandle,
+    StateMachine,
+};
+use verify::{wait_for_connection, ConnectivityMonitor, Verification};
+
+// Seconds
+const SCAN_CACHE_EXPIRY: u64 = 600;
 
-#[derive(Debug, PartialEq, Clone)]
+/// Commands sent by the server carry their own reply channel. Commands that
+/// cannot be handled in the current state are dropped, which disconnects
+/// the reply channel.
+#[derive(Debug, Clone)]
 pub enum NetworkCommand {
-    ActivatePortal,
+    ActivatePortal {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
     Timeout,
+    /// Sent every `scan_interval` seconds while the portal is up
+    BackgroundScan,
     Exit,
     WiFiConnect {
         ssid: String,
         identity: String,
         passphrase: String,
+        /// Security of a hidden network, which is not in the scan results
+        hidden: Option<Security>,
+        enterprise: EnterpriseSettings,
+        ip: IpSettings,
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    RestartApp {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    Scan {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    SavedNetworks {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    DeleteSavedNetwork {
+        ssid: String,
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    SetSavedNetworkPriority {
+        ssid: String,
+        priority: i32,
+        reply_tx: Sender<NetworkCommandResponse>,
     },
-    RestartApp,
     CheckConnectivity,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Network {
     ssid: String,
     security: String,
+    /// Signal quality of the strongest access point, 0-100
+    strength: u32,
+    /// `2.4GHz`, `5GHz` or `6GHz`, `None` if the backend does not report the
+    /// frequency
+    band: Option<String>,
+    channel: Option<u32>,
+    /// Number of access points seen for the SSID
+    bssids: usize,
+    /// Seconds since the network was last seen by a scan
+    age: u64,
+    /// Whether a client profile of the SSID is saved on the device
+    saved: bool,
+}
+
+/// Strongest access point of an SSID, standing in for all access points of
+/// the network
+#[derive(Debug, Clone)]
+struct ScannedNetwork {
+    access_point: AccessPoint,
+    bssids: usize,
+    last_seen: SystemTime,
 }
 
+#[derive(Debug)]
 pub enum NetworkCommandResponse {
     Networks(Vec<Network>),
+    /// Networks of a scan with the hotspot up, or of the last scan since
+    /// `stale_since` if the device cannot scan in AP mode
+    Scanned {
+        networks: Vec<Network>,
+        stale_since: Option<SystemTime>,
+    },
+    /// The command was picked up, its outcome is reported separately, e.g.
+    /// by a `Done` or `Failed` reply
+    Accepted,
+    SavedNetworks(Vec<SavedNetwork>),
+    Done,
+    Failed(Error),
+}
+
+impl NetworkCommandResponse {
+    fn from_result(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => NetworkCommandResponse::Done,
+            Err(e) => NetworkCommandResponse::Failed(e),
+        }
+    }
+}
+
+/// Processes and threads running alongside the access point. Kept apart from
+/// the WiFi backend, so that the network thread can be driven without them.
+pub trait PortalServices {
+    /// Starts the DHCP and DNS server of the portal, unless it is running
+    fn start_dnsmasq(&mut self, config: &Config, interface: &str) -> Result<()>;
+
+    fn stop_dnsmasq(&mut self) -> Result<()>;
+
+    /// Starts the HTTP server and the thread trapping exit signals
+    fn spawn_threads(&mut self, config: &Config, handles: PortalHandles);
+}
+
+/// Shared state of the network thread handed to the threads of the portal
+#[derive(Clone)]
+pub struct PortalHandles {
+    pub exit_tx: Sender<ExitResult>,
+    pub network_tx: Sender<NetworkCommand>,
+    pub state: StateHandle,
+    pub last_attempt: LastAttempt,
+    pub events: EventBus,
+    pub timer: PortalTimer,
+}
+
+/// dnsmasq child process, iron server and signal handling
+#[derive(Default)]
+pub struct SystemServices {
+    dnsmasq: Option<process::Child>,
+}
+
+impl PortalServices for SystemServices {
+    fn start_dnsmasq(&mut self, config: &Config, interface: &str) -> Result<()> {
+        if self.dnsmasq.is_none() {
+            let mut dnsmasq = start_dnsmasq(config, interface)?;
+            check_dnsmasq_running(&mut dnsmasq)?;
+            self.dnsmasq = Some(dnsmasq);
+        }
+
+        Ok(())
+    }
+
+    fn stop_dnsmasq(&mut self) -> Result<()> {
+        if let Some(mut dnsmasq) = self.dnsmasq.take() {
+            stop_dnsmasq(&mut dnsmasq)?;
+        }
+
+        Ok(())
+    }
+
+    fn spawn_threads(&mut self, config: &Config, handles: PortalHandles) {
+        spawn_trap_exit_signals(&handles.exit_tx, handles.network_tx.clone());
+        spawn_server(config, handles);
+    }
+}
+
+fn spawn_server(config: &Config, handles: PortalHandles) {
+    let gateway = config.gateway;
+    let listening_port = config.listening_port;
+    let ui_directory = config.ui_directory.clone();
+    let certificate_directory = config.certificate_directory.clone();
+    let user_portal_url = config.user_portal_url.as_ref().map(ToString::to_string);
+
+    thread::spawn(move || {
+        start_server(
+            gateway,
+            listening_port,
+            handles.network_tx,
+            handles.exit_tx,
+            handles.state,
+            handles.last_attempt,
+            handles.events,
+            handles.timer,
+            &ui_directory,
+            certificate_directory,
+            user_portal_url,
+        );
+    });
+}
+
+fn spawn_trap_exit_signals(exit_tx: &Sender<ExitResult>, network_tx: Sender<NetworkCommand>) {
+    let exit_tx_trap = exit_tx.clone();
+
+    thread::spawn(move || {
+        if let Err(e) = trap_exit_signals() {
+            exit(&exit_tx_trap, e);
+            return;
+        }
+
+        if let Err(err) = network_tx.send(NetworkCommand::Exit) {
+            error!("Sending NetworkCommand::Exit failed: {}", err.to_string());
+        }
+    });
 }
 
 struct NetworkCommandHandler {
-    manager: NetworkManager,
-    device: Device,
-    access_points: Vec<AccessPoint>,
-    portal_connection: Option<Connection>,
+    backend: Box<dyn WifiBackend>,
+    services: Box<dyn PortalServices>,
+    networks: Vec<ScannedNetwork>,
+    scanned_at: SystemTime,
+    portal_up: bool,
+    timer: PortalTimer,
     config: Config,
-    dnsmasq: process::Child,
-    server_tx: Sender<NetworkCommandResponse>,
+    network_tx: Sender<NetworkCommand>,
     network_rx: Receiver<NetworkCommand>,
     exit_tx: Sender<ExitResult>,
-    portal_active: bool,
+    state: StateMachine,
+    last_attempt: LastAttempt,
+    events: EventBus,
+    /// Connectivity checks while the portal is up
+    portal_check: ConnectivityMonitor,
+    /// Connectivity checks of daemons once connected
+    connection_check: ConnectivityMonitor,
+    /// SSIDs of the saved client profiles, read again only when the profiles
+    /// change, as listing them can take a while
+    saved_ssids: HashSet<String>,
 }
 
 fn check_dnsmasq_running(child: &mut process::Child) -> Result<()> {
@@ -76,65 +261,73 @@ fn check_dnsmasq_running(child: &mut process::Child) -> Result<()> {
 }
 
 impl NetworkCommandHandler {
-    pub fn new(config: &Config, exit_tx: &Sender<ExitResult>) -> Result<Self> {
+    pub fn new(
+        mut backend: Box<dyn WifiBackend>,
+        mut services: Box<dyn PortalServices>,
+        config: &Config,
+        exit_tx: &Sender<ExitResult>,
+    ) -> Result<Self> {
         // Thread channels
         let (network_tx, network_rx) = channel();
-        let (server_tx, server_rx) = channel();
         let exit_tx = exit_tx.clone();
 
-        let manager = NetworkManager::new();
-        let device = find_device(&manager, &config.interface)?;
-        let access_points = get_access_points(&device)?;
-        let portal_connection = Some(create_portal(&device, config)?);
-        let mut dnsmasq = start_dnsmasq(config, &device)?;
-        let portal_active = false;
+        let events = EventBus::default();
+        let mut state = StateMachine::new(events.clone());
+        state.transition(StateEvent::Started)?;
+        let last_attempt = LastAttempt::new(events.clone());
 
-        check_dnsmasq_running(&mut dnsmasq)?;
+        let networks = get_access_points(&mut *backend)?;
+        let saved_ssids = saved_ssids(&mut *backend);
+        events.publish(PortalEvent::Networks {
+            networks: get_networks(&networks, &saved_ssids),
+        });
+        create_portal(&mut *backend, config)?;
+        let portal_up = true;
+        let timer = PortalTimer::new(config.activity_timeout);
+        services.start_dnsmasq(config, backend.interface())?;
+
+        state.transition(StateEvent::PortalStarted)?;
 
         // Spawn other threads
-        Self::spawn_trap_exit_signals(&exit_tx, network_tx.clone());
-        Self::spawn_server(config, &exit_tx, server_rx, network_tx.clone());
-        Self::spawn_activity_timeout(config, network_tx);
+        services.spawn_threads(
+            config,
+            PortalHandles {
+                exit_tx: exit_tx.clone(),
+                network_tx: network_tx.clone(),
+                state: state.handle(),
+                last_attempt: last_attempt.clone(),
+                events: events.clone(),
+                timer: timer.clone(),
+            },
+        );
+        Self::spawn_activity_timeout(config, network_tx.clone());
+        Self::spawn_background_scan(config, network_tx.clone());
+
+        let portal_check = ConnectivityMonitor::new(config.verify.portal_policy());
+        let connection_check = ConnectivityMonitor::new(config.verify.clone());
 
         let config = config.clone();
 
         Ok(NetworkCommandHandler {
-            manager,
-            device,
-            access_points,
-            portal_connection,
+            backend,
+            services,
+            networks,
+            scanned_at: SystemTime::now(),
+            portal_up,
+            timer,
             config,
-            dnsmasq,
-            server_tx,
+            network_tx,
             network_rx,
             exit_tx,
-            portal_active,
+            state,
+            last_attempt,
+            events,
+            portal_check,
+            connection_check,
+            saved_ssids,
         })
     }
 
-    fn spawn_server(
-        config: &Config,
-        exit_tx: &Sender<ExitResult>,
-        server_rx: Receiver<NetworkCommandResponse>,
-        network_tx: Sender<NetworkCommand>,
-    ) {
-        let gateway = config.gateway;
-        let listening_port = config.listening_port;
-        let exit_tx_server = exit_tx.clone();
-        let ui_directory = config.ui_directory.clone();
-
-        thread::spawn(move || {
-            start_server(
-                gateway,
-                listening_port,
-                server_rx,
-                network_tx,
-                exit_tx_server,
-                &ui_directory,
-            );
-        });
-    }
-
     fn spawn_activity_timeout(config: &Config, network_tx: Sender<NetworkCommand>) {
         let activity_timeout = config.activity_timeout;
 
@@ -154,17 +347,19 @@ impl NetworkCommandHandler {
         });
     }
 
-    fn spawn_trap_exit_signals(exit_tx: &Sender<ExitResult>, network_tx: Sender<NetworkCommand>) {
-        let exit_tx_trap = exit_tx.clone();
+    fn spawn_background_scan(config: &Config, network_tx: Sender<NetworkCommand>) {
+        let scan_interval = config.scan_interval;
 
-        thread::spawn(move || {
-            if let Err(e) = trap_exit_signals() {
-                exit(&exit_tx_trap, e);
-                return;
-            }
+        if scan_interval == 0 {
+            return;
+        }
+
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(scan_interval));
 
-            if let Err(err) = network_tx.send(NetworkCommand::Exit) {
-                error!("Sending NetworkCommand::Exit failed: {}", err.to_string());
+            // The network thread has exited
+            if network_tx.send(NetworkCommand::BackgroundScan).is_err() {
+                return;
             }
         });
     }
@@ -181,6 +376,217 @@ impl NetworkCommandHandler {
         }
     }
 
+    pub fn state(&self) -> PortalState {
+        self.state.current()
+    }
+
+    fn handle_command(&mut self, command: NetworkCommand) -> Result<()> {
+        match command {
+            NetworkCommand::ActivatePortal { reply_tx } => self.activate_portal(&reply_tx),
+            NetworkCommand::Timeout => {
+                if self.is_portal_timed_out() {
+                    info!("Timeout reached");
+                    self.state.transition(StateEvent::TimedOut)?;
+                    self.finish(ExitEvent::Timeout)?;
+                }
+                Ok(())
+            }
+            NetworkCommand::BackgroundScan => {
+                if let Err(e) = self.scan_with_portal_up() {
+                    debug!("Background scan failed: {}", e);
+                }
+                Ok(())
+            }
+            NetworkCommand::Exit => {
+                info!("Signal for Exiting...");
+                self.stop(ExitEvent::ExitSignal)
+            }
+            NetworkCommand::WiFiConnect {
+                ssid,
+                identity,
+                passphrase,
+                hidden,
+                enterprise,
+                ip,
+                reply_tx,
+            } => {
+                if !reply(&reply_tx, NetworkCommandResponse::Accepted) {
+                    warn!("Dropping stale connection request to '{}'", ssid);
+                    remove_certificates(&enterprise);
+                    return Ok(());
+                }
+
+                self.last_attempt
+                    .update(&ssid, AttemptStatus::Pending, None);
+                self.state.transition(StateEvent::ConnectRequested)?;
+
+                let connected =
+                    self.connect_to_wifi(&ssid, &identity, &passphrase, hidden, &enterprise, &ip);
+                self.refresh_saved_ssids();
+
+                match connected {
+                    Ok(Verification::LoginRequired(login_url)) => {
+                        self.last_attempt.login_required(&ssid, login_url);
+                        self.state.transition(StateEvent::ConnectSucceeded)?;
+                        self.finish(ExitEvent::LoginRequired)
+                    }
+                    Ok(_) => {
+                        self.last_attempt
+                            .update(&ssid, AttemptStatus::Succeeded, None);
+                        self.state.transition(StateEvent::ConnectSucceeded)?;
+                        self.finish(ExitEvent::WiFiConnected)
+                    }
+                    Err(e) => {
+                        let reason = FailureReason::from_error(&e);
+                        match reason {
+                            FailureReason::Unknown => error!("Unknown error {}", e),
+                            _ => error!("{}", e.to_string()),
+                        }
+                        self.last_attempt
+                            .update(&ssid, AttemptStatus::Failed, Some(reason));
+                        remove_certificates(&enterprise);
+                        self.reload()?;
+                        self.state.transition(StateEvent::ConnectFailed)?;
+                        Ok(())
+                    }
+                }
+            }
+            NetworkCommand::RestartApp { reply_tx } => {
+                if !reply(&reply_tx, NetworkCommandResponse::Accepted) {
+                    warn!("Dropping stale restart request");
+                    return Ok(());
+                }
+
+                info!("Restarting...");
+                self.reload()?;
+                self.state.transition(StateEvent::RestartRequested)?;
+                Ok(())
+            }
+            NetworkCommand::Scan { reply_tx } => self.scan_in_portal(&reply_tx),
+            NetworkCommand::SavedNetworks { reply_tx } => {
+                let response = match self.backend.saved_networks() {
+                    Ok(networks) => {
+                        self.saved_ssids = networks
+                            .iter()
+                            .map(|network| network.ssid.clone())
+                            .collect();
+                        NetworkCommandResponse::SavedNetworks(networks)
+                    }
+                    Err(e) => NetworkCommandResponse::Failed(e),
+                };
+                reply(&reply_tx, response);
+                Ok(())
+            }
+            NetworkCommand::DeleteSavedNetwork { ssid, reply_tx } => {
+                if !reply(&reply_tx, NetworkCommandResponse::Accepted) {
+                    warn!("Dropping stale request deleting saved network '{}'", ssid);
+                    return Ok(());
+                }
+
+                let result = self.backend.delete_saved_network(&ssid);
+                self.refresh_saved_ssids();
+                reply(&reply_tx, NetworkCommandResponse::from_result(result));
+                Ok(())
+            }
+            NetworkCommand::SetSavedNetworkPriority {
+                ssid,
+                priority,
+                reply_tx,
+            } => {
+                if !reply(&reply_tx, NetworkCommandResponse::Accepted) {
+                    warn!(
+                        "Dropping stale priority request for saved network '{}'",
+                        ssid
+                    );
+                    return Ok(());
+                }
+
+                let result = self.backend.set_saved_network_priority(&ssid, priority);
+                reply(&reply_tx, NetworkCommandResponse::from_result(result));
+                Ok(())
+            }
+            NetworkCommand::CheckConnectivity => {
+                if let Ok(Some(true)) = self.portal_check.check(&*self.backend) {
+                    info!("Connection verified: {}", self.portal_check.policy());
+                    self.state.transition(StateEvent::InternetConnected)?;
+                    return self.finish(ExitEvent::InternetConnected);
+                }
+                thread::sleep(Duration::from_secs(2));
+                Ok(())
+            }
+        }
+    }
+
+    /// Scans for networks and brings the access point back up
+    fn start_portal(&mut self) -> Result<()> {
+        // Profiles may have changed while connected
+        self.refresh_saved_ssids();
+        self.scan()?;
+        create_portal(&mut *self.backend, &self.config)?;
+        self.portal_up = true;
+        self.start_dnsmasq()?;
+        self.state.transition(StateEvent::PortalStarted)?;
+        Ok(())
+    }
+
+    fn refresh_saved_ssids(&mut self) {
+        self.saved_ssids = saved_ssids(&mut *self.backend);
+    }
+
+    fn scan(&mut self) -> Result<()> {
+        let networks = get_access_points(&mut *self.backend)?;
+        self.update_networks(networks);
+        Ok(())
+    }
+
+    /// Scans with the hotspot up and replies with the networks found. If the
+    /// device cannot scan in AP mode, the networks of the last scan are
+    /// returned instead, so that users stay connected to the portal.
+    fn scan_in_portal(&mut self, reply_tx: &Sender<NetworkCommandResponse>) -> Result<()> {
+        self.state.transition(StateEvent::ClientActivated)?;
+
+        let stale_since = match self.scan_with_portal_up() {
+            Ok(()) => None,
+            Err(e) => {
+                warn!("{}", e);
+                Some(self.scanned_at)
+            }
+        };
+
+        let networks = get_networks(&self.networks, &self.saved_ssids);
+
+        if !reply(
+            reply_tx,
+            NetworkCommandResponse::Scanned {
+                networks,
+                stale_since,
+            },
+        ) {
+            warn!("Server stopped waiting for the scan results");
+        }
+
+        Ok(())
+    }
+
+    fn scan_with_portal_up(&mut self) -> Result<()> {
+        let mut access_points = self.backend.scan_in_portal()?;
+
+        access_points.retain(|ap| !ap.ssid.is_empty());
+        self.update_networks(group_access_points(access_points));
+
+        Ok(())
+    }
+
+    /// Merges the networks of a scan into the cache
+    fn update_networks(&mut self, networks: Vec<ScannedNetwork>) {
+        merge_networks(&mut self.networks, networks);
+        self.scanned_at = SystemTime::now();
+
+        self.events.publish(PortalEvent::Networks {
+            networks: get_networks(&self.networks, &self.saved_ssids),
+        });
+    }
+
     pub fn reload(&mut self) -> Result<()> {
         // Only stop the portal to allow scanning of APs, keeping dnsmasq alive
         self.stop_portal()?;
@@ -189,7 +595,10 @@ impl NetworkCommandHandler {
 
     pub fn stop(&mut self, event: ExitEvent) -> Result<()> {
         self.stop_portal()?;
-        stop_dnsmasq(&mut self.dnsmasq)?;
+        self.stop_dnsmasq()?;
+        self.state.transition(StateEvent::Stopped)?;
+
+        self.events.publish(PortalEvent::Exit { reason: event });
 
         // Notify main thread of exit event
         let _ = self.exit_tx.send(Ok(event));
@@ -197,14 +606,114 @@ impl NetworkCommandHandler {
         Ok(())
     }
 
-    pub fn activate_portal(&mut self) -> Result<()> {
-        self.portal_active = true;
+    /// Ends the portal session with `event` once `Connected`. Daemons stay in
+    /// that state to monitor the connection, otherwise the thread exits.
+    fn finish(&mut self, event: ExitEvent) -> Result<()> {
+        if !self.config.daemon || !event.is_daemon_transition() {
+            return self.stop(event);
+        }
+
+        info!("{:?} - monitoring connectivity", event);
+
+        self.stop_portal()?;
+        self.stop_dnsmasq()
+    }
+
+    /// Blocks until connectivity has been lost for the daemon grace period
+    /// and the portal should be restarted, or the application is signalled
+    /// to exit
+    fn monitor_connectivity(&mut self) -> Result<()> {
+        let grace_period = Duration::from_secs(self.config.daemon_grace_period);
+        let mut lost_since: Option<Instant> = None;
+
+        loop {
+            match self.receive_network_command()? {
+                NetworkCommand::Exit => {
+                    info!("Signal for Exiting...");
+                    return self.stop(ExitEvent::ExitSignal);
+                }
+                NetworkCommand::CheckConnectivity => {
+                    match self.check_monitored_connection() {
+                        Ok(Some(true)) => {
+                            if lost_since.take().is_some() {
+                                info!("Connectivity restored");
+                            }
+                        }
+                        // No probe finished since the last check
+                        Ok(None) => {}
+                        verified => {
+                            let policy = &self.config.verify;
+                            let since = *lost_since.get_or_insert_with(|| {
+                                match verified {
+                                    Err(e) => warn!("Connectivity lost: {}", e),
+                                    _ => warn!("Connectivity lost: {} failed", policy),
+                                }
+                                Instant::now()
+                            });
+
+                            if since.elapsed() >= grace_period {
+                                info!(
+                                    "Connectivity lost for {}s - restarting portal",
+                                    grace_period.as_secs()
+                                );
+                                break;
+                            }
+                        }
+                    }
+
+                    thread::sleep(Duration::from_secs(2));
+                }
+                // Dropping server commands lets the server report that the
+                // portal is unavailable
+                _ => debug!("Ignoring network command while monitoring connectivity"),
+            }
+        }
+
+        if let Err(e) = self.backend.disconnect() {
+            warn!("Disconnecting WiFi failed: {}", e);
+        }
+
+        // The portal comes back up with a fresh activity timeout
+        self.timer.restart();
+        Self::spawn_activity_timeout(&self.config, self.network_tx.clone());
+
+        self.state.transition(StateEvent::ConnectivityLost)?;
+
+        Ok(())
+    }
+
+    /// A network with a captive portal of its own stays connected until the
+    /// user logs in, even though it fails the verification policy
+    fn check_monitored_connection(&mut self) -> Result<Option<bool>> {
+        match self.connection_check.check(&*self.backend)? {
+            Some(false) => Ok(Some(self.backend.connectivity()? == Connectivity::Portal)),
+            verified => Ok(verified),
+        }
+    }
+
+    fn start_dnsmasq(&mut self) -> Result<()> {
+        self.services
+            .start_dnsmasq(&self.config, self.backend.interface())
+    }
+
+    fn stop_dnsmasq(&mut self) -> Result<()> {
+        self.services.stop_dnsmasq()
+    }
 
-        let networks = get_networks(&self.access_points);
+    fn is_portal_timed_out(&self) -> bool {
+        self.state() == PortalState::PortalUp && self.timer.is_expired()
+    }
+
+    pub fn activate_portal(&mut self, reply_tx: &Sender<NetworkCommandResponse>) -> Result<()> {
+        self.state.transition(StateEvent::ClientActivated)?;
+
+        let networks = get_networks(&self.networks, &self.saved_ssids);
 
-        self.server_tx
-            .send(NetworkCommandResponse::Networks(networks))
-            .chain_err(|| ErrorKind::SendAccessPointSSIDs)
+        if !reply(reply_tx, NetworkCommandResponse::Networks(networks)) {
+            warn!("Server stopped waiting for the networks list");
+        }
+
+        Ok(())
     }
 
     fn stop_portal(&mut self) -> Result<()> {
@@ -214,79 +723,152 @@ impl NetworkCommandHandler {
 
     fn stop_portal_impl(&mut self) -> Result<()> {
         info!("Stopping access point '{}'...", self.config.ssid);
-        if let Some(conn) = &self.portal_connection {
-            conn.deactivate()?;
-            conn.delete()?;
-            thread::sleep(Duration::from_secs(1));
+        if self.portal_up {
+            self.backend.stop_portal()?;
             info!("Access point '{}' stopped", self.config.ssid);
         } else {
             warn!("No connection to deactivate or delete.");
         }
 
-        self.portal_active = false;
-        self.portal_connection = None;
+        self.portal_up = false;
 
         Ok(())
     }
 
-    fn connect_to_wifi(&mut self, ssid: &str, identity: &str, passphrase: &str) -> Result<()> {
-        delete_existing_wifi_connections(&self.manager);
-
+    /// Connects to `ssid` from the scan results, or as hidden network of the
+    /// given security. A network with a captive portal of its own is kept, as
+    /// the user has to log in once connected.
+    fn connect_to_wifi(
+        &mut self,
+        ssid: &str,
+        identity: &str,
+        passphrase: &str,
+        hidden: Option<Security>,
+        enterprise: &EnterpriseSettings,
+        ip: &IpSettings,
+    ) -> Result<Verification> {
         self.stop_portal()?;
 
-        self.access_points = get_access_points(&self.device)?;
+        self.scan()?;
 
-        if let Some(access_point) = find_access_point(&self.access_points, ssid) {
-            let wifi_device = self.device.as_wifi_device().unwrap();
+        match hidden {
+            Some(security) => {
+                info!("Connecting to hidden network '{}'...", ssid);
 
-            info!("Connecting to access point '{}'...", ssid);
+                self.last_attempt
+                    .update(ssid, AttemptStatus::Connecting, None);
 
-            let credentials = init_access_point_credentials(access_point, identity, passphrase);
+                self.backend
+                    .connect_hidden(ssid, security, identity, passphrase, enterprise, ip)?;
+            }
+            None => {
+                let access_point = match find_access_point(&self.networks, ssid) {
+                    Some(access_point) => access_point.clone(),
+                    None => bail!(ErrorKind::AccessPointNotFound(ssid.to_string())),
+                };
 
-            match wifi_device.connect(access_point, &credentials) {
-                Ok((connection, state)) => {
-                    if state == ConnectionState::Activated || state == ConnectionState::Activating {
-                        match wait_for_wifi_connection(&self.manager, 30) {
-                            Ok(has_connectivity) => {
-                                if has_connectivity {
-                                    info!("Internet connectivity established");
-                                } else {
-                                    warn!("Cannot establish Internet connectivity");
-                                }
-                            }
-                            Err(err) => error!("Getting Internet connectivity failed: {}", err),
-                        }
+                info!("Connecting to access point '{}'...", ssid);
 
-                        return Ok(());
-                    } else {
-                        error!("Wrong connection state: {:?}", state);
-                    }
+                self.last_attempt
+                    .update(ssid, AttemptStatus::Connecting, None);
 
-                    // connection not activated - delete
-                    if let Err(err) = connection.delete() {
-                        error!("Deleting connection object failed: {}", err)
-                    }
+                self.backend
+                    .connect(&access_point, identity, passphrase, enterprise, ip)?;
+            }
+        }
+
+        let policy = &self.config.verify;
+        let login_check_url = self.config.login_check_url.as_ref();
+
+        let verification = match wait_for_connection(
+            &*self.backend,
+            policy,
+            login_check_url,
+            self.config.verify_timeout,
+        ) {
+            Ok(Verification::Verified) => {
+                info!("Connection verified: {}", policy);
+                Verification::Verified
+            }
+            Ok(Verification::LoginRequired(login_url)) => {
+                match login_url {
+                    Some(ref login_url) => warn!("Login required: {}", login_url),
+                    None => warn!("Login required by a captive portal of '{}'", ssid),
                 }
-                Err(e) => {
-                    warn!("Error connecting to access point '{}': {}", ssid, e);
+                Verification::LoginRequired(login_url)
+            }
+            Ok(Verification::Failed) => {
+                warn!("Cannot verify the connection: {}", policy);
+
+                // Associated, but never got an address or through to the
+                // probe
+                if let Err(e) = self.backend.forget_connection() {
+                    error!("Deleting WiFi connection failed: {}", e);
+                }
+
+                if policy.is_addressing() {
+                    bail!(ErrorKind::DhcpTimeout(ssid.to_string()));
                 }
+
+                bail!(ErrorKind::ConnectionNotVerified(
+                    ssid.to_string(),
+                    policy.to_string()
+                ));
             }
-        }
+            Err(err) => {
+                error!("Getting Internet connectivity failed: {}", err);
+
+                // Nothing is known about the connection, so the saved
+                // profiles are kept
+                if let Err(e) = self.backend.forget_connection() {
+                    error!("Deleting WiFi connection failed: {}", e);
+                }
+
+                return Err(err);
+            }
+        };
+
+        self.update_saved_profiles();
+
+        Ok(verification)
+    }
 
-        // Connection activation failed
-        return Err(ErrorKind::WiFiConnectionFailed.into());
+    /// Only once the new connection works, so that the previous profiles are
+    /// kept if it fails
+    fn update_saved_profiles(&mut self) {
+        match self.config.saved_profiles {
+            SavedProfiles::Replace => {
+                if let Err(e) = self.backend.delete_wifi_connections() {
+                    error!("Deleting existing WiFi connections failed: {}", e);
+                }
+            }
+            SavedProfiles::Keep => {
+                if let Err(e) = self.backend.prioritize_connection() {
+                    error!("Prioritizing WiFi connection failed: {}", e);
+                }
+            }
+        }
     }
 }
 
 impl Drop for NetworkCommandHandler {
     fn drop(&mut self) {
-        let _ = self.stop(ExitEvent::UnexpectedExit);
+        if self.state() != PortalState::Exiting {
+            let _ = self.stop(ExitEvent::UnexpectedExit);
+        }
     }
 }
 
-pub fn network_init(config: &Config) -> Result<()> {
-    start_network_manager_service()?;
-    delete_existing_wifi_connect_ap_profile(&config.ssid).chain_err(|| ErrorKind::DeleteAccessPoint)?;
+pub fn network_init(config: &mut Config) -> Result<()> {
+    let mut backend = create_backend(config)?;
+
+    config.ssid = config
+        .ssid_template
+        .expand(backend.interface(), &config.serial_file)?;
+
+    backend
+        .delete_portal_profiles(&config.ssid_template)
+        .chain_err(|| ErrorKind::DeleteAccessPoint)?;
 
     Ok(())
 }
@@ -300,7 +882,10 @@ pub fn network_thread(config: &Config, exit_tx: &Sender<ExitResult>) {
 }
 
 pub fn network_thread_impl(config: &Config, exit_tx: &Sender<ExitResult>) -> Result<()> {
-    let mut command_handler = match NetworkCommandHandler::new(config, exit_tx) {
+    let backend = create_backend(config)?;
+    let services = Box::new(SystemServices::default());
+
+    let mut command_handler = match NetworkCommandHandler::new(backend, services, config, exit_tx) {
         Ok(command_handler) => command_handler,
         Err(e) => {
             error!("Failed to create NetworkCommandHandler");
@@ -309,166 +894,49 @@ pub fn network_thread_impl(config: &Config, exit_tx: &Sender<ExitResult>) -> Res
     };
 
     loop {
-        if command_handler.portal_connection.is_none() {
-            command_handler.access_points = get_access_points(&command_handler.device)?;
-            command_handler.portal_connection = Some(create_portal(
-                &command_handler.device,
-                &command_handler.config,
-            )?);
-        }
-
-        loop {
-            // Check if command available
-            match command_handler.receive_network_command()? {
-                NetworkCommand::ActivatePortal => {
-                    command_handler.activate_portal()?;
-                }
-                NetworkCommand::Timeout => {
-                    if !command_handler.portal_active {
-                        info!("Timeout reached. Exiting...");
-                        command_handler.stop(ExitEvent::Timeout)?;
-                        return Ok(());
-                    }
-                }
-                NetworkCommand::Exit => {
-                    info!("Signal for Exiting...");
-                    command_handler.stop(ExitEvent::ExitSignal)?;
-                    return Ok(());
-                }
-                NetworkCommand::WiFiConnect {
-                    ssid,
-                    identity,
-                    passphrase,
-                } => match command_handler.connect_to_wifi(&ssid, &identity, &passphrase) {
-                    Ok(_) => {
-                        command_handler.stop(ExitEvent::WiFiConnected)?;
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        match e.kind() {
-                            ErrorKind::WiFiConnectionFailed => {
-                                error!("{}", e.to_string())
-                            }
-                            _ => error!("Unknown error {}", e),
-                        }
-                        command_handler.reload()?;
-                        break;
-                    }
-                },
-                NetworkCommand::RestartApp => {
-                    info!("Restarting...");
-                    command_handler.reload()?;
-                    break;
-                }
-                NetworkCommand::CheckConnectivity => {
-                    if let Ok(Connectivity::Full) = command_handler.manager.get_connectivity() {
-                        info!("Full internet connectivity");
-                        command_handler.stop(ExitEvent::InternetConnected)?;
-                        return Ok(());
-                    }
-                    thread::sleep(Duration::from_secs(2));
-                }
+        match command_handler.state() {
+            PortalState::Scanning => command_handler.start_portal()?,
+            PortalState::PortalUp | PortalState::ClientActive => {
+                let command = command_handler.receive_network_command()?;
+                command_handler.handle_command(command)?;
+            }
+            PortalState::Connected | PortalState::Idle => command_handler.monitor_connectivity()?,
+            PortalState::Exiting => return Ok(()),
+            // Left before handing back control to this loop
+            state @ PortalState::Starting | state @ PortalState::Connecting => {
+                bail!(ErrorKind::UnexpectedState(state.to_string()))
             }
         }
     }
 }
 
-// Private functions for NetworkManager handling
-fn init_access_point_credentials(
-    access_point: &AccessPoint,
-    identity: &str,
-    passphrase: &str,
-) -> AccessPointCredentials {
-    if access_point.security.contains(Security::ENTERPRISE) {
-        AccessPointCredentials::Enterprise {
-            identity: identity.to_string(),
-            passphrase: passphrase.to_string(),
-        }
-    } else if access_point.security.contains(Security::WPA2)
-        || access_point.security.contains(Security::WPA)
-    {
-        AccessPointCredentials::Wpa {
-            passphrase: passphrase.to_string(),
-        }
-    } else if access_point.security.contains(Security::WEP) {
-        AccessPointCredentials::Wep {
-            passphrase: passphrase.to_string(),
-        }
-    } else {
-        AccessPointCredentials::None
-    }
-}
-
-fn find_device(manager: &NetworkManager, interface: &Option<String>) -> Result<Device> {
-    if let Some(ref interface) = *interface {
-        let device = manager
-            .get_device_by_interface(interface)
-            .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
-
-        info!("Targeted WiFi device: {}", interface);
-
-        if *device.device_type() != DeviceType::WiFi {
-            bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
-        }
-
-        if device.get_state()? == DeviceState::Unmanaged {
-            bail!(ErrorKind::UnmanagedDevice(interface.clone()))
-        }
-
-        Ok(device)
-    } else {
-        let devices = manager.get_devices()?;
-
-        if let Some(device) = find_wifi_managed_device(devices)? {
-            info!("WiFi device: {}", device.interface());
-            Ok(device)
-        } else {
-            bail!(ErrorKind::NoWiFiDevice)
-        }
-    }
-}
-
-fn find_wifi_managed_device(devices: Vec<Device>) -> Result<Option<Device>> {
-    for device in devices {
-        if *device.device_type() == DeviceType::WiFi
-            && device.get_state()? != DeviceState::Unmanaged
-        {
-            return Ok(Some(device));
-        }
-    }
-
-    Ok(None)
+/// Replies to a server command. Returns `false` if the server has given up
+/// waiting and already reported the portal as unavailable, in which case
+/// the command should not be carried out.
+fn reply(reply_tx: &Sender<NetworkCommandResponse>, response: NetworkCommandResponse) -> bool {
+    reply_tx.send(response).is_ok()
 }
 
-fn get_access_points(device: &Device) -> Result<Vec<AccessPoint>> {
-    get_access_points_impl(device).chain_err(|| ErrorKind::NoAccessPoints)
+fn get_access_points(backend: &mut dyn WifiBackend) -> Result<Vec<ScannedNetwork>> {
+    get_access_points_impl(backend).chain_err(|| ErrorKind::NoAccessPoints)
 }
 
-fn get_access_points_impl(device: &Device) -> Result<Vec<AccessPoint>> {
+fn get_access_points_impl(backend: &mut dyn WifiBackend) -> Result<Vec<ScannedNetwork>> {
     let retries_allowed = 10;
     let mut retries = 0;
 
     // After stopping the hotspot we may have to wait a bit for the list
     // of access points to become available
     while retries < retries_allowed {
-        let wifi_device = device.as_wifi_device().unwrap();
-        let mut access_points = wifi_device.get_access_points()?;
-
-        access_points.retain(|ap| ap.ssid().as_str().is_ok());
-
-        // Purge access points with duplicate SSIDs
-        let mut inserted = HashSet::new();
-        access_points.retain(|ap| inserted.insert(ap.ssid.clone()));
+        let mut access_points = backend.scan()?;
 
         // Remove access points without SSID (hidden)
-        access_points.retain(|ap| !ap.ssid().as_str().unwrap().is_empty());
+        access_points.retain(|ap| !ap.ssid.is_empty());
 
         if !access_points.is_empty() {
-            info!(
-                "Access points: {:?}",
-                get_access_points_ssids(&access_points)
-            );
-            return Ok(access_points);
+            let networks = group_access_points(access_points);
+            info!("Access points: {:?}", get_access_points_ssids(&networks));
+            return Ok(networks);
         }
 
         retries += 1;
@@ -480,179 +948,544 @@ fn get_access_points_impl(device: &Device) -> Result<Vec<AccessPoint>> {
     Ok(vec![])
 }
 
-fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
-    access_points
-        .iter()
-        .map(|ap| ap.ssid().as_str().unwrap())
-        .collect()
+/// Purges access points with duplicate SSIDs, keeping the strongest one of
+/// each network. The networks are ordered by strength.
+fn group_access_points(mut access_points: Vec<AccessPoint>) -> Vec<ScannedNetwork> {
+    access_points.sort_by(|a, b| b.strength.cmp(&a.strength));
+
+    let last_seen = SystemTime::now();
+
+    let mut networks: Vec<ScannedNetwork> = Vec::new();
+    let mut indices: HashMap<String, usize> = HashMap::new();
+
+    for access_point in access_points {
+        if let Some(&index) = indices.get(&access_point.ssid) {
+            networks[index].bssids += 1;
+            continue;
+        }
+
+        indices.insert(access_point.ssid.clone(), networks.len());
+        networks.push(ScannedNetwork {
+            access_point,
+            bssids: 1,
+            last_seen,
+        });
+    }
+
+    networks
 }
 
-fn get_networks(access_points: &[AccessPoint]) -> Vec<Network> {
-    access_points.iter().map(get_network_info).collect()
+/// Replaces cached networks with those of a newer scan. Networks missing
+/// from the scan are kept until they have not been seen for
+/// `SCAN_CACHE_EXPIRY`, so that they do not vanish from the list because a
+/// single scan missed them. Networks of the latest scan come first.
+fn merge_networks(cache: &mut Vec<ScannedNetwork>, networks: Vec<ScannedNetwork>) {
+    cache.retain(|cached| {
+        age(cached) < SCAN_CACHE_EXPIRY
+            && !networks
+                .iter()
+                .any(|network| network.access_point.ssid == cached.access_point.ssid)
+    });
+
+    cache.extend(networks);
+
+    cache.sort_by(|a, b| {
+        b.last_seen
+            .cmp(&a.last_seen)
+            .then(b.access_point.strength.cmp(&a.access_point.strength))
+    });
 }
 
-fn get_network_info(access_point: &AccessPoint) -> Network {
-    Network {
-        ssid: access_point.ssid().as_str().unwrap().to_string(),
-        security: get_network_security(access_point).to_string(),
-    }
+/// Seconds since `network` was last seen
+fn age(network: &ScannedNetwork) -> u64 {
+    network
+        .last_seen
+        .elapsed()
+        .map(|age| age.as_secs())
+        .unwrap_or(0)
 }
 
-fn get_network_security(access_point: &AccessPoint) -> &str {
-    if access_point.security.contains(Security::ENTERPRISE) {
-        "enterprise"
-    } else if access_point.security.contains(Security::WPA2)
-        || access_point.security.contains(Security::WPA)
-    {
-        "wpa"
-    } else if access_point.security.contains(Security::WEP) {
-        "wep"
-    } else {
-        "none"
-    }
+fn get_access_points_ssids(networks: &[ScannedNetwork]) -> Vec<&str> {
+    networks
+        .iter()
+        .map(|network| network.access_point.ssid.as_str())
+        .collect()
 }
 
-fn find_access_point<'a>(access_points: &'a [AccessPoint], ssid: &str) -> Option<&'a AccessPoint> {
-    for access_point in access_points.iter() {
-        if let Ok(access_point_ssid) = access_point.ssid().as_str() {
-            if access_point_ssid == ssid {
-                return Some(access_point);
-            }
+/// SSIDs of the saved client profiles, which are marked in the networks list
+fn saved_ssids(backend: &mut dyn WifiBackend) -> HashSet<String> {
+    match backend.saved_networks() {
+        Ok(saved_networks) => saved_networks
+            .into_iter()
+            .map(|saved_network| saved_network.ssid)
+            .collect(),
+        Err(e) => {
+            warn!("Listing saved networks failed: {}", e);
+            HashSet::new()
         }
     }
+}
+
+fn get_networks(networks: &[ScannedNetwork], saved: &HashSet<String>) -> Vec<Network> {
+    networks
+        .iter()
+        .map(|network| get_network_info(network, saved))
+        .collect()
+}
+
+fn get_network_info(network: &ScannedNetwork, saved: &HashSet<String>) -> Network {
+    let access_point = &network.access_point;
 
-    None
+    Network {
+        ssid: access_point.ssid.clone(),
+        security: access_point.security.as_str().to_string(),
+        strength: access_point.strength,
+        band: access_point
+            .frequency
+            .and_then(frequency_band)
+            .map(|band| band.to_string()),
+        channel: access_point.frequency.and_then(frequency_channel),
+        bssids: network.bssids,
+        age: age(network),
+        saved: saved.contains(&access_point.ssid),
+    }
+}
+
+fn find_access_point<'a>(networks: &'a [ScannedNetwork], ssid: &str) -> Option<&'a AccessPoint> {
+    networks
+        .iter()
+        .map(|network| &network.access_point)
+        .find(|access_point| access_point.ssid == ssid)
 }
 
-fn create_portal(device: &Device, config: &Config) -> Result<Connection> {
+fn create_portal(backend: &mut dyn WifiBackend, config: &Config) -> Result<()> {
     let portal_passphrase = config.passphrase.as_ref().map(|p| p as &str);
 
-    create_portal_impl(device, &config.ssid, &config.gateway, &portal_passphrase)
-        .chain_err(|| ErrorKind::CreateCaptivePortal)
+    create_portal_impl(
+        backend,
+        &config.ssid,
+        config.portal_security,
+        &config.gateway,
+        &portal_passphrase,
+    )
+    .chain_err(|| ErrorKind::CreateCaptivePortal)
 }
 
 fn create_portal_impl(
-    device: &Device,
+    backend: &mut dyn WifiBackend,
     ssid: &str,
+    security: PortalSecurity,
     gateway: &Ipv4Addr,
     passphrase: &Option<&str>,
-) -> Result<Connection> {
+) -> Result<()> {
     info!("Starting access point...");
-    let wifi_device = device.as_wifi_device().unwrap();
-    let (portal_connection, _) = wifi_device.create_hotspot(ssid, *passphrase, Some(*gateway))?;
+    backend.create_portal(ssid, security, *passphrase, *gateway)?;
     info!("Access point '{}' created", ssid);
-    Ok(portal_connection)
+    Ok(())
 }
 
-fn wait_for_wifi_connection(manager: &NetworkManager, timeout: u64) -> Result<bool> {
-    let mut total_time = 0;
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
 
-    loop {
-        let connectivity = manager.get_connectivity()?;
+    use super::*;
+    use backend::fake::FakeBackend;
+    use backend::Connectivity;
 
-        if connectivity == Connectivity::Full || connectivity == Connectivity::Limited {
-            debug!(
-                "Connectivity established: {:?} / {}s elapsed",
-                connectivity, total_time
-            );
+    struct NoServices;
 
-            return Ok(true);
-        } else if total_time >= timeout {
-            debug!(
-                "Timeout reached in waiting for connectivity: {:?} / {}s elapsed",
-                connectivity, total_time
-            );
+    impl PortalServices for NoServices {
+        fn start_dnsmasq(&mut self, _config: &Config, _interface: &str) -> Result<()> {
+            Ok(())
+        }
 
-            return Ok(false);
+        fn stop_dnsmasq(&mut self) -> Result<()> {
+            Ok(())
         }
 
-        thread::sleep(Duration::from_secs(1));
+        fn spawn_threads(&mut self, _config: &Config, _handles: PortalHandles) {}
+    }
+
+    fn office() -> FakeBackend {
+        FakeBackend::new("wlan0")
+            .with_network("Office", Security::Wpa, 80, "secret")
+            .with_network("Guest", Security::None, 40, "")
+    }
+
+    fn start(
+        backend: FakeBackend,
+        config: Config,
+    ) -> (NetworkCommandHandler, Receiver<ExitResult>) {
+        let (exit_tx, exit_rx) = channel();
+
+        let handler =
+            NetworkCommandHandler::new(Box::new(backend), Box::new(NoServices), &config, &exit_tx)
+                .unwrap();
+
+        (handler, exit_rx)
+    }
+
+    fn connect(
+        handler: &mut NetworkCommandHandler,
+        ssid: &str,
+        passphrase: &str,
+        hidden: Option<Security>,
+    ) -> Receiver<NetworkCommandResponse> {
+        let (reply_tx, reply_rx) = channel();
+
+        handler
+            .handle_command(NetworkCommand::WiFiConnect {
+                ssid: ssid.into(),
+                identity: String::new(),
+                passphrase: passphrase.into(),
+                hidden,
+                enterprise: EnterpriseSettings::default(),
+                ip: IpSettings::default(),
+                reply_tx,
+            })
+            .unwrap();
+
+        reply_rx
+    }
+
+    fn exit_event(exit_rx: &Receiver<ExitResult>) -> Option<ExitEvent> {
+        exit_rx.try_recv().ok().map(|result| result.unwrap())
+    }
+
+    fn saved(handler: &mut NetworkCommandHandler) -> Vec<String> {
+        handler
+            .backend
+            .saved_networks()
+            .unwrap()
+            .into_iter()
+            .map(|network| network.ssid)
+            .collect()
+    }
 
-        total_time += 1;
+    #[test]
+    fn starts_portal_with_scanned_networks() {
+        let (handler, _exit_rx) = start(office(), Config::for_tests());
 
-        debug!(
-            "Still waiting for connectivity: {:?} / {}s elapsed",
-            connectivity, total_time
+        assert_eq!(handler.state(), PortalState::PortalUp);
+        assert_eq!(
+            get_access_points_ssids(&handler.networks),
+            vec!["Office", "Guest"]
         );
     }
-}
 
-fn start_network_manager_service() -> Result<()> {
-    let state = match NetworkManager::get_service_state() {
-        Ok(state) => state,
-        _ => {
-            info!("Cannot get the NetworkManager service state");
-            return Ok(());
+    #[test]
+    fn scan_replies_with_networks() {
+        let (mut handler, _exit_rx) = start(office().with_portal_scan(), Config::for_tests());
+        let (reply_tx, reply_rx) = channel();
+
+        handler
+            .handle_command(NetworkCommand::Scan { reply_tx })
+            .unwrap();
+
+        match reply_rx.try_recv().unwrap() {
+            NetworkCommandResponse::Scanned {
+                networks,
+                stale_since,
+            } => {
+                assert_eq!(networks.len(), 2);
+                assert_eq!(networks[0].ssid, "Office");
+                assert!(stale_since.is_none());
+            }
+            response => panic!("Unexpected response {:?}", response),
         }
-    };
 
-    if state != ServiceState::Active {
-        let state =
-            NetworkManager::start_service(15).chain_err(|| ErrorKind::StartNetworkManager)?;
-        if state != ServiceState::Active {
-            bail!(ErrorKind::StartActiveNetworkManager);
-        } else {
-            info!("NetworkManager service started successfully");
+        assert_eq!(handler.state(), PortalState::ClientActive);
+    }
+
+    #[test]
+    fn scan_without_portal_scan_replies_with_cache() {
+        let (mut handler, _exit_rx) = start(office(), Config::for_tests());
+        let (reply_tx, reply_rx) = channel();
+
+        handler
+            .handle_command(NetworkCommand::Scan { reply_tx })
+            .unwrap();
+
+        match reply_rx.try_recv().unwrap() {
+            NetworkCommandResponse::Scanned {
+                networks,
+                stale_since,
+            } => {
+                assert_eq!(networks.len(), 2);
+                assert_eq!(stale_since, Some(handler.scanned_at));
+            }
+            response => panic!("Unexpected response {:?}", response),
         }
-    } else {
-        debug!("NetworkManager service already running");
     }
 
-    Ok(())
-}
+    #[test]
+    fn connect_success_exits() {
+        let (mut handler, exit_rx) = start(office(), Config::for_tests());
 
-fn delete_existing_wifi_connect_ap_profile(ssid: &str) -> Result<()> {
-    let manager = NetworkManager::new();
+        let reply_rx = connect(&mut handler, "Office", "secret", None);
 
-    for connection in &manager.get_connections()? {
-        if is_access_point_connection(connection) && is_same_ssid(connection, ssid) {
-            info!(
-                "Deleting already created by WiFi Connect access point connection profile: {:?}",
-                connection.settings().ssid,
-            );
-            connection.delete()?;
+        match reply_rx.try_recv().unwrap() {
+            NetworkCommandResponse::Accepted => {}
+            response => panic!("Unexpected response {:?}", response),
         }
+
+        let attempt = handler.last_attempt.current().unwrap();
+        assert_eq!(attempt.ssid, "Office");
+        assert_eq!(attempt.status, AttemptStatus::Succeeded);
+        assert_eq!(handler.state(), PortalState::Exiting);
+        assert!(matches!(
+            exit_event(&exit_rx),
+            Some(ExitEvent::WiFiConnected)
+        ));
+        assert_eq!(saved(&mut handler), vec!["Office"]);
     }
 
-    Ok(())
-}
+    #[test]
+    fn connect_hidden_network() {
+        let backend = office().with_hidden_network("Lab", "hidden-secret");
+        let (mut handler, exit_rx) = start(backend, Config::for_tests());
 
-fn delete_existing_wifi_connections(manager: &NetworkManager) {
-    let connections = match manager.get_connections() {
-        Ok(connections) => connections,
-        Err(e) => {
-            error!("Getting existing connections failed: {}", e);
-            return;
-        }
-    };
+        connect(&mut handler, "Lab", "hidden-secret", Some(Security::Wpa));
 
-    for connection in &connections {
-        if is_wifi_connection(connection) && !is_access_point_connection(connection) {
-            info!(
-                "Deleting existing WiFi connection: {:?}",
-                connection.settings().ssid,
-            );
+        assert_eq!(
+            handler.last_attempt.current().unwrap().status,
+            AttemptStatus::Succeeded
+        );
+        assert!(matches!(
+            exit_event(&exit_rx),
+            Some(ExitEvent::WiFiConnected)
+        ));
+    }
 
-            if let Err(e) = connection.delete() {
-                error!("Deleting existing WiFi connection failed: {}", e);
-            }
-        }
+    #[test]
+    fn connect_daemon_monitors_connection() {
+        let mut config = Config::for_tests();
+        config.daemon = true;
+        let (mut handler, exit_rx) = start(office(), config);
+
+        connect(&mut handler, "Office", "secret", None);
+
+        assert_eq!(handler.state(), PortalState::Connected);
+        assert!(exit_event(&exit_rx).is_none());
     }
-}
 
-fn is_same_ssid(connection: &Connection, ssid: &str) -> bool {
-    connection_ssid_as_str(connection) == Some(ssid)
-}
+    #[test]
+    fn connect_wrong_key_restarts_portal() {
+        let (mut handler, exit_rx) = start(office(), Config::for_tests());
 
-fn connection_ssid_as_str(connection: &Connection) -> Option<&str> {
-    // An access point SSID could be random bytes and not a UTF-8 encoded string
-    connection.settings().ssid.as_str().ok()
-}
+        connect(&mut handler, "Office", "wrong", None);
 
-fn is_access_point_connection(connection: &Connection) -> bool {
-    is_wifi_connection(connection) && connection.settings().mode == "ap"
-}
+        let attempt = handler.last_attempt.current().unwrap();
+        assert_eq!(attempt.status, AttemptStatus::Failed);
+        assert_eq!(attempt.reason, Some(FailureReason::WrongKey));
+        assert_eq!(handler.state(), PortalState::Scanning);
+        assert!(exit_event(&exit_rx).is_none());
+        assert!(saved(&mut handler).is_empty());
+
+        handler.start_portal().unwrap();
+
+        assert_eq!(handler.state(), PortalState::PortalUp);
+    }
+
+    #[test]
+    fn connect_unknown_network_fails() {
+        let (mut handler, _exit_rx) = start(office(), Config::for_tests());
+
+        connect(&mut handler, "Elsewhere", "secret", None);
+
+        let attempt = handler.last_attempt.current().unwrap();
+        assert_eq!(attempt.reason, Some(FailureReason::AccessPointNotFound));
+        assert_eq!(handler.state(), PortalState::Scanning);
+    }
+
+    #[test]
+    fn connect_without_address_forgets_network() {
+        let backend = office().with_connected_connectivity(Connectivity::None);
+        let mut config = Config::for_tests();
+        config.verify_timeout = 0;
+        let (mut handler, _exit_rx) = start(backend, config);
+
+        connect(&mut handler, "Office", "secret", None);
+
+        let attempt = handler.last_attempt.current().unwrap();
+        assert_eq!(attempt.reason, Some(FailureReason::DhcpTimeout));
+        assert_eq!(handler.state(), PortalState::Scanning);
+        assert!(saved(&mut handler).is_empty());
+    }
+
+    #[test]
+    fn connectivity_error_keeps_saved_networks() {
+        let backend = office()
+            .with_saved_network("Guest")
+            .with_connectivity_failure();
+        let (mut handler, _exit_rx) = start(backend, Config::for_tests());
+
+        connect(&mut handler, "Office", "secret", None);
+
+        let attempt = handler.last_attempt.current().unwrap();
+        assert_eq!(attempt.status, AttemptStatus::Failed);
+        assert_eq!(handler.state(), PortalState::Scanning);
+        assert_eq!(saved(&mut handler), vec!["Guest".to_string()]);
+    }
+
+    #[test]
+    fn stale_connect_is_dropped() {
+        let (mut handler, exit_rx) = start(office(), Config::for_tests());
+
+        // The server has given up waiting for the reply
+        let (reply_tx, reply_rx) = channel();
+        drop(reply_rx);
+
+        handler
+            .handle_command(NetworkCommand::WiFiConnect {
+                ssid: "Office".into(),
+                identity: String::new(),
+                passphrase: "secret".into(),
+                hidden: None,
+                enterprise: EnterpriseSettings::default(),
+                ip: IpSettings::default(),
+                reply_tx,
+            })
+            .unwrap();
+
+        assert_eq!(handler.state(), PortalState::PortalUp);
+        assert!(handler.last_attempt.current().is_none());
+        assert!(exit_event(&exit_rx).is_none());
+    }
+
+    #[test]
+    fn stale_saved_network_change_is_dropped() {
+        let mut config = Config::for_tests();
+        config.daemon = true;
+        let (mut handler, _exit_rx) = start(office(), config);
+
+        connect(&mut handler, "Office", "secret", None);
+
+        let (reply_tx, reply_rx) = channel();
+        drop(reply_rx);
+
+        handler
+            .handle_command(NetworkCommand::DeleteSavedNetwork {
+                ssid: "Office".into(),
+                reply_tx,
+            })
+            .unwrap();
+
+        assert_eq!(saved(&mut handler), vec!["Office".to_string()]);
+
+        let (reply_tx, reply_rx) = channel();
+
+        handler
+            .handle_command(NetworkCommand::DeleteSavedNetwork {
+                ssid: "Office".into(),
+                reply_tx,
+            })
+            .unwrap();
+
+        assert!(matches!(
+            reply_rx.try_recv(),
+            Ok(NetworkCommandResponse::Accepted)
+        ));
+        assert!(matches!(
+            reply_rx.try_recv(),
+            Ok(NetworkCommandResponse::Done)
+        ));
+        assert!(saved(&mut handler).is_empty());
+    }
+
+    #[test]
+    fn saved_ssids_follow_profile_changes() {
+        let backend = office().with_saved_network("Guest");
+        let (mut handler, _exit_rx) = start(backend, Config::for_tests());
+
+        assert!(handler.saved_ssids.contains("Guest"));
+
+        let (reply_tx, _reply_rx) = channel();
+        handler
+            .handle_command(NetworkCommand::DeleteSavedNetwork {
+                ssid: "Guest".into(),
+                reply_tx,
+            })
+            .unwrap();
+
+        assert!(handler.saved_ssids.is_empty());
+
+        connect(&mut handler, "Office", "secret", None);
+
+        assert!(handler.saved_ssids.contains("Office"));
+    }
 
-fn is_wifi_connection(connection: &Connection) -> bool {
-    connection.settings().kind == "802-11-wireless"
+    #[test]
+    fn timeout_exits() {
+        let (mut handler, exit_rx) = start(office(), Config::for_tests());
+
+        handler.handle_command(NetworkCommand::Timeout).unwrap();
+
+        assert_eq!(handler.state(), PortalState::Exiting);
+        assert!(matches!(exit_event(&exit_rx), Some(ExitEvent::Timeout)));
+    }
+
+    #[test]
+    fn timeout_daemon_monitors_connection() {
+        let mut config = Config::for_tests();
+        config.daemon = true;
+        let (mut handler, exit_rx) = start(office(), config);
+
+        handler.handle_command(NetworkCommand::Timeout).unwrap();
+
+        assert_eq!(handler.state(), PortalState::Idle);
+        assert!(exit_event(&exit_rx).is_none());
+    }
+
+    #[test]
+    fn timeout_ignored_while_client_active() {
+        let (mut handler, exit_rx) = start(office(), Config::for_tests());
+        let (reply_tx, _reply_rx) = channel();
+
+        handler
+            .handle_command(NetworkCommand::ActivatePortal { reply_tx })
+            .unwrap();
+        handler.handle_command(NetworkCommand::Timeout).unwrap();
+
+        assert_eq!(handler.state(), PortalState::ClientActive);
+        assert!(exit_event(&exit_rx).is_none());
+    }
+
+    #[test]
+    fn exit_signal_exits() {
+        let (mut handler, exit_rx) = start(office(), Config::for_tests());
+
+        handler.handle_command(NetworkCommand::Exit).unwrap();
+
+        assert_eq!(handler.state(), PortalState::Exiting);
+        assert!(matches!(exit_event(&exit_rx), Some(ExitEvent::ExitSignal)));
+    }
+
+    #[test]
+    fn limited_connectivity_keeps_portal() {
+        let backend = office().with_portal_connectivity(Connectivity::Limited);
+        let (mut handler, exit_rx) = start(backend, Config::for_tests());
+
+        handler
+            .handle_command(NetworkCommand::CheckConnectivity)
+            .unwrap();
+
+        assert_eq!(handler.state(), PortalState::PortalUp);
+        assert!(exit_event(&exit_rx).is_none());
+    }
+
+    #[test]
+    fn full_connectivity_ends_portal() {
+        let backend = office().with_portal_connectivity(Connectivity::Full);
+        let (mut handler, exit_rx) = start(backend, Config::for_tests());
+
+        handler
+            .handle_command(NetworkCommand::CheckConnectivity)
+            .unwrap();
+
+        assert!(matches!(
+            exit_event(&exit_rx),
+            Some(ExitEvent::InternetConnected)
+        ));
+    }
 }
@@ -0,0 +1,170 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use errors::*;
+use ssid::read_trimmed;
+
+// Lower case letters and digits without the easily confused `l`, `o`, `0` and
+// `1`, so that a passphrase read from a label is typed correctly
+const ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+// 16 characters of a 32 character alphabet are 80 bits
+const GROUPS: usize = 4;
+const GROUP_LENGTH: usize = 4;
+
+// WPA passphrases are 8 to 63 printable ASCII characters
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+const MAX_PASSPHRASE_LENGTH: usize = 63;
+
+// Distinguishes the passphrase from other values derived from the machine ID
+const DERIVATION_CONTEXT: &[u8] = b"wifi-connect portal passphrase";
+
+/// How the portal passphrase is generated instead of being configured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PassphraseGenerator {
+    /// HMAC-SHA256 of the serial number file, the same after a reinstall
+    Derived,
+    /// Random and persisted in the passphrase file
+    Random,
+}
+
+impl FromStr for PassphraseGenerator {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "derived" => Ok(PassphraseGenerator::Derived),
+            "random" => Ok(PassphraseGenerator::Random),
+            _ => Err(format!("Unknown passphrase generator '{}'", s)),
+        }
+    }
+}
+
+pub fn generate_passphrase(
+    generator: PassphraseGenerator,
+    serial_file: &Path,
+    passphrase_file: &Path,
+) -> Result<String> {
+    let passphrase = match generator {
+        PassphraseGenerator::Derived => derive_passphrase(serial_file),
+        PassphraseGenerator::Random => load_random_passphrase(passphrase_file),
+    }
+    .chain_err(|| ErrorKind::GeneratePassphrase)?;
+
+    info!("Portal passphrase generated: {:?}", generator);
+
+    Ok(passphrase)
+}
+
+fn derive_passphrase(serial_file: &Path) -> Result<String> {
+    let serial = read_trimmed(serial_file)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(serial.as_bytes())
+        .map_err(|_| Error::from(ErrorKind::GeneratePassphrase))?;
+    mac.update(DERIVATION_CONTEXT);
+
+    Ok(encode(&mac.finalize().into_bytes()))
+}
+
+/// Reads the passphrase persisted by a previous run, replacing it if the file
+/// is empty or was edited into something the access point cannot use
+fn load_random_passphrase(passphrase_file: &Path) -> Result<String> {
+    if passphrase_file.exists() {
+        match read_trimmed(passphrase_file) {
+            Ok(ref passphrase) if is_valid_passphrase(passphrase) => return Ok(passphrase.clone()),
+            Ok(_) => warn!("Invalid passphrase file: {}", passphrase_file.display()),
+            Err(Error(ErrorKind::EmptyFile(_), _)) => {
+                warn!("Empty passphrase file: {}", passphrase_file.display())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut random = [0; GROUPS * GROUP_LENGTH];
+    fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+
+    let passphrase = encode(&random);
+
+    write_passphrase_file(passphrase_file, &passphrase)
+        .chain_err(|| ErrorKind::WritePassphraseFile(passphrase_file.display().to_string()))?;
+
+    Ok(passphrase)
+}
+
+fn is_valid_passphrase(passphrase: &str) -> bool {
+    passphrase.len() >= MIN_PASSPHRASE_LENGTH
+        && passphrase.len() <= MAX_PASSPHRASE_LENGTH
+        && passphrase.bytes().all(|byte| byte >= b' ' && byte <= b'~')
+}
+
+fn write_passphrase_file(passphrase_file: &Path, passphrase: &str) -> Result<()> {
+    if let Some(directory) = passphrase_file.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(passphrase_file)?;
+
+    writeln!(file, "{}", passphrase)?;
+
+    Ok(())
+}
+
+/// Groups of characters separated by dashes, e.g. `x7kq-m2va-9pne-3tcw`. As the
+/// alphabet has 32 characters, taking each byte modulo 32 keeps them uniform.
+fn encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take(GROUPS * GROUP_LENGTH)
+        .map(|byte| ALPHABET[*byte as usize % ALPHABET.len()] as char)
+        .collect::<Vec<_>>()
+        .chunks(GROUP_LENGTH)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn invalid_random_passphrase_is_replaced() {
+        let passphrase_file =
+            env::temp_dir().join(format!("wifi-connect-passphrase-{}", process::id()));
+
+        fs::write(&passphrase_file, "short\n").unwrap();
+
+        let passphrase = load_random_passphrase(&passphrase_file).unwrap();
+        assert_eq!(passphrase.len(), GROUPS * (GROUP_LENGTH + 1) - 1);
+        assert_eq!(read_trimmed(&passphrase_file).unwrap(), passphrase);
+
+        fs::write(&passphrase_file, format!("{}\n", "x".repeat(64))).unwrap();
+        assert_ne!(
+            load_random_passphrase(&passphrase_file).unwrap(),
+            "x".repeat(64)
+        );
+
+        fs::write(&passphrase_file, "kept passphrase\n").unwrap();
+        assert_eq!(
+            load_random_passphrase(&passphrase_file).unwrap(),
+            "kept passphrase"
+        );
+
+        fs::remove_file(&passphrase_file).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::Path;
+
+use image::Luma;
+use qrcode::render::{svg, unicode};
+use qrcode::QrCode;
+
+use config::Config;
+use errors::*;
+
+// Pixels, large enough to be printed on a device label
+const IMAGE_SIZE: u32 = 400;
+
+/// Writes a QR code joining the portal network to the terminal and to an
+/// image file, as configured
+pub fn show_qr_code(config: &Config) -> Result<()> {
+    if !config.print_qr_code && config.qr_code.is_none() {
+        return Ok(());
+    }
+
+    let payload = wifi_payload(&config.ssid, config.passphrase.as_ref().map(|p| p as &str));
+    let code = QrCode::new(payload.as_bytes()).chain_err(|| ErrorKind::QrCode)?;
+
+    if config.print_qr_code {
+        let text = code
+            .render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build();
+        println!("{}", text);
+    }
+
+    if let Some(ref path) = config.qr_code {
+        write_image(&code, path)
+            .chain_err(|| ErrorKind::WriteQrCode(path.display().to_string()))?;
+        info!("Portal QR code written to {}", path.display());
+    }
+
+    Ok(())
+}
+
+fn write_image(code: &QrCode, path: &Path) -> Result<()> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("png") => code
+            .render::<Luma<u8>>()
+            .min_dimensions(IMAGE_SIZE, IMAGE_SIZE)
+            .build()
+            .save(path)
+            .map_err(|e| Error::from(e.to_string())),
+        Some("svg") => {
+            let image = code
+                .render::<svg::Color>()
+                .min_dimensions(IMAGE_SIZE, IMAGE_SIZE)
+                .build();
+            fs::write(path, image)?;
+            Ok(())
+        }
+        _ => bail!(ErrorKind::QrCodeFormat(path.display().to_string())),
+    }
+}
+
+/// Network in the format understood by the camera apps of phones, e.g.
+/// `WIFI:S:WiFi Connect;T:WPA;P:passphrase;;`
+fn wifi_payload(ssid: &str, passphrase: Option<&str>) -> String {
+    match passphrase {
+        Some(passphrase) => format!("WIFI:S:{};T:WPA;P:{};;", escape(ssid), escape(passphrase)),
+        None => format!("WIFI:S:{};T:nopass;;", escape(ssid)),
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if let '\\' | ';' | ',' | ':' | '"' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
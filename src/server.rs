@@ -1,37 +1,166 @@
 use std::error::Error as StdError;
 use std::fmt;
-use std::net::Ipv4Addr;
-use std::sync::mpsc::{Receiver, Sender};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use iron::modifiers::Redirect;
+use iron::mime::Mime;
+use iron::modifiers::{Header, Redirect};
 use iron::prelude::*;
+use iron::response::WriteBody;
+use iron::url::percent_encoding::percent_decode;
 use iron::{
     headers, status, typemap, AfterMiddleware, Iron, IronError, IronResult, Request, Response, Url,
 };
 use iron_cors::CorsMiddleware;
 use mount::Mount;
-use params::{FromValue, Params};
+use params::{File, FromValue, Map, Params};
 use path::PathBuf;
 use persistent::Write;
 use router::Router;
 use serde_json;
 use staticfile::Static;
 
+use backend::{EnterpriseSettings, IpSettings, Ipv4Config, Ipv6Config, Security, SCAN_TIMEOUT};
+use certificates::{remove_certificates, store_certificates};
 use errors::*;
+use events::{EventBus, PortalEvent};
 use exit::{exit, ExitResult};
-use network::{NetworkCommand, NetworkCommandResponse};
+use network::{Network, NetworkCommand, NetworkCommandResponse};
+use state::{ConnectionAttempt, LastAttempt, PortalState, PortalTimer, StateHandle};
 
 struct RequestSharedState {
     gateway: Ipv4Addr,
-    server_rx: Receiver<NetworkCommandResponse>,
     network_tx: Sender<NetworkCommand>,
     exit_tx: Sender<ExitResult>,
+    state: StateHandle,
+    last_attempt: LastAttempt,
+    events: EventBus,
+    timer: PortalTimer,
+    certificate_directory: PathBuf,
+    user_portal_url: Option<String>,
+    event_streams: Arc<AtomicUsize>,
 }
 
 impl typemap::Key for RequestSharedState {
     type Value = RequestSharedState;
 }
 
+#[derive(Serialize)]
+struct StateResponse {
+    state: PortalState,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    state: PortalState,
+    last_attempt: Option<ConnectionAttempt>,
+}
+
+#[derive(Serialize)]
+struct ScanResponse {
+    networks: Vec<Network>,
+    /// Unix time of the scan the networks are from, if the device could not
+    /// scan with the access point up
+    stale_since: Option<u64>,
+}
+
+/// RFC 8908 Captive Portal API state
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CaptivePortalApiResponse {
+    captive: bool,
+    user_portal_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seconds_remaining: Option<u64>,
+}
+
+// Every stream occupies a server thread
+const MAX_EVENT_STREAMS: usize = 4;
+
+// Seconds
+const EVENTS_KEEP_ALIVE: u64 = 15;
+// Clients reconnect on their own after the stream ends
+const EVENTS_MAX_DURATION: u64 = 300;
+// Longer than a background scan, which keeps the network thread busy
+const REPLY_TIMEOUT: u64 = SCAN_TIMEOUT + 10;
+// Picking up the request and scanning
+const SCAN_REPLY_TIMEOUT: u64 = REPLY_TIMEOUT + SCAN_TIMEOUT;
+
+/// `text/event-stream` body writing events until the portal exits, the
+/// client goes away or `EVENTS_MAX_DURATION` is reached
+struct EventStream {
+    events: Receiver<PortalEvent>,
+    _slot: EventStreamSlot,
+}
+
+/// One of the `MAX_EVENT_STREAMS` streams, released when the stream is
+/// dropped
+struct EventStreamSlot {
+    streams: Arc<AtomicUsize>,
+}
+
+impl EventStreamSlot {
+    fn acquire(streams: &Arc<AtomicUsize>) -> Option<Self> {
+        if streams.fetch_add(1, Ordering::SeqCst) >= MAX_EVENT_STREAMS {
+            streams.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(EventStreamSlot {
+            streams: streams.clone(),
+        })
+    }
+}
+
+impl Drop for EventStreamSlot {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut dyn io::Write) -> io::Result<()> {
+        // Tell the browser to reconnect quickly, e.g. after a portal restart
+        res.write_all(b"retry: 3000\n\n")?;
+        res.flush()?;
+
+        let deadline = Instant::now() + Duration::from_secs(EVENTS_MAX_DURATION);
+
+        while Instant::now() < deadline {
+            match self
+                .events
+                .recv_timeout(Duration::from_secs(EVENTS_KEEP_ALIVE))
+            {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                    write!(res, "event: {}\ndata: {}\n\n", event.name(), data)?;
+                    res.flush()?;
+
+                    if let PortalEvent::Exit { .. } = event {
+                        return Ok(());
+                    }
+                }
+                // Comments keep proxies from closing the connection and
+                // detect clients that went away
+                Err(RecvTimeoutError::Timeout) => {
+                    res.write_all(b": keep-alive\n\n")?;
+                    res.flush()?;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct StringError(String);
 
@@ -85,6 +214,22 @@ macro_rules! get_param {
     };
 }
 
+macro_rules! get_optional_param {
+    ($params:ident, $param:expr, $ty:ty) => {
+        match $params.get($param) {
+            Some(value) => match <$ty as FromValue>::from_value(value) {
+                Some(converted) => Some(converted),
+                None => {
+                    let err = format!("Unexpected type for '{}'", $param);
+                    error!("{}", err);
+                    return Err(IronError::new(StringError(err), status::BadRequest));
+                }
+            },
+            None => None,
+        }
+    };
+}
+
 macro_rules! get_request_state {
     ($req:ident) => {
         get_request_ref!(
@@ -111,6 +256,163 @@ where
     ))
 }
 
+/// Connectivity check of an operating system or browser. It is answered
+/// with the response expected when online once the connection is verified,
+/// so that the sign-in sheet closes. Before that a redirect to the portal
+/// makes the sheet pop up.
+struct ConnectivityCheck {
+    path: &'static str,
+    status: status::Status,
+    body: &'static str,
+}
+
+const CONNECTIVITY_CHECKS: &[ConnectivityCheck] = &[
+    // iOS and macOS
+    ConnectivityCheck {
+        path: "/hotspot-detect.html",
+        status: status::Ok,
+        body: "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>",
+    },
+    // Android and ChromeOS
+    ConnectivityCheck {
+        path: "/generate_204",
+        status: status::NoContent,
+        body: "",
+    },
+    ConnectivityCheck {
+        path: "/gen_204",
+        status: status::NoContent,
+        body: "",
+    },
+    // Windows
+    ConnectivityCheck {
+        path: "/ncsi.txt",
+        status: status::Ok,
+        body: "Microsoft NCSI",
+    },
+    ConnectivityCheck {
+        path: "/connecttest.txt",
+        status: status::Ok,
+        body: "Microsoft Connect Test",
+    },
+    // Firefox
+    ConnectivityCheck {
+        path: "/success.txt",
+        status: status::Ok,
+        body: "success\n",
+    },
+    ConnectivityCheck {
+        path: "/canonical.html",
+        status: status::Ok,
+        body: "<meta http-equiv=\"refresh\" content=\"0;url=https://support.mozilla.org/kb/captive-portal\"/>",
+    },
+];
+
+/// Turns a failed command or a reply not matching the command into an error
+/// response
+fn unexpected_reply(response: NetworkCommandResponse) -> IronError {
+    match response {
+        NetworkCommandResponse::Failed(e) => {
+            let status = match *e.kind() {
+                ErrorKind::SavedNetworkNotFound(_) => status::NotFound,
+                ErrorKind::UnsupportedByBackend(_, _) => status::NotImplemented,
+                _ => status::InternalServerError,
+            };
+            warn!("{}", e);
+            IronError::new(e, status)
+        }
+        response => {
+            let err = format!("Unexpected network thread reply: {:?}", response);
+            error!("{}", err);
+            IronError::new(StringError(err), status::InternalServerError)
+        }
+    }
+}
+
+fn bad_request(err: String) -> IronError {
+    IronError::new(StringError(err), status::BadRequest)
+}
+
+fn service_unavailable(e: Error) -> IronError {
+    warn!("{}", e);
+    IronError::new(e, status::ServiceUnavailable)
+}
+
+/// Sends the command built by `command` with a reply channel of its own and
+/// waits for the network thread to reply. A busy or stopped network thread
+/// results in 503 Service Unavailable.
+fn request_network_thread<F>(
+    req: &mut Request,
+    command: F,
+    e_kind: ErrorKind,
+) -> IronResult<NetworkCommandResponse>
+where
+    F: FnOnce(Sender<NetworkCommandResponse>) -> NetworkCommand,
+{
+    request_network_thread_within(req, command, e_kind, REPLY_TIMEOUT)
+}
+
+/// Same as `request_network_thread`, but waits `timeout` seconds for commands
+/// that take longer than usual
+fn request_network_thread_within<F>(
+    req: &mut Request,
+    command: F,
+    e_kind: ErrorKind,
+    timeout: u64,
+) -> IronResult<NetworkCommandResponse>
+where
+    F: FnOnce(Sender<NetworkCommandResponse>) -> NetworkCommand,
+{
+    send_network_command(req, command, e_kind)?
+        .recv_timeout(Duration::from_secs(timeout))
+        .map_err(|e| service_unavailable(Error::with_chain(e, ErrorKind::RecvNetworkCommandReply)))
+}
+
+/// Same as `request_network_thread` for commands changing saved networks.
+/// Once the network thread accepted the command, the outcome is waited for
+/// without timeout, so that no change is made unreported.
+fn request_network_thread_change<F>(
+    req: &mut Request,
+    command: F,
+    e_kind: ErrorKind,
+) -> IronResult<NetworkCommandResponse>
+where
+    F: FnOnce(Sender<NetworkCommandResponse>) -> NetworkCommand,
+{
+    let reply_rx = send_network_command(req, command, e_kind)?;
+
+    match reply_rx.recv_timeout(Duration::from_secs(REPLY_TIMEOUT)) {
+        Ok(NetworkCommandResponse::Accepted) => reply_rx.recv().map_err(|e| {
+            service_unavailable(Error::with_chain(e, ErrorKind::RecvNetworkCommandReply))
+        }),
+        Ok(response) => Ok(response),
+        Err(e) => Err(service_unavailable(Error::with_chain(
+            e,
+            ErrorKind::RecvNetworkCommandReply,
+        ))),
+    }
+}
+
+fn send_network_command<F>(
+    req: &mut Request,
+    command: F,
+    e_kind: ErrorKind,
+) -> IronResult<Receiver<NetworkCommandResponse>>
+where
+    F: FnOnce(Sender<NetworkCommandResponse>) -> NetworkCommand,
+{
+    // Do not keep other requests waiting on the shared state
+    let network_tx = get_request_state!(req).network_tx.clone();
+
+    let (reply_tx, reply_rx) = channel();
+
+    if let Err(e) = network_tx.send(command(reply_tx)) {
+        return Err(service_unavailable(Error::with_chain(e, e_kind)));
+    }
+
+    Ok(reply_rx)
+}
+
 struct RedirectMiddleware;
 
 impl AfterMiddleware for RedirectMiddleware {
@@ -134,18 +436,29 @@ impl AfterMiddleware for RedirectMiddleware {
 pub fn start_server(
     gateway: Ipv4Addr,
     listening_port: u16,
-    server_rx: Receiver<NetworkCommandResponse>,
     network_tx: Sender<NetworkCommand>,
     exit_tx: Sender<ExitResult>,
+    state: StateHandle,
+    last_attempt: LastAttempt,
+    events: EventBus,
+    timer: PortalTimer,
     ui_directory: &PathBuf,
+    certificate_directory: PathBuf,
+    user_portal_url: Option<String>,
 ) {
     let exit_tx_clone = exit_tx.clone();
     let gateway_clone = gateway;
     let request_state = RequestSharedState {
         gateway,
-        server_rx,
         network_tx,
         exit_tx,
+        state,
+        last_attempt,
+        events,
+        timer,
+        certificate_directory,
+        user_portal_url,
+        event_streams: Arc::new(AtomicUsize::new(0)),
     };
 
     let mut router = Router::new();
@@ -153,6 +466,34 @@ pub fn start_server(
     router.get("/networks", networks, "networks");
     router.post("/connect", connect, "connect");
     router.post("/restart", restart, "restart");
+    router.post("/scan", scan, "scan");
+    router.get("/saved-networks", saved_networks, "saved-networks");
+    router.delete(
+        "/saved-networks/:ssid",
+        delete_saved_network,
+        "delete-saved-network",
+    );
+    router.put(
+        "/saved-networks/:ssid/priority",
+        set_saved_network_priority,
+        "saved-network-priority",
+    );
+    router.get("/state", portal_state, "state");
+    router.get("/status", connection_status, "status");
+    router.get("/events", portal_events, "events");
+    router.get(
+        "/captive-portal/api",
+        captive_portal_api,
+        "captive-portal-api",
+    );
+
+    for check in CONNECTIVITY_CHECKS {
+        router.get(
+            check.path,
+            move |req: &mut Request| connectivity_check(req, check),
+            check.path,
+        );
+    }
 
     let mut assets = Mount::new();
     assets.mount("/", router);
@@ -183,19 +524,19 @@ pub fn start_server(
 fn networks(req: &mut Request) -> IronResult<Response> {
     info!("User connected to the captive portal");
 
-    let request_state = get_request_state!(req);
-
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::ActivatePortal) {
-        return exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandActivate);
-    }
+    let response = request_network_thread(
+        req,
+        |reply_tx| NetworkCommand::ActivatePortal { reply_tx },
+        ErrorKind::SendNetworkCommandActivate,
+    )?;
 
-    let networks = match request_state.server_rx.recv() {
-        Ok(result) => match result {
-            NetworkCommandResponse::Networks(networks) => networks,
-        },
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::RecvAccessPointSSIDs),
+    let networks = match response {
+        NetworkCommandResponse::Networks(networks) => networks,
+        response => return Err(unexpected_reply(response)),
     };
 
+    let request_state = get_request_state!(req);
+
     let access_points_json = match serde_json::to_string(&networks) {
         Ok(json) => json,
         Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
@@ -205,39 +546,480 @@ fn networks(req: &mut Request) -> IronResult<Response> {
 }
 
 fn connect(req: &mut Request) -> IronResult<Response> {
-    let (ssid, identity, passphrase) = {
+    let (ssid, identity, passphrase, hidden, security, mut enterprise, ip) = {
         let params = get_request_ref!(req, Params, "Getting request params failed");
         let ssid = get_param!(params, "ssid", String);
         let identity = get_param!(params, "identity", String);
         let passphrase = get_param!(params, "passphrase", String);
-        (ssid, identity, passphrase)
+        let hidden = get_optional_param!(params, "hidden", bool).unwrap_or(false);
+        let security = get_optional_param!(params, "security", String);
+        let enterprise = enterprise_settings(params)?;
+        let ip = ip_settings(params)?;
+        (ssid, identity, passphrase, hidden, security, enterprise, ip)
     };
 
     debug!("Incoming `connect` to access point `{}` request", ssid);
 
-    let request_state = get_request_state!(req);
+    // Hidden networks are not scanned, so their security cannot be looked up
+    let hidden = if hidden {
+        match security.map(|security| security.parse::<Security>()) {
+            Some(Ok(security)) => Some(security),
+            Some(Err(err)) => return Err(bad_request(err)),
+            None => {
+                return Err(bad_request(
+                    "'security' is required for hidden networks".to_string(),
+                ))
+            }
+        }
+    } else {
+        None
+    };
+
+    // Uploads are removed after the request, the backends need the files
+    // whenever they connect
+    let certificate_directory = get_request_state!(req).certificate_directory.clone();
+
+    store_certificates(&certificate_directory, &ssid, &mut enterprise).map_err(|e| {
+        error!("{}", e);
+        IronError::new(e, status::InternalServerError)
+    })?;
+
+    // The network thread removes the files if the attempt fails
+    let stored = enterprise.clone();
+
+    let accepted = request_network_thread(
+        req,
+        |reply_tx| NetworkCommand::WiFiConnect {
+            ssid,
+            identity,
+            passphrase,
+            hidden,
+            enterprise,
+            ip,
+            reply_tx,
+        },
+        ErrorKind::SendNetworkCommandConnect,
+    );
+
+    if accepted.is_err() {
+        remove_certificates(&stored);
+    }
+
+    accepted?;
+
+    Ok(Response::with(status::Ok))
+}
+
+/// 802.1X settings of a `connect` request, only used if the network turns out
+/// to be an enterprise one. Certificates point to the uploaded files until
+/// they are stored.
+fn enterprise_settings(params: &Map) -> IronResult<EnterpriseSettings> {
+    let mut enterprise = EnterpriseSettings::default();
+
+    if let Some(eap) = get_optional_param!(params, "eap_method", String) {
+        enterprise.eap = eap.parse().map_err(bad_request)?;
+    }
+
+    if let Some(phase2) = get_optional_param!(params, "phase2_auth", String) {
+        enterprise.phase2 = Some(phase2.parse().map_err(bad_request)?);
+    }
+
+    enterprise.anonymous_identity =
+        non_empty(get_optional_param!(params, "anonymous_identity", String));
+    enterprise.domain_suffix_match =
+        non_empty(get_optional_param!(params, "domain_suffix_match", String));
+
+    enterprise.ca_cert = get_optional_param!(params, "ca_cert", File).map(|file| file.path);
+    enterprise.client_cert = get_optional_param!(params, "client_cert", File).map(|file| file.path);
+    enterprise.private_key = get_optional_param!(params, "private_key", File).map(|file| file.path);
+
+    enterprise.validate().map_err(bad_request)?;
+
+    Ok(enterprise)
+}
+
+/// Static addresses and DNS servers of a `connect` request, automatic unless
+/// set
+fn ip_settings(params: &Map) -> IronResult<IpSettings> {
+    let ipv4 = static_address(params, "ipv4", 32)?.map(|(address, prefix, gateway)| Ipv4Config {
+        address,
+        prefix,
+        gateway,
+    });
+
+    let ipv6 = static_address(params, "ipv6", 128)?.map(|(address, prefix, gateway)| Ipv6Config {
+        address,
+        prefix,
+        gateway,
+    });
 
-    let command = NetworkCommand::WiFiConnect {
-        ssid,
-        identity,
-        passphrase,
+    let dns = match non_empty(get_optional_param!(params, "dns", String)) {
+        Some(servers) => servers
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(parse_ip)
+            .collect::<::std::result::Result<Vec<IpAddr>, String>>()
+            .map_err(bad_request)?,
+        None => Vec::new(),
     };
 
-    if let Err(e) = request_state.network_tx.send(command) {
-        exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandConnect)
-    } else {
-        Ok(Response::with(status::Ok))
+    let ip = IpSettings { ipv4, ipv6, dns };
+
+    ip.validate().map_err(bad_request)?;
+
+    Ok(ip)
+}
+
+/// Address, prefix length and gateway of the `manual` method of `family`,
+/// `None` for the default `auto` method
+fn static_address<A: FromStr>(
+    params: &Map,
+    family: &str,
+    max_prefix: u8,
+) -> IronResult<Option<(A, u8, Option<A>)>> {
+    let method_param = format!("{}_method", family);
+    let address_param = format!("{}_address", family);
+    let gateway_param = format!("{}_gateway", family);
+
+    let method = non_empty(get_optional_param!(params, &method_param, String));
+    let address = non_empty(get_optional_param!(params, &address_param, String));
+    let gateway = non_empty(get_optional_param!(params, &gateway_param, String));
+
+    match method.as_ref().map_or("auto", |method| method as &str) {
+        "auto" => {
+            if address.is_some() || gateway.is_some() {
+                return Err(bad_request(format!(
+                    "'{}' and '{}' require '{}' to be 'manual'",
+                    address_param, gateway_param, method_param
+                )));
+            }
+
+            Ok(None)
+        }
+        "manual" => {
+            let address = match address {
+                Some(address) => address,
+                None => {
+                    return Err(bad_request(format!(
+                        "'{}' is required with the manual method",
+                        address_param
+                    )))
+                }
+            };
+
+            let (address, prefix) = parse_prefixed(&address, max_prefix).map_err(bad_request)?;
+
+            let gateway = match gateway {
+                Some(gateway) => Some(parse_ip(&gateway).map_err(bad_request)?),
+                None => None,
+            };
+
+            Ok(Some((address, prefix, gateway)))
+        }
+        method => Err(bad_request(format!(
+            "Unknown {} method '{}', expected 'auto' or 'manual'",
+            family, method
+        ))),
+    }
+}
+
+/// `address/prefix`, e.g. `192.168.1.10/24`
+fn parse_prefixed<A: FromStr>(
+    value: &str,
+    max_prefix: u8,
+) -> ::std::result::Result<(A, u8), String> {
+    let mut parts = value.trim().splitn(2, '/');
+    let address = parse_ip(parts.next().unwrap_or(""))?;
+
+    match parts.next().map(str::parse::<u8>) {
+        Some(Ok(prefix)) if prefix > 0 && prefix <= max_prefix => Ok((address, prefix)),
+        _ => Err(format!(
+            "Invalid address '{}', expected address/prefix with a prefix length of 1 to {}",
+            value, max_prefix
+        )),
     }
 }
 
+fn parse_ip<A: FromStr>(value: &str) -> ::std::result::Result<A, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid IP address '{}'", value))
+}
+
+/// Forms send fields left empty
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.trim().is_empty())
+}
+
 fn restart(req: &mut Request) -> IronResult<Response> {
     info!("User requested restart of the captive portal");
 
+    request_network_thread(
+        req,
+        |reply_tx| NetworkCommand::RestartApp { reply_tx },
+        ErrorKind::RestartCommand,
+    )?;
+
+    Ok(Response::with(status::Ok))
+}
+
+fn scan(req: &mut Request) -> IronResult<Response> {
+    info!("User requested a scan");
+
+    let response = request_network_thread_within(
+        req,
+        |reply_tx| NetworkCommand::Scan { reply_tx },
+        ErrorKind::ScanCommand,
+        SCAN_REPLY_TIMEOUT,
+    )?;
+
+    let (networks, stale_since) = match response {
+        NetworkCommandResponse::Scanned {
+            networks,
+            stale_since,
+        } => (networks, stale_since),
+        response => return Err(unexpected_reply(response)),
+    };
+
+    let scan_response = ScanResponse {
+        networks,
+        stale_since: stale_since.map(unix_time),
+    };
+
     let request_state = get_request_state!(req);
 
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::RestartApp) {
-        exit_with_error(&request_state, e, ErrorKind::RestartCommand)
-    } else {
-        Ok(Response::with(status::Ok))
+    match serde_json::to_string(&scan_response) {
+        Ok(json) => Ok(Response::with((status::Ok, json))),
+        Err(e) => exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn saved_networks(req: &mut Request) -> IronResult<Response> {
+    let response = request_network_thread(
+        req,
+        |reply_tx| NetworkCommand::SavedNetworks { reply_tx },
+        ErrorKind::SavedNetworksCommand,
+    )?;
+
+    let networks = match response {
+        NetworkCommandResponse::SavedNetworks(networks) => networks,
+        response => return Err(unexpected_reply(response)),
+    };
+
+    let request_state = get_request_state!(req);
+
+    match serde_json::to_string(&networks) {
+        Ok(json) => Ok(Response::with((status::Ok, json))),
+        Err(e) => exit_with_error(&request_state, e, ErrorKind::SerializeSavedNetworks),
+    }
+}
+
+fn delete_saved_network(req: &mut Request) -> IronResult<Response> {
+    let ssid = ssid_param(req)?;
+
+    info!("User requested deleting saved network '{}'", ssid);
+
+    match request_network_thread_change(
+        req,
+        |reply_tx| NetworkCommand::DeleteSavedNetwork { ssid, reply_tx },
+        ErrorKind::SavedNetworksCommand,
+    )? {
+        NetworkCommandResponse::Done => Ok(Response::with(status::NoContent)),
+        response => Err(unexpected_reply(response)),
+    }
+}
+
+fn set_saved_network_priority(req: &mut Request) -> IronResult<Response> {
+    let ssid = ssid_param(req)?;
+
+    // A missing or invalid priority is a mistake of the client
+    let priority = {
+        let params = get_request_ref!(req, Params, "Getting request params failed");
+        match get_optional_param!(params, "priority", i32) {
+            Some(priority) => priority,
+            None => return Err(bad_request("'priority' is required".to_string())),
+        }
+    };
+
+    match request_network_thread_change(
+        req,
+        |reply_tx| NetworkCommand::SetSavedNetworkPriority {
+            ssid,
+            priority,
+            reply_tx,
+        },
+        ErrorKind::SavedNetworksCommand,
+    )? {
+        NetworkCommandResponse::Done => Ok(Response::with(status::NoContent)),
+        response => Err(unexpected_reply(response)),
+    }
+}
+
+/// Percent-decoded `:ssid` segment of the request path
+fn ssid_param(req: &Request) -> IronResult<String> {
+    let ssid = req
+        .extensions
+        .get::<Router>()
+        .and_then(|params| params.find("ssid"))
+        .unwrap_or("");
+
+    percent_decode(ssid.as_bytes())
+        .decode_utf8()
+        .map(|ssid| ssid.into_owned())
+        .map_err(|e| IronError::new(e, status::BadRequest))
+}
+
+fn portal_state(req: &mut Request) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    let response = StateResponse {
+        state: request_state.state.current(),
+    };
+
+    match serde_json::to_string(&response) {
+        Ok(json) => Ok(Response::with((status::Ok, json))),
+        Err(e) => exit_with_error(&request_state, e, ErrorKind::SerializeState),
+    }
+}
+
+fn connection_status(req: &mut Request) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    let response = StatusResponse {
+        state: request_state.state.current(),
+        last_attempt: request_state.last_attempt.current(),
+    };
+
+    match serde_json::to_string(&response) {
+        Ok(json) => Ok(Response::with((status::Ok, json))),
+        Err(e) => exit_with_error(&request_state, e, ErrorKind::SerializeState),
+    }
+}
+
+fn portal_events(req: &mut Request) -> IronResult<Response> {
+    debug!("Client subscribed to portal events");
+
+    let request_state = get_request_state!(req);
+
+    let slot = match EventStreamSlot::acquire(&request_state.event_streams) {
+        Some(slot) => slot,
+        None => return Err(service_unavailable(ErrorKind::TooManyEventStreams.into())),
+    };
+
+    let stream: Box<dyn WriteBody> = Box::new(EventStream {
+        events: request_state.events.subscribe(),
+        _slot: slot,
+    });
+
+    let mime: Mime = "text/event-stream".parse().unwrap();
+
+    Ok(Response::with((
+        status::Ok,
+        mime,
+        Header(headers::CacheControl(vec![
+            headers::CacheDirective::NoCache,
+        ])),
+        stream,
+    )))
+}
+
+/// Whether clients should be told that they are online, only once the
+/// connection has been verified. While connecting the outcome is unknown and
+/// a failed attempt brings the portal back, so it is still captive.
+fn is_online(request_state: &RequestSharedState) -> bool {
+    request_state.state.current() == PortalState::Connected
+}
+
+fn portal_url(request_state: &RequestSharedState) -> Url {
+    Url::parse(&format!("http://{}/", request_state.gateway)).unwrap()
+}
+
+fn connectivity_check(req: &mut Request, check: &ConnectivityCheck) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    if is_online(&request_state) {
+        debug!("Answering connectivity check {} as online", check.path);
+        return Ok(Response::with((check.status, check.body)));
+    }
+
+    debug!(
+        "Redirecting connectivity check {} to the portal",
+        check.path
+    );
+
+    Ok(Response::with((
+        status::Found,
+        Redirect(portal_url(&request_state)),
+    )))
+}
+
+fn captive_portal_api(req: &mut Request) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    let captive = !is_online(&request_state);
+
+    // The activity timeout only runs until a user opens the portal
+    let seconds_remaining = match request_state.state.current() {
+        PortalState::PortalUp => request_state.timer.seconds_remaining(),
+        _ => None,
+    };
+
+    // RFC 8908 requires an https:// URL, which only a TLS proxy in front of
+    // the server can provide
+    let user_portal_url = match request_state.user_portal_url {
+        Some(ref url) => url.clone(),
+        None => portal_url(&request_state).to_string(),
+    };
+
+    let response = CaptivePortalApiResponse {
+        captive,
+        user_portal_url,
+        seconds_remaining,
+    };
+
+    let json = match serde_json::to_string(&response) {
+        Ok(json) => json,
+        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeState),
+    };
+
+    let mime: Mime = "application/captive+json".parse().unwrap();
+
+    Ok(Response::with((
+        status::Ok,
+        mime,
+        Header(headers::CacheControl(vec![
+            headers::CacheDirective::Private,
+        ])),
+        json,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_stream_slots_are_limited() {
+        let streams = Arc::new(AtomicUsize::new(0));
+
+        let slots: Vec<EventStreamSlot> = (0..MAX_EVENT_STREAMS)
+            .filter_map(|_| EventStreamSlot::acquire(&streams))
+            .collect();
+
+        assert_eq!(slots.len(), MAX_EVENT_STREAMS);
+        assert!(EventStreamSlot::acquire(&streams).is_none());
+
+        drop(slots);
+
+        assert_eq!(streams.load(Ordering::SeqCst), 0);
+        assert!(EventStreamSlot::acquire(&streams).is_some());
     }
 }
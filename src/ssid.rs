@@ -0,0 +1,205 @@
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use errors::*;
+
+const PLACEHOLDERS: &[&str] = &["mac4", "mac6", "hostname", "serial"];
+
+// Characters of the serial number used by `{serial}`, `/etc/machine-id` alone
+// would fill the whole SSID
+const SERIAL_LENGTH: usize = 8;
+
+const MAX_SSID_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// Portal SSID with placeholders for device-unique values:
+///
+/// * `{mac4}`, `{mac6}` - last 4 or 6 hex digits of the interface MAC address
+/// * `{hostname}` - host name of the device
+/// * `{serial}` - last 8 characters of the serial number file
+#[derive(Debug, Clone)]
+pub struct SsidTemplate {
+    template: String,
+    parts: Vec<Part>,
+    /// Result of `expand`, matched exactly by templates without literals
+    expansion: Option<String>,
+}
+
+impl SsidTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!(ErrorKind::InvalidSsidTemplate(template.into())),
+            };
+
+            let name = &rest[start + 1..end];
+
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(ErrorKind::InvalidSsidTemplate(template.into()));
+            }
+
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].into()));
+            }
+
+            parts.push(Part::Placeholder(name.into()));
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.into()));
+        }
+
+        Ok(SsidTemplate {
+            template: template.into(),
+            parts,
+            expansion: None,
+        })
+    }
+
+    pub fn expand(&mut self, interface: &str, serial_file: &Path) -> Result<String> {
+        let mut ssid = String::new();
+
+        for part in &self.parts {
+            match *part {
+                Part::Literal(ref literal) => ssid.push_str(literal),
+                Part::Placeholder(ref name) => {
+                    let value = placeholder_value(name, interface, serial_file)
+                        .chain_err(|| ErrorKind::SsidPlaceholder(name.clone()))?;
+                    ssid.push_str(&value);
+                }
+            }
+        }
+
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LENGTH {
+            bail!(ErrorKind::InvalidSsid(ssid));
+        }
+
+        if ssid != self.template {
+            info!("Portal SSID: {}", ssid);
+        }
+
+        self.expansion = Some(ssid.clone());
+
+        Ok(ssid)
+    }
+
+    /// Whether `ssid` is an expansion of this template with any values, so
+    /// that profiles of previous runs are found after e.g. a host name change.
+    ///
+    /// A template of placeholders only, e.g. `{hostname}`, would match any
+    /// SSID, so it only matches its own expansion.
+    pub fn matches(&self, ssid: &str) -> bool {
+        let has_literal = self.parts.iter().any(|part| match *part {
+            Part::Literal(_) => true,
+            Part::Placeholder(_) => false,
+        });
+
+        if has_literal {
+            matches_parts(&self.parts, ssid)
+        } else {
+            self.expansion
+                .as_ref()
+                .map_or(false, |expansion| expansion == ssid)
+        }
+    }
+}
+
+fn matches_parts(parts: &[Part], ssid: &str) -> bool {
+    match parts.split_first() {
+        None => ssid.is_empty(),
+        Some((&Part::Literal(ref literal), rest)) => {
+            ssid.starts_with(literal.as_str()) && matches_parts(rest, &ssid[literal.len()..])
+        }
+        // Placeholders expand to at least one character
+        Some((&Part::Placeholder(_), rest)) => ssid
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain(iter::once(ssid.len()))
+            .filter(|&index| index > 0)
+            .any(|index| matches_parts(rest, &ssid[index..])),
+    }
+}
+
+fn placeholder_value(name: &str, interface: &str, serial_file: &Path) -> Result<String> {
+    match name {
+        "mac4" => mac_suffix(interface, 4),
+        "mac6" => mac_suffix(interface, 6),
+        "hostname" => read_trimmed(Path::new("/proc/sys/kernel/hostname")),
+        "serial" => {
+            let serial = read_trimmed(serial_file)?;
+            let start = serial
+                .char_indices()
+                .rev()
+                .nth(SERIAL_LENGTH - 1)
+                .map_or(0, |(index, _)| index);
+            Ok(serial[start..].to_string())
+        }
+        _ => bail!(ErrorKind::SsidPlaceholder(name.into())),
+    }
+}
+
+fn mac_suffix(interface: &str, digits: usize) -> Result<String> {
+    let path = PathBuf::from(format!("/sys/class/net/{}/address", interface));
+
+    let hex: String = read_trimmed(&path)?
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    if hex.len() < digits {
+        bail!(ErrorKind::SsidPlaceholder(format!("mac{}", digits)));
+    }
+
+    Ok(hex[hex.len() - digits..].to_string())
+}
+
+pub fn read_trimmed(path: &Path) -> Result<String> {
+    let content = fs::read_to_string(path)?;
+    let content = content.trim();
+
+    if content.is_empty() {
+        bail!(ErrorKind::EmptyFile(path.display().to_string()));
+    }
+
+    Ok(content.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_any_values() {
+        let template = SsidTemplate::parse("WiFi Connect {mac4}").unwrap();
+
+        assert!(template.matches("WiFi Connect 1A2B"));
+        assert!(template.matches("WiFi Connect C3D4"));
+        assert!(!template.matches("WiFi Connect "));
+        assert!(!template.matches("Office"));
+    }
+
+    #[test]
+    fn placeholders_only_match_expansion() {
+        let mut template = SsidTemplate::parse("{hostname}").unwrap();
+
+        assert!(!template.matches("Office"));
+
+        let ssid = template.expand("lo", Path::new("/nonexistent")).unwrap();
+
+        assert!(template.matches(&ssid));
+        assert!(!template.matches(&format!("{}-5G", ssid)));
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use errors::*;
+use events::{EventBus, PortalEvent};
+
+/// Lifecycle of the network thread, see `docs/state-flow-diagram.md`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PortalState {
+    /// Network thread initializing
+    Starting,
+    /// Access point down, scanning for networks before (re)starting it
+    Scanning,
+    /// Access point up, no user has requested the networks list yet
+    PortalUp,
+    /// A user is interacting with the captive portal
+    ClientActive,
+    /// Access point down, connecting to the network chosen by the user
+    Connecting,
+    /// Access point down, the device is connected. Daemons monitor the
+    /// connection in this state.
+    Connected,
+    /// Access point down after the activity timeout, whether or not the
+    /// device is connected. Daemons monitor the connection in this state.
+    Idle,
+    /// Terminal state, the network thread is shutting down
+    Exiting,
+}
+
+impl PortalState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PortalState::Starting => "starting",
+            PortalState::Scanning => "scanning",
+            PortalState::PortalUp => "portal-up",
+            PortalState::ClientActive => "client-active",
+            PortalState::Connecting => "connecting",
+            PortalState::Connected => "connected",
+            PortalState::Idle => "idle",
+            PortalState::Exiting => "exiting",
+        }
+    }
+
+    /// The state entered on `event`, or `None` if `event` is not valid in
+    /// this state
+    pub fn next(&self, event: StateEvent) -> Option<PortalState> {
+        use self::PortalState::*;
+        use self::StateEvent::*;
+
+        match (*self, event) {
+            (Exiting, _) => None,
+            (_, Stopped) => Some(Exiting),
+            (Starting, Started) => Some(Scanning),
+            (Scanning, PortalStarted) => Some(PortalUp),
+            (PortalUp, ClientActivated) | (ClientActive, ClientActivated) => Some(ClientActive),
+            (PortalUp, ConnectRequested) | (ClientActive, ConnectRequested) => Some(Connecting),
+            (PortalUp, RestartRequested) | (ClientActive, RestartRequested) => Some(Scanning),
+            (PortalUp, TimedOut) => Some(Idle),
+            (PortalUp, InternetConnected) | (ClientActive, InternetConnected) => Some(Connected),
+            (Connecting, ConnectSucceeded) => Some(Connected),
+            (Connecting, ConnectFailed) => Some(Scanning),
+            (Connected, ConnectivityLost) | (Idle, ConnectivityLost) => Some(Scanning),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PortalState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Events driving `PortalState` transitions. Most of them are the outcome of
+/// handling a `NetworkCommand`:
+///
+/// * `ActivatePortal`, `Scan` - `ClientActivated`
+/// * `WiFiConnect` - `ConnectRequested`, then `ConnectSucceeded` or `ConnectFailed`
+/// * `RestartApp` - `RestartRequested`
+/// * `Timeout` - `TimedOut` if no user is active
+/// * `CheckConnectivity` - `InternetConnected` with full connectivity, or
+///   `ConnectivityLost` when a daemon loses connectivity
+/// * `Exit` - `Stopped`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateEvent {
+    Started,
+    PortalStarted,
+    ClientActivated,
+    ConnectRequested,
+    ConnectSucceeded,
+    ConnectFailed,
+    RestartRequested,
+    TimedOut,
+    InternetConnected,
+    ConnectivityLost,
+    Stopped,
+}
+
+/// Owner of the current state, used by the network thread. Transitions are
+/// published on `events`.
+pub struct StateMachine {
+    state: Arc<Mutex<PortalState>>,
+    events: EventBus,
+}
+
+impl StateMachine {
+    pub fn new(events: EventBus) -> Self {
+        StateMachine {
+            state: Arc::new(Mutex::new(PortalState::Starting)),
+            events,
+        }
+    }
+
+    pub fn current(&self) -> PortalState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Read-only view of the state for other threads
+    pub fn handle(&self) -> StateHandle {
+        StateHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn transition(&mut self, event: StateEvent) -> Result<PortalState> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.next(event) {
+            Some(next) => {
+                info!("State: {} -> {} ({:?})", *state, next, event);
+                self.events.publish(PortalEvent::State {
+                    state: next,
+                    previous: *state,
+                });
+                *state = next;
+                Ok(next)
+            }
+            None => bail!(ErrorKind::InvalidStateTransition(
+                state.to_string(),
+                format!("{:?}", event)
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StateHandle {
+    state: Arc<Mutex<PortalState>>,
+}
+
+impl StateHandle {
+    pub fn current(&self) -> PortalState {
+        *self.state.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttemptStatus {
+    /// Accepted by the network thread, which takes the access point down
+    /// and rescans before connecting
+    Pending,
+    Connecting,
+    Succeeded,
+    /// Connected, but the network has a captive portal of its own
+    LoginRequired,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureReason {
+    WrongKey,
+    AccessPointNotFound,
+    DhcpTimeout,
+    NotVerified,
+    Unknown,
+}
+
+impl FailureReason {
+    pub fn from_error(e: &Error) -> Self {
+        match *e.kind() {
+            ErrorKind::WrongPassphrase(_) => FailureReason::WrongKey,
+            ErrorKind::AccessPointNotFound(_) => FailureReason::AccessPointNotFound,
+            ErrorKind::DhcpTimeout(_) => FailureReason::DhcpTimeout,
+            ErrorKind::ConnectionNotVerified(_, _) => FailureReason::NotVerified,
+            _ => FailureReason::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionAttempt {
+    pub ssid: String,
+    pub status: AttemptStatus,
+    pub reason: Option<FailureReason>,
+    /// Login page of the captive portal of the network, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_url: Option<String>,
+}
+
+/// Last `/connect` request and its outcome, updated by both the server and
+/// the network thread. Updates are published on `events`.
+#[derive(Clone)]
+pub struct LastAttempt {
+    attempt: Arc<Mutex<Option<ConnectionAttempt>>>,
+    events: EventBus,
+}
+
+impl LastAttempt {
+    pub fn new(events: EventBus) -> Self {
+        LastAttempt {
+            attempt: Arc::new(Mutex::new(None)),
+            events,
+        }
+    }
+
+    pub fn current(&self) -> Option<ConnectionAttempt> {
+        self.attempt.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, ssid: &str, status: AttemptStatus, reason: Option<FailureReason>) {
+        self.set(ConnectionAttempt {
+            ssid: ssid.to_string(),
+            status,
+            reason,
+            login_url: None,
+        });
+    }
+
+    pub fn login_required(&self, ssid: &str, login_url: Option<String>) {
+        self.set(ConnectionAttempt {
+            ssid: ssid.to_string(),
+            status: AttemptStatus::LoginRequired,
+            reason: None,
+            login_url,
+        });
+    }
+
+    fn set(&self, attempt: ConnectionAttempt) {
+        *self.attempt.lock().unwrap() = Some(attempt.clone());
+
+        self.events.publish(PortalEvent::Connection(attempt));
+    }
+}
+
+/// Start of the activity timeout period of the portal, shared with the
+/// server to report the time left
+#[derive(Clone)]
+pub struct PortalTimer {
+    started: Arc<Mutex<Instant>>,
+    timeout: Duration,
+}
+
+impl PortalTimer {
+    /// A `timeout` of 0 seconds disables the timer
+    pub fn new(timeout: u64) -> Self {
+        PortalTimer {
+            started: Arc::new(Mutex::new(Instant::now())),
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    pub fn restart(&self) {
+        *self.started.lock().unwrap() = Instant::now();
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.started.lock().unwrap().elapsed() >= self.timeout
+    }
+
+    pub fn seconds_remaining(&self) -> Option<u64> {
+        if self.timeout.as_secs() == 0 {
+            return None;
+        }
+
+        let elapsed = self.started.lock().unwrap().elapsed();
+
+        Some(
+            self.timeout
+                .checked_sub(elapsed)
+                .unwrap_or_default()
+                .as_secs(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PortalState::*;
+    use super::StateEvent::*;
+
+    #[test]
+    fn documented_transitions() {
+        let transitions = [
+            (Starting, Started, Scanning),
+            (Scanning, PortalStarted, PortalUp),
+            (PortalUp, ClientActivated, ClientActive),
+            (ClientActive, ClientActivated, ClientActive),
+            (PortalUp, ConnectRequested, Connecting),
+            (ClientActive, ConnectRequested, Connecting),
+            (PortalUp, RestartRequested, Scanning),
+            (ClientActive, RestartRequested, Scanning),
+            (PortalUp, TimedOut, Idle),
+            (PortalUp, InternetConnected, Connected),
+            (ClientActive, InternetConnected, Connected),
+            (Connecting, ConnectSucceeded, Connected),
+            (Connecting, ConnectFailed, Scanning),
+            (Connected, ConnectivityLost, Scanning),
+            (Idle, ConnectivityLost, Scanning),
+            (Starting, Stopped, Exiting),
+            (Scanning, Stopped, Exiting),
+            (PortalUp, Stopped, Exiting),
+            (ClientActive, Stopped, Exiting),
+            (Connecting, Stopped, Exiting),
+            (Connected, Stopped, Exiting),
+            (Idle, Stopped, Exiting),
+        ];
+
+        for &(state, event, next) in &transitions {
+            assert_eq!(state.next(event), Some(next), "{} on {:?}", state, event);
+        }
+    }
+
+    #[test]
+    fn rejected_transitions() {
+        let transitions = [
+            (Starting, PortalStarted),
+            (Scanning, ConnectRequested),
+            (ClientActive, TimedOut),
+            (Connecting, ClientActivated),
+            (Connecting, TimedOut),
+            (Connected, ConnectRequested),
+            (Idle, InternetConnected),
+            (Exiting, Stopped),
+            (Exiting, Started),
+        ];
+
+        for &(state, event) in &transitions {
+            assert_eq!(state.next(event), None, "{} on {:?}", state, event);
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iron::url::Url;
+
+use backend::{has_global_address, Connectivity, WifiBackend};
+use errors::*;
+
+// Seconds
+const PROBE_TIMEOUT: u64 = 5;
+const PROBE_INTERVAL: u64 = 10;
+
+// Status line, headers and the start of the body
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// Outcome of verifying a new connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    Verified,
+    /// Connected to a network with a captive portal of its own, which has to
+    /// be logged into first. The login page is known if the portal redirects
+    /// to it.
+    LoginRequired(Option<String>),
+    Failed,
+}
+
+struct HttpResponse {
+    status: u16,
+    location: Option<String>,
+    body: String,
+}
+
+/// When a connection counts as working, both right after connecting and
+/// while checking connectivity later on
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyPolicy {
+    /// Associated with the access point, with or without an address
+    Associated,
+    /// Associated and holding an address that is not link-local
+    Ip,
+    /// Limited or full connectivity as reported by the backend
+    Limited,
+    /// Full connectivity as reported by the backend
+    Full,
+    /// `GET` request answered with `status` and a body containing `body`
+    Http {
+        url: Url,
+        status: u16,
+        body: Option<String>,
+    },
+    /// TCP connection to `host:port`
+    Tcp(String),
+    /// Resolution of a host name
+    Dns(String),
+}
+
+impl FromStr for VerifyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "associated" => return Ok(VerifyPolicy::Associated),
+            "ip" => return Ok(VerifyPolicy::Ip),
+            "limited" => return Ok(VerifyPolicy::Limited),
+            "full" => return Ok(VerifyPolicy::Full),
+            _ => {}
+        }
+
+        if s.starts_with("http://") {
+            return match Url::parse(s) {
+                Ok(ref url) if url.host_str().is_none() => Err(format!("No host in '{}'", s)),
+                Ok(url) => Ok(VerifyPolicy::Http {
+                    url,
+                    status: 200,
+                    body: None,
+                }),
+                Err(e) => Err(format!("Invalid URL '{}': {}", s, e)),
+            };
+        }
+
+        if s.starts_with("tcp:") {
+            let address = &s["tcp:".len()..];
+
+            return match address.rsplitn(2, ':').next().map(str::parse::<u16>) {
+                Some(Ok(port)) if port > 0 && address.contains(':') => {
+                    Ok(VerifyPolicy::Tcp(address.to_string()))
+                }
+                _ => Err(format!("Expected tcp:host:port, got '{}'", s)),
+            };
+        }
+
+        if s.starts_with("dns:") && s.len() > "dns:".len() {
+            return Ok(VerifyPolicy::Dns(s["dns:".len()..].to_string()));
+        }
+
+        Err(format!(
+            "Unknown verification policy '{}', expected associated, ip, limited, full, \
+             an http:// URL, tcp:host:port or dns:host",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for VerifyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerifyPolicy::Associated => write!(f, "associated"),
+            VerifyPolicy::Ip => write!(f, "ip"),
+            VerifyPolicy::Limited => write!(f, "limited"),
+            VerifyPolicy::Full => write!(f, "full"),
+            VerifyPolicy::Http { ref url, .. } => write!(f, "{}", url),
+            VerifyPolicy::Tcp(ref address) => write!(f, "tcp:{}", address),
+            VerifyPolicy::Dns(ref host) => write!(f, "dns:{}", host),
+        }
+    }
+}
+
+impl VerifyPolicy {
+    /// Whether the connection of `backend` meets the policy right now. Probes
+    /// that fail are logged and count as not met.
+    pub fn check(&self, backend: &dyn WifiBackend) -> Result<bool> {
+        match *self {
+            VerifyPolicy::Associated => backend.is_associated(),
+            VerifyPolicy::Ip => {
+                Ok(backend.is_associated()? && has_global_address(backend.interface())?)
+            }
+            VerifyPolicy::Limited => match backend.connectivity()? {
+                Connectivity::Limited | Connectivity::Full => Ok(true),
+                _ => Ok(false),
+            },
+            VerifyPolicy::Full => Ok(backend.connectivity()? == Connectivity::Full),
+            VerifyPolicy::Http { .. } | VerifyPolicy::Tcp(_) | VerifyPolicy::Dns(_) => {
+                Ok(self.probe())
+            }
+        }
+    }
+
+    /// Policy of the connectivity checks while the portal is up. Limited
+    /// connectivity, e.g. over Ethernet without Internet access, does not end
+    /// the portal.
+    pub fn portal_policy(&self) -> VerifyPolicy {
+        match *self {
+            VerifyPolicy::Limited => VerifyPolicy::Full,
+            ref policy => policy.clone(),
+        }
+    }
+
+    /// Whether the policy is checked by a request to another host, which may
+    /// take several seconds
+    fn is_probe(&self) -> bool {
+        match *self {
+            VerifyPolicy::Http { .. } | VerifyPolicy::Tcp(_) | VerifyPolicy::Dns(_) => true,
+            _ => false,
+        }
+    }
+
+    fn probe(&self) -> bool {
+        match *self {
+            VerifyPolicy::Http {
+                ref url,
+                status,
+                ref body,
+            } => probe_http(url, status, body),
+            VerifyPolicy::Tcp(ref address) => probe_tcp(address),
+            VerifyPolicy::Dns(ref host) => probe_dns(host),
+            _ => false,
+        }
+    }
+
+    /// Whether a connection failing the policy is most likely missing an
+    /// address, rather than being cut off from the Internet
+    pub fn is_addressing(&self) -> bool {
+        match *self {
+            VerifyPolicy::Associated | VerifyPolicy::Ip | VerifyPolicy::Limited => true,
+            _ => false,
+        }
+    }
+}
+
+/// Repeated checks of a policy by a thread that has to stay responsive.
+/// Probes run on a thread of their own, one at a time and at most every
+/// `PROBE_INTERVAL` seconds.
+pub struct ConnectivityMonitor {
+    policy: VerifyPolicy,
+    outcome: Arc<Mutex<Option<bool>>>,
+    probing: Arc<AtomicBool>,
+    probed_at: Option<Instant>,
+}
+
+impl ConnectivityMonitor {
+    pub fn new(policy: VerifyPolicy) -> Self {
+        ConnectivityMonitor {
+            policy,
+            outcome: Arc::new(Mutex::new(None)),
+            probing: Arc::new(AtomicBool::new(false)),
+            probed_at: None,
+        }
+    }
+
+    pub fn policy(&self) -> &VerifyPolicy {
+        &self.policy
+    }
+
+    /// Whether the policy is met. Backend policies are checked right away,
+    /// probes report the outcome of a probe finished since the last call, or
+    /// `None` if there is none.
+    pub fn check(&mut self, backend: &dyn WifiBackend) -> Result<Option<bool>> {
+        if !self.policy.is_probe() {
+            return self.policy.check(backend).map(Some);
+        }
+
+        let outcome = self.outcome.lock().unwrap().take();
+
+        let due = self.probed_at.map_or(true, |probed_at| {
+            probed_at.elapsed() >= Duration::from_secs(PROBE_INTERVAL)
+        });
+
+        if due && !self.probing.swap(true, Ordering::SeqCst) {
+            self.probed_at = Some(Instant::now());
+
+            let policy = self.policy.clone();
+            let outcome = self.outcome.clone();
+            let probing = self.probing.clone();
+
+            thread::spawn(move || {
+                *outcome.lock().unwrap() = Some(policy.probe());
+                probing.store(false, Ordering::SeqCst);
+            });
+        }
+
+        Ok(outcome)
+    }
+}
+
+/// Checks `policy` every second until it is met or `timeout` seconds have
+/// passed. A captive portal of the network is detected once the backend
+/// reports it, or by `login_check_url` when the policy is not met in time.
+pub fn wait_for_connection(
+    backend: &dyn WifiBackend,
+    policy: &VerifyPolicy,
+    login_check_url: Option<&Url>,
+    timeout: u64,
+) -> Result<Verification> {
+    let started = Instant::now();
+
+    loop {
+        let verified = policy.check(backend)?;
+        let elapsed = started.elapsed().as_secs();
+
+        if verified {
+            debug!("Connection verified: {} / {}s elapsed", policy, elapsed);
+
+            return Ok(Verification::Verified);
+        } else if backend.connectivity()? == Connectivity::Portal {
+            debug!("Captive portal reported / {}s elapsed", elapsed);
+
+            let login_url = login_check_url.and_then(check_login).unwrap_or(None);
+
+            return Ok(Verification::LoginRequired(login_url));
+        } else if elapsed >= timeout {
+            debug!(
+                "Timeout reached in verifying the connection: {} / {}s elapsed",
+                policy, elapsed
+            );
+
+            // Probes of other hosts may have been answered over Ethernet
+            if let Some(url) = login_check_url {
+                if backend.is_associated()? {
+                    if let Some(login_url) = check_login(url) {
+                        debug!("Captive portal detected by {}", url);
+
+                        return Ok(Verification::LoginRequired(login_url));
+                    }
+                }
+            }
+
+            return Ok(Verification::Failed);
+        }
+
+        thread::sleep(Duration::from_secs(1));
+
+        debug!(
+            "Still verifying the connection: {} / {}s elapsed",
+            policy, elapsed
+        );
+    }
+}
+
+/// Requests `url`, which answers with 204 No Content unless a captive
+/// portal intercepts the request. Returns the login page of a portal, if it
+/// redirects to one, and `None` without a portal or if `url` is unreachable.
+fn check_login(url: &Url) -> Option<Option<String>> {
+    match http_get(url) {
+        Ok(response) => login_page(url, &response),
+        Err(e) => {
+            debug!("Login check {} failed: {}", url, e);
+            None
+        }
+    }
+}
+
+/// A portal either redirects to its login page or serves a page of its own.
+/// Any other answer, 204 No Content included, does not tell of a portal.
+fn login_page(url: &Url, response: &HttpResponse) -> Option<Option<String>> {
+    match response.status {
+        300..=399 => response
+            .location
+            .as_ref()
+            .and_then(|location| url.join(location).ok())
+            .map(|login_url| Some(login_url.to_string())),
+        200 if !response.body.is_empty() => Some(None),
+        status => {
+            debug!("Login check {} answered {}", url, status);
+            None
+        }
+    }
+}
+
+fn probe_http(url: &Url, status: u16, body: &Option<String>) -> bool {
+    match http_get(url) {
+        Ok(response) => {
+            let verified = response.status == status
+                && body
+                    .as_ref()
+                    .map_or(true, |body| response.body.contains(body as &str));
+
+            if !verified {
+                debug!("HTTP probe {} answered {}", url, response.status);
+            }
+
+            verified
+        }
+        Err(e) => {
+            debug!("HTTP probe {} failed: {}", url, e);
+            false
+        }
+    }
+}
+
+fn probe_tcp(address: &str) -> bool {
+    match connect(address) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("TCP probe {} failed: {}", address, e);
+            false
+        }
+    }
+}
+
+fn probe_dns(host: &str) -> bool {
+    match (host, 0).to_socket_addrs() {
+        Ok(mut addresses) => addresses.next().is_some(),
+        Err(e) => {
+            debug!("DNS probe {} failed: {}", host, e);
+            false
+        }
+    }
+}
+
+/// Response to a plain HTTP/1.0 `GET` request
+fn http_get(url: &Url) -> Result<HttpResponse> {
+    let host = url.host_str().unwrap_or("");
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut target = url.path().to_string();
+
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+
+    // IPv6 literals are enclosed in brackets
+    let address = host.trim_start_matches('[').trim_end_matches(']');
+    let mut stream = connect((address, port))?;
+
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: wifi-connect\r\nConnection: close\r\n\r\n",
+        target, host_header
+    )?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut response)?;
+
+    let response = String::from_utf8_lossy(&response);
+
+    let status = response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok());
+
+    let status = match status {
+        Some(status) => status,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "No HTTP status line").into())
+        }
+    };
+
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(index) => (&response[..index], response[index + 4..].to_string()),
+        None => (&response[..], String::new()),
+    };
+
+    let location = head.lines().skip(1).find_map(|line| {
+        let mut header = line.splitn(2, ':');
+        match (header.next(), header.next()) {
+            (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("location") => {
+                Some(value.trim().to_string())
+            }
+            _ => None,
+        }
+    });
+
+    Ok(HttpResponse {
+        status,
+        location,
+        body,
+    })
+}
+
+/// Connects to the first reachable address of `address`
+fn connect<A: ToSocketAddrs>(address: A) -> Result<TcpStream> {
+    let timeout = Duration::from_secs(PROBE_TIMEOUT);
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No address resolved");
+
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, location: Option<&str>, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            location: location.map(|location| location.to_string()),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn login_page_of_portals() {
+        let url = Url::parse("http://connectivitycheck.gstatic.com/generate_204").unwrap();
+
+        assert_eq!(
+            login_page(&url, &response(302, Some("http://login.example.com/"), "")),
+            Some(Some("http://login.example.com/".to_string()))
+        );
+        assert_eq!(
+            login_page(&url, &response(307, Some("/login"), "")),
+            Some(Some(
+                "http://connectivitycheck.gstatic.com/login".to_string()
+            ))
+        );
+        assert_eq!(login_page(&url, &response(200, None, "<html>")), Some(None));
+    }
+
+    #[test]
+    fn login_page_without_portal() {
+        let url = Url::parse("http://connectivitycheck.gstatic.com/generate_204").unwrap();
+
+        assert_eq!(login_page(&url, &response(204, None, "")), None);
+        assert_eq!(login_page(&url, &response(200, None, "")), None);
+        assert_eq!(login_page(&url, &response(302, None, "")), None);
+        assert_eq!(login_page(&url, &response(404, None, "Not Found")), None);
+        assert_eq!(login_page(&url, &response(503, None, "")), None);
+    }
+}
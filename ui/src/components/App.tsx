@@ -44,6 +44,12 @@ export interface NetworkInfo {
 export interface Network {
 	ssid: string;
 	security: string;
+	strength: number;
+	band: string | null;
+	channel: number | null;
+	bssids: number;
+	age: number;
+	saved: boolean;
 }
 
 const App = () => {